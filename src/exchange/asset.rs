@@ -1,5 +1,9 @@
 pub type AssetId = usize;
 
+/// A tradeable symbol.
+///
+/// Futures, perpetual swaps and options are margined and settled in the quote asset,
+/// the base asset only names the underlying.
 #[derive(PartialEq, Eq, Hash, Clone)]
 pub struct Symbol {
     pub symbol_type: SymbolType,
@@ -10,6 +14,110 @@ pub struct Symbol {
 #[derive(PartialEq, Eq, Hash, Clone)]
 pub enum SymbolType {
     ExchangePair,
-    FuturesContract,
-    Option,
+    FuturesContract(FuturesSpec),
+    PerpetualSwap(PerpetualSpec),
+    Option(OptionSpec),
+}
+
+/// Margin requirements of a futures contract in basis points of the notional value
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub struct FuturesSpec {
+    /// Margin needed to open a position
+    pub initial_margin: u64,
+    /// Margin below which a position has to be liquidated
+    pub maintenance_margin: u64,
+}
+
+impl FuturesSpec {
+    /// Initial margin for a notional value, rounded up
+    pub fn initial_margin(&self, value: u64) -> u64 {
+        (value * self.initial_margin).div_ceil(10_000)
+    }
+
+    /// Maintenance margin for a notional value, rounded up
+    pub fn maintenance_margin(&self, value: u64) -> u64 {
+        (value * self.maintenance_margin).div_ceil(10_000)
+    }
+}
+
+/// A futures contract without expiry, kept close to its index price by periodic funding payments
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub struct PerpetualSpec {
+    pub margin: FuturesSpec,
+    /// Seconds between two funding payments
+    pub funding_interval: u64,
+    /// Highest funding rate per interval in basis points, in both directions
+    pub max_funding_rate: u64,
+}
+
+/// An option on the price of an underlying symbol, cash settled at expiry
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub struct OptionSpec {
+    /// Symbol whose price the option is written on
+    pub underlying: u64,
+    pub strike: u64,
+    /// Unix timestamp in seconds
+    pub expiry: u64,
+    pub kind: OptionKind,
+    /// How the margin of short positions is computed
+    pub margin: OptionMarginModel,
+}
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub enum OptionKind {
+    Call,
+    Put,
+}
+
+/// Margin of a short option contract, rates in basis points
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub enum OptionMarginModel {
+    /// The premium plus a rate of the underlying price less the amount the option is
+    /// out of the money, but at least the minimum rate of the underlying price
+    PercentOfUnderlying { rate: u64, minimum: u64 },
+    /// Highest exercise value over a grid of underlying prices,
+    /// moving up to `price_move` in `steps` steps in each direction, but at least the premium
+    Scenario { price_move: u64, steps: u64 },
+}
+
+impl OptionSpec {
+    /// Value of one contract when exercised at the given underlying price
+    pub fn intrinsic_value(&self, underlying_price: u64) -> u64 {
+        match self.kind {
+            OptionKind::Call => underlying_price.saturating_sub(self.strike),
+            OptionKind::Put => self.strike.saturating_sub(underlying_price),
+        }
+    }
+
+    /// Margin of one short contract sold for `premium`
+    pub fn short_margin(&self, premium: u64, underlying_price: u64) -> u64 {
+        match self.margin {
+            OptionMarginModel::PercentOfUnderlying { rate, minimum } => {
+                let out_of_the_money = match self.kind {
+                    OptionKind::Call => self.strike.saturating_sub(underlying_price),
+                    OptionKind::Put => underlying_price.saturating_sub(self.strike),
+                };
+                let margin = std::cmp::max(
+                    (underlying_price * rate / 10_000).saturating_sub(out_of_the_money),
+                    underlying_price * minimum / 10_000,
+                );
+                premium + margin
+            }
+            OptionMarginModel::Scenario { price_move, steps } => {
+                let steps = std::cmp::max(steps, 1);
+                let worst = (0..=steps)
+                    .flat_map(|step| {
+                        let moved = underlying_price * price_move * step / steps / 10_000;
+                        [
+                            underlying_price + moved,
+                            underlying_price.saturating_sub(moved),
+                        ]
+                    })
+                    .map(|price| self.intrinsic_value(price))
+                    .max()
+                    .unwrap_or(0);
+                std::cmp::max(worst, premium)
+            }
+        }
+    }
 }
@@ -1,15 +1,185 @@
 use crate::order_handling::order::OrderSide;
+use crate::risk::exposure_limits::ExposureLimits;
+use crate::risk::order_limits::OrderLimits;
+use crate::risk::position_record::MarginMode;
 
-use super::asset::Symbol;
+use super::asset::{AssetId, Symbol};
 
 pub trait Command {
     fn sender(&self) -> &str;
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Clone, Debug)]
 pub enum OrderCommand {
     Trade(TradeCommand),
     Cancel(CancelCommand),
+    Quote(QuoteCommand),
+    MassQuote(MassQuoteCommand),
+    Oco(OcoCommand),
+    Bracket(BracketCommand),
+    Admin(AdminCommand),
+    Query(QueryCommand),
+    Margin(MarginCommand),
+    Account(AccountCommand),
+    Migration(MigrationCommand),
+}
+
+impl OrderCommand {
+    /// Participant the command is sent for, None for commands of the operator
+    pub fn participant_id(&self) -> Option<u64> {
+        match self {
+            OrderCommand::Trade(trade) => Some(trade.participant_id),
+            OrderCommand::Cancel(cancel) => Some(cancel.participant_id),
+            OrderCommand::Quote(quote) => Some(quote.participant_id),
+            OrderCommand::Oco(oco) => Some(oco.first.participant_id),
+            OrderCommand::Bracket(bracket) => Some(bracket.entry.participant_id),
+            OrderCommand::MassQuote(mass_quote) => Some(mass_quote.participant_id),
+            OrderCommand::Margin(MarginCommand::SetMode { participant_id, .. })
+            | OrderCommand::Margin(MarginCommand::Transfer { participant_id, .. }) => {
+                Some(*participant_id)
+            }
+            OrderCommand::Account(command) => Some(command.participant_id()),
+            OrderCommand::Migration(command) => command.participant_id(),
+            OrderCommand::Admin(_) | OrderCommand::Query(_) => None,
+        }
+    }
+}
+
+/// Commands of the exchange operator, applied on every risk engine shard
+#[derive(Copy, Clone, Debug)]
+pub enum AdminCommand {
+    /// Exercise and assign all positions of an expired option at the settlement price
+    /// of its underlying and close its order book
+    ExpireOption { symbol: u64, settlement_price: u64 },
+    /// Mark a symbol at a price from outside the exchange, overriding the fair price of its book
+    MarkPrice { symbol: u64, price: u64 },
+    /// Pay funding between the longs and shorts of a perpetual swap.
+    /// Ignored if the funding interval has not passed since the last payment.
+    Funding {
+        symbol: u64,
+        /// Unix timestamp in seconds
+        time: u64,
+        mark_price: u64,
+        index_price: u64,
+    },
+    /// Start a new trading day for the rolling volume of the fee tiers
+    RollDay,
+    /// Pay funds into the insurance fund of a risk engine shard, the other shards ignore it
+    InsuranceDeposit {
+        shard: u64,
+        asset: AssetId,
+        amount: u64,
+    },
+    /// Stop trading a symbol and cancel every order in its book.
+    /// A shard also halts a symbol by itself once a loss on it is beyond its insurance fund.
+    Halt { symbol: u64 },
+    /// Replace the fat finger limits of every order on a symbol
+    SymbolLimits { symbol: u64, limits: OrderLimits },
+    /// Replace the fat finger limits of every order of a participant
+    ParticipantLimits {
+        participant_id: u64,
+        limits: OrderLimits,
+    },
+    /// Replace the position and open order limits of a participant
+    ExposureLimits {
+        participant_id: u64,
+        limits: ExposureLimits,
+    },
+}
+
+/// Creation and funding of participants, routed to the shard that holds the participant
+#[derive(Copy, Clone, Debug)]
+pub enum AccountCommand {
+    CreateParticipant {
+        participant_id: u64,
+    },
+    Deposit {
+        participant_id: u64,
+        asset: AssetId,
+        amount: u64,
+    },
+    /// Only funds that are neither held by open orders nor needed as margin can be withdrawn
+    Withdraw {
+        participant_id: u64,
+        asset: AssetId,
+        amount: u64,
+    },
+    /// Correction of a balance by the operator, can not take more than the free funds
+    Adjust {
+        participant_id: u64,
+        asset: AssetId,
+        amount: i64,
+    },
+}
+
+impl AccountCommand {
+    pub fn participant_id(&self) -> u64 {
+        match *self {
+            AccountCommand::CreateParticipant { participant_id }
+            | AccountCommand::Deposit { participant_id, .. }
+            | AccountCommand::Withdraw { participant_id, .. }
+            | AccountCommand::Adjust { participant_id, .. } => participant_id,
+        }
+    }
+}
+
+/// Collateral management of a participant's futures, perpetual swap and option positions
+#[derive(Copy, Clone, Debug)]
+pub enum MarginCommand {
+    /// Back the position of a symbol by all collateral or only by collateral allocated to it.
+    /// Only possible without position and open orders on the symbol.
+    SetMode {
+        participant_id: u64,
+        symbol: u64,
+        mode: MarginMode,
+    },
+    /// Move collateral between isolated positions of the same settlement asset,
+    /// None stands for the cross margin collateral
+    Transfer {
+        participant_id: u64,
+        from: Option<u64>,
+        to: Option<u64>,
+        amount: u64,
+    },
+}
+
+/// Steps of moving a participant to another risk engine shard, sent by the exchange
+#[derive(Copy, Clone, Debug)]
+pub enum MigrationCommand {
+    /// Hold back the commands of the participant until its state arrives from `from`
+    MoveIn { participant_id: u64, from: usize },
+    /// Hand the participant over to `to` once every order book sent the events of its orders
+    MoveOut { participant_id: u64, to: usize },
+    /// Sent by the shard giving up the participant through every order book,
+    /// which answers with `MatchingEngineEvent::Flushed` to `shard`
+    Flush { participant_id: u64, shard: usize },
+    /// Send the shard `to` that was just added its share of this shard's state,
+    /// `shards` counting the new one
+    Seed { to: usize, shards: usize },
+}
+
+impl MigrationCommand {
+    /// Participant that moves, None for seeding a new shard
+    pub fn participant_id(&self) -> Option<u64> {
+        match *self {
+            MigrationCommand::MoveIn { participant_id, .. }
+            | MigrationCommand::MoveOut { participant_id, .. }
+            | MigrationCommand::Flush { participant_id, .. } => Some(participant_id),
+            MigrationCommand::Seed { .. } => None,
+        }
+    }
+}
+
+/// Requests for information, answered with a report
+#[derive(Copy, Clone, Debug)]
+pub enum QueryCommand {
+    FundingHistory {
+        symbol: u64,
+    },
+    /// Available and held funds of every asset of a participant
+    Balances {
+        participant_id: u64,
+    },
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -19,8 +189,89 @@ pub struct TradeCommand {
     pub symbol: u64,
     pub side: OrderSide,
     pub volume: u64,
+    /// Limit price, for pegged orders the cap the effective limit never crosses
     pub limit: u64,
     pub immediate_or_cancel: bool,
+    pub order_type: OrderType,
+    /// Not displayed in the book, matched only after the displayed orders of a price
+    pub hidden: bool,
+    /// Smallest volume to trade at once, zero for no minimum.
+    /// Applies when the order comes in as well as while it rests in the book.
+    pub min_quantity: u64,
+    /// Only reduces the position of a futures, perpetual swap or option symbol.
+    /// Capped at the position and canceled once the position shrinks below it.
+    pub reduce_only: bool,
+    /// Closes the whole position, the volume is replaced by the position. Implies reduce only.
+    pub close_position: bool,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OrderType {
+    Limit,
+    /// The effective limit follows a reference price of the book
+    Pegged(Peg),
+    /// Waits in the book until the last traded price moves against it by the offset
+    TrailingStop(TrailingStop),
+    /// Waits in the book until its trigger price is traded
+    Stop(Stop),
+}
+
+impl OrderType {
+    /// Whether the order waits for a trigger instead of entering the book
+    pub fn is_stop(&self) -> bool {
+        matches!(self, Self::TrailingStop(_) | Self::Stop(_))
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Stop {
+    /// Asks trigger at or below this price, bids at or above
+    pub trigger: u64,
+    /// Enter the book as a market order once triggered.
+    /// Bids still never pay more than their limit, the funds are held against it.
+    pub market: bool,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Peg {
+    pub reference: PegReference,
+    /// Added to the reference price to get the effective limit
+    pub offset: i64,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TrailingStop {
+    /// Distance of the trigger price from the last traded price
+    pub offset: TrailingOffset,
+    /// Enter the book as a market order once triggered.
+    /// Bids still never pay more than their limit, the funds are held against it.
+    pub market: bool,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TrailingOffset {
+    Fixed(u64),
+    /// Percentage of the last traded price in basis points
+    Percentage(u64),
+}
+
+impl TrailingOffset {
+    pub fn at(&self, price: u64) -> u64 {
+        match self {
+            Self::Fixed(offset) => *offset,
+            Self::Percentage(basis_points) => price * basis_points / 10_000,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PegReference {
+    /// Best price on the own side of the book
+    Primary,
+    /// Best price on the opposite side of the book
+    Market,
+    /// Middle between best bid and best ask
+    Midpoint,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -28,21 +279,129 @@ pub struct CancelCommand {
     pub symbol: u64,
     pub order_id: u64,
     pub participant_id: u64,
+    /// Volume the order keeps in the book, 0 cancels it completely
+    pub remaining: u64,
+}
+
+/// Two-sided quote of a market maker.
+/// Replaces the previous quote of the participant on this symbol.
+/// A side with zero volume is pulled from the book.
+#[derive(Copy, Clone, Debug)]
+pub struct QuoteCommand {
+    pub participant_id: u64,
+    pub symbol: u64,
+    pub bid_id: u64,
+    pub bid_limit: u64,
+    pub bid_volume: u64,
+    pub ask_id: u64,
+    pub ask_limit: u64,
+    pub ask_volume: u64,
+}
+
+/// Quotes of one participant for many symbols in a single message
+#[derive(Clone, Debug)]
+pub struct MassQuoteCommand {
+    /// Id under which the aggregated acknowledgement is reported
+    pub id: u64,
+    pub participant_id: u64,
+    /// Accept the quotes that pass the risk check even if others fail
+    pub allow_partial: bool,
+    pub quotes: Vec<QuoteCommand>,
+}
+
+/// Two orders of which only one executes, a fill on one leg cancels the other
+///
+/// Both legs are on the same side of the same symbol, e.g. a take-profit limit and a stop.
+#[derive(Copy, Clone, Debug)]
+pub struct OcoCommand {
+    pub first: TradeCommand,
+    pub second: TradeCommand,
+}
+
+/// An entry order whose exit pair becomes active once the entry is completely filled
+#[derive(Copy, Clone, Debug)]
+pub struct BracketCommand {
+    pub entry: TradeCommand,
+    /// Closes the position opened by the entry, same volume on the opposite side
+    pub exit: OcoCommand,
+}
+
+impl OcoCommand {
+    pub fn is_valid(&self) -> bool {
+        self.first.participant_id == self.second.participant_id
+            && self.first.symbol == self.second.symbol
+            && self.first.side == self.second.side
+            && self.first.id != self.second.id
+    }
+}
+
+impl BracketCommand {
+    pub fn is_valid(&self) -> bool {
+        self.exit.is_valid()
+            && self.entry.participant_id == self.exit.first.participant_id
+            && self.entry.symbol == self.exit.first.symbol
+            && self.entry.side == -self.exit.first.side
+            && self.entry.volume == self.exit.first.volume
+            && self.entry.volume == self.exit.second.volume
+            && self.entry.id != self.exit.first.id
+            && self.entry.id != self.exit.second.id
+    }
+}
+
+impl QuoteCommand {
+    /// Both sides of the quote as trade commands, sides without volume are skipped
+    pub fn legs(&self) -> impl Iterator<Item = TradeCommand> {
+        let bid = TradeCommand {
+            id: self.bid_id,
+            participant_id: self.participant_id,
+            symbol: self.symbol,
+            side: OrderSide::BID,
+            volume: self.bid_volume,
+            limit: self.bid_limit,
+            immediate_or_cancel: false,
+            order_type: OrderType::Limit,
+            hidden: false,
+            min_quantity: 0,
+            reduce_only: false,
+            close_position: false,
+        };
+        let ask = TradeCommand {
+            id: self.ask_id,
+            side: OrderSide::ASK,
+            volume: self.ask_volume,
+            limit: self.ask_limit,
+            ..bid
+        };
+        IntoIterator::into_iter([bid, ask]).filter(|leg| leg.volume > 0)
+    }
 }
 
 impl TradeCommand {
-    /// Highest amount of value that could be spent (asset_id, value)
-    pub fn pessimistic(&self, symbol: &Symbol) -> (usize, u64) {
-        match self.side {
-            OrderSide::BID => (symbol.base_asset, self.volume * self.limit),
-            OrderSide::ASK => (symbol.quote_asset, self.volume),
-        }
+    pub fn is_reduce_only(&self) -> bool {
+        self.reduce_only || self.close_position
+    }
+
+    /// Highest amount of value that could be spent (asset_id, value).
+    /// Bids include the fee at `fee_rate` basis points, asks pay it out of their proceeds.
+    pub fn pessimistic(&self, symbol: &Symbol, fee_rate: u64) -> Option<(usize, u64)> {
+        Self::historic_pessimistic(self.side, self.limit, symbol, self.volume, fee_rate)
     }
-    /// Highest amount of value that could be spent (asset_id, value)
-    pub fn historic_pessimistic(side: OrderSide, limit: u64, symbol: &Symbol, volume: u64) -> (usize, u64) {
+    /// Highest amount of value that could be spent (asset_id, value),
+    /// None if it does not fit into a balance
+    pub fn historic_pessimistic(
+        side: OrderSide,
+        limit: u64,
+        symbol: &Symbol,
+        volume: u64,
+        fee_rate: u64,
+    ) -> Option<(usize, u64)> {
         match side {
-            OrderSide::BID => (symbol.base_asset, volume * limit),
-            OrderSide::ASK => (symbol.quote_asset, volume),
+            OrderSide::BID => {
+                let value = volume.checked_mul(limit)?;
+                let fee = value.checked_mul(fee_rate)?.div_ceil(10_000);
+                Some((symbol.base_asset, value.checked_add(fee)?))
+            }
+            OrderSide::ASK => Some((symbol.quote_asset, volume)),
         }
     }
 }
@@ -1,87 +1,337 @@
-use crate::exchange::commands::OrderCommand;
-use crate::order_handling::event::MatchingEngineEvent;
+use crate::exchange::commands::{
+    AccountCommand, AdminCommand, MigrationCommand, OrderCommand, QueryCommand,
+};
+use crate::exchange::report::Report;
+use crate::journal::{Clock, Journal, JournalError, JournalRecord, JournalSettings};
+use crate::order_handling::event::{DbEvent, MatchingEngineEvent};
 use crate::order_handling::order::*;
 use crate::order_handling::order_book::OrderBook;
+use crate::persistence::Persistence;
+use crate::processor::database_processor::DatabaseProcessor;
 use crate::processor::order_book_processor::OrderBookProcessor;
 use crate::processor::risk_engine_processor::RiskEngineProcessor;
-use crate::risk::router::risk_router;
+use crate::processor::sequencer::Sequencer;
+use crate::risk::migration::Handoff;
+use crate::risk::risk_engine::FEE_ACCOUNT_ID;
+use crate::risk::router::ShardDirectory;
 
 use futures::executor::block_on;
-use log::{info, debug};
+use log::{info, debug, error, warn};
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::thread;
-use crossbeam::channel::{bounded, Receiver, Sender};
+use crossbeam::channel::{bounded, unbounded, Receiver, Sender};
 use tokio::sync::RwLock;
 
 use super::asset::Symbol;
 use super::exchange_settings::ExchangeSettings;
 
 const ORDER_BOOK_COUNT: usize = 1;
-#[derive(Default)]
+/// Reports kept for the clients until they are read, later reports are dropped
+pub const REPORT_CAPACITY: usize = 100_000;
+
+/// The exchange runs every order book and risk engine shard on a thread of its own.
+/// `Exchange::sequenced` runs them in sequence on the caller's thread instead,
+/// so the same commands always lead to the same state and a journal can rebuild it.
 pub struct Exchange {
     //pub accounts: RwLock<HashMap<u64, Account>>,
     //pub assets: RwLock<HashMap<&'a str, Asset>>,
     pub settings: ExchangeSettings,
     order_senders: Vec<Sender<OrderCommand>>,
+    /// Order book of each symbol, for the risk engine shards added later
+    book_senders: Vec<Sender<OrderCommand>>,
+    /// Risk engine shard of every participant
+    directory: Arc<ShardDirectory>,
+    report_sender: Sender<Report>,
+    /// None if nothing is persisted
+    db_sender: Option<Sender<DbEvent>>,
+    /// None if nothing is journaled
+    journal: Option<Journal>,
+    /// Time the records are carried out at
+    clock: Clock,
+    /// Runs the shards and books in sequence instead of on their own threads, None for threads
+    sequencer: Option<Sequencer>,
+    /// Reports of the risk engines for the clients.
+    /// Reports beyond `REPORT_CAPACITY` are dropped, so the risk engines never stall
+    /// on a slow reader.
+    pub reports: Receiver<Report>,
 }
 
 impl Exchange {
+    /// Start an exchange that runs every order book and risk engine shard on a thread of its own.
+    ///
+    /// The shards see the events of different books in the order the threads send them,
+    /// so the state the commands lead to can vary and the exchange can not be journaled.
     pub fn new(settings: ExchangeSettings) -> Self {
-        let mut stage_1_senders = Vec::new();
-        let mut stage_1_receivers = Vec::new();
-        let mut stage_2_senders = Vec::new();
-        let mut stage_2_receivers = Vec::new();
-        let mut stage_3_senders = Vec::new();
-        let mut stage_3_receivers = Vec::new();
-        //Create Channels
-        // Stage 1: One per risk engine shard:
-        for i in 0..settings.risk_engine_shards {
-            let (tx, rx) = bounded::<OrderCommand>(1000);
-            stage_1_senders.push(tx);
-            stage_1_receivers.push(rx);
-        }
+        Self::start(settings, None, false)
+    }
 
-        // Stage 2: One per book
-        for i in 0..settings.symbols.len() {
-            let (tx, rx) = bounded::<OrderCommand>(1000);
-            stage_2_senders.push(tx);
-            stage_2_receivers.push(rx);
+    /// Start a threaded exchange that writes its trades, order events and balance changes
+    /// to the persistence, batched by `db_sync_speed`
+    pub fn with_persistence(settings: ExchangeSettings, persistence: Box<dyn Persistence>) -> Self {
+        Self::start(settings, Some(persistence), false)
+    }
+
+    /// Start an exchange that runs its order books and risk engine shards in sequence
+    /// on the caller's thread, every command is carried out before the call returns.
+    /// It can be journaled with `with_journal`.
+    pub fn sequenced(
+        settings: ExchangeSettings,
+        persistence: Option<Box<dyn Persistence>>,
+    ) -> Self {
+        Self::start(settings, persistence, true)
+    }
+
+    /// Journal everything the exchange is asked to do from now on, after replaying the journal
+    /// to rebuild the participants, balances and resting orders it had when it stopped.
+    /// Call it before the exchange is sent anything.
+    ///
+    /// Replayed records send no reports and no database events again.
+    ///
+    /// # Panics
+    /// If the exchange is threaded, its journal could not be replayed.
+    pub fn with_journal(mut self, journal: JournalSettings) -> Result<Self, JournalError> {
+        assert!(
+            self.sequencer.is_some(),
+            "A threaded exchange can not be journaled"
+        );
+        let directory = journal.directory.clone();
+        let journal = Journal::open(journal)?;
+        let entries = Journal::read(directory)?;
+        info!("Replaying {} journal entries", entries.len());
+        self.set_replaying(true);
+        for entry in entries {
+            self.apply(entry.time, entry.record);
         }
-        // Stage 3: One per risk engine shard
-        for i in 0..settings.risk_engine_shards {
-            let (tx, rx) = bounded::<MatchingEngineEvent>(1000);
-            stage_3_senders.push(tx);
-            stage_3_receivers.push(rx);
+        self.set_replaying(false);
+        self.journal = Some(journal);
+        Ok(self)
+    }
+
+    /// Start an exchange with a journal, rebuilt from the journal if there is one
+    pub fn recover(
+        settings: ExchangeSettings,
+        persistence: Option<Box<dyn Persistence>>,
+        journal: JournalSettings,
+    ) -> Result<Self, JournalError> {
+        Self::start(settings, persistence, true).with_journal(journal)
+    }
+
+    fn set_replaying(&mut self, replaying: bool) {
+        if let Some(sequencer) = self.sequencer.as_mut() {
+            sequencer.set_replaying(replaying);
         }
+    }
 
-        //Create risk engines
-        for i in 0..settings.risk_engine_shards {
-            let rec = stage_1_receivers.remove(0);
-            let ev_rec = stage_3_receivers.remove(0);
-            let senders = stage_2_senders.clone();
+    fn start(
+        settings: ExchangeSettings,
+        persistence: Option<Box<dyn Persistence>>,
+        sequenced: bool,
+    ) -> Self {
+        let directory = Arc::new(ShardDirectory::default());
+        let mut book_senders = Vec::new();
+        let (report_sender, reports) = bounded::<Report>(REPORT_CAPACITY);
+        // Unbounded, a slow database never stalls the order books
+        let (db_sender, db_receiver) = unbounded::<DbEvent>();
+        let db_sender = persistence.map(|persistence| {
             let set = settings.clone();
+            let report_sender = report_sender.clone();
             thread::spawn(move || {
-                info!("Starting Risk Engine {:?}", i);
-                let mut risk_engine = RiskEngineProcessor::new(set);
-               risk_engine.run(rec, senders, ev_rec);
+                info!("Starting Database");
+                DatabaseProcessor::new(set, persistence, report_sender).run(db_receiver);
             });
-        }
+            db_sender
+        });
+        let mut sequencer = if sequenced {
+            Some(Sequencer::new(report_sender.clone(), db_sender.clone()))
+        } else {
+            None
+        };
 
         // Create Order Book Processors for each symbol
         for (i, symbol) in settings.symbols.iter().enumerate() {
-            let rev = stage_2_receivers.remove(0);
-            let send = stage_3_senders.clone();
+            if let Some(sequencer) = sequencer.as_mut() {
+                let db_sender = sequencer.db_sender().unwrap_or_else(|| unbounded().0);
+                let book = OrderBook::new(i, settings.clone(), directory.clone(), db_sender);
+                book_senders.push(sequencer.add_book(book));
+                continue;
+            }
+            let (tx, rx) = bounded::<OrderCommand>(1000);
+            book_senders.push(tx);
+            let directory = directory.clone();
             let set = settings.clone();
+            // Without persistence the trades go nowhere
+            let db_sender = db_sender.clone().unwrap_or_else(|| unbounded().0);
             thread::spawn(move || {
                 info!("Starting Order Book {:?}", i);
-                OrderBookProcessor::new(set).run(rev, send);
+                OrderBookProcessor::new(i, set).run(rx, directory, db_sender);
             });
         }
 
-        Self {
+        let mut exchange = Self {
             settings,
-            order_senders: stage_1_senders,
+            order_senders: Vec::new(),
+            book_senders,
+            directory,
+            report_sender,
+            db_sender,
+            journal: None,
+            clock: Clock::new(),
+            sequencer,
+            reports,
+        };
+        //Create risk engines
+        for _ in 0..exchange.settings.risk_engine_shards {
+            exchange.spawn_shard(0);
+        }
+        exchange
+    }
+
+    /// Journal a record unless it is a query, then carry it out.
+    /// Returns false if it could not be journaled, the request is dropped then.
+    fn submit(&mut self, record: JournalRecord) -> bool {
+        let time = self.clock.now();
+        let journal = self.journal.as_mut().filter(|_| record.changes_state());
+        if let Some(journal) = journal {
+            match journal.append(time, &record) {
+                Ok(sequence) => debug!("Journaled {:?} as {}", record, sequence),
+                Err(error) => {
+                    error!(
+                        "Dropped {:?}, it could not be journaled: {:?}",
+                        record, error
+                    );
+                    return false;
+                }
+            }
+        }
+        self.apply(time, record);
+        true
+    }
+
+    /// Carry out a record, live or replayed from the journal.
+    /// A sequencer runs until every shard and order book is done with it.
+    fn apply(&mut self, time: u64, record: JournalRecord) {
+        if let Some(sequencer) = self.sequencer.as_mut() {
+            sequencer.set_time(time);
+        }
+        match record {
+            JournalRecord::Command(order_command) => self.forward(*order_command),
+            JournalRecord::Migrate { participant_id, to } => {
+                self.move_participant(participant_id, to)
+            }
+            JournalRecord::AddShard => self.grow(),
+        }
+        if let Some(sequencer) = self.sequencer.as_mut() {
+            sequencer.run();
+        }
+    }
+
+    /// Start a risk engine shard with its channels, waiting for the state of `seeds` older shards.
+    /// Returns its index.
+    fn spawn_shard(&mut self, seeds: usize) -> usize {
+        let (order_sender, order_receiver) = self.channel::<OrderCommand>();
+        let (event_sender, event_receiver) = self.channel::<MatchingEngineEvent>();
+        let (handoff_sender, handoff_receiver) = unbounded::<Handoff>();
+        let shard = self.directory.add_shard(event_sender, handoff_sender);
+        let senders = self.book_senders.clone();
+        let set = self.settings.clone();
+        let directory = self.directory.clone();
+        self.order_senders.push(order_sender);
+        if let Some(sequencer) = self.sequencer.as_mut() {
+            let mut risk_engine = RiskEngineProcessor::new(
+                shard as u64,
+                set,
+                sequencer.report_sender(),
+                directory,
+                sequencer.db_sender(),
+            );
+            risk_engine.await_seeds(seeds);
+            sequencer.add_shard(
+                risk_engine,
+                order_receiver,
+                event_receiver,
+                handoff_receiver,
+            );
+            return shard;
+        }
+        let report_sender = self.report_sender.clone();
+        let db_sender = self.db_sender.clone();
+        thread::spawn(move || {
+            info!("Starting Risk Engine {:?}", shard);
+            let mut risk_engine =
+                RiskEngineProcessor::new(shard as u64, set, report_sender, directory, db_sender);
+            risk_engine.await_seeds(seeds);
+            risk_engine.run(order_receiver, senders, event_receiver, handoff_receiver);
+        });
+        shard
+    }
+
+    /// Channel between the shards and the order books.
+    /// Bounded between threads, a sequencer could never empty a full one.
+    fn channel<T>(&self) -> (Sender<T>, Receiver<T>) {
+        match self.sequencer {
+            Some(_) => unbounded(),
+            None => bounded(1000),
+        }
+    }
+
+    /// Add a risk engine shard while trading goes on and move the participants
+    /// the hash ring now places on it there. Returns its index, None if it could not be journaled.
+    pub fn add_shard(&mut self) -> Option<usize> {
+        if !self.submit(JournalRecord::AddShard) {
+            return None;
+        }
+        Some(self.order_senders.len() - 1)
+    }
+
+    fn grow(&mut self) {
+        let shards = self.order_senders.len();
+        let shard = self.spawn_shard(shards);
+        // Every older shard sends its state and a share of its insurance fund and fee account
+        for sender in &self.order_senders[..shards] {
+            let _ = sender.send(OrderCommand::Migration(MigrationCommand::Seed {
+                to: shard,
+                shards: shards + 1,
+            }));
+        }
+        for (participant_id, _) in self.directory.participants() {
+            if self.directory.placement(participant_id) == shard {
+                self.move_participant(participant_id, shard);
+            }
+        }
+    }
+
+    /// Move a participant with its open orders to another risk engine shard while trading goes on.
+    ///
+    /// The new shard holds the participant's commands back until the old shard hands it over,
+    /// which it does once every order book delivered the events sent to it before the move.
+    pub fn migrate(&mut self, participant_id: u64, to: usize) {
+        let from = self.directory.shard(participant_id);
+        // The insurance fund and the fee account live on every shard
+        if from == to || to >= self.order_senders.len() || participant_id >= FEE_ACCOUNT_ID {
+            return;
+        }
+        self.submit(JournalRecord::Migrate { participant_id, to });
+    }
+
+    fn move_participant(&mut self, participant_id: u64, to: usize) {
+        let from = self.directory.shard(participant_id);
+        if from == to {
+            return;
         }
+        debug!(
+            "Moving participant {} from shard {} to shard {}",
+            participant_id, from, to
+        );
+        let _ = self.order_senders[to].send(OrderCommand::Migration(MigrationCommand::MoveIn {
+            participant_id,
+            from,
+        }));
+        self.directory.assign(participant_id, to);
+        let _ = self.order_senders[from].send(OrderCommand::Migration(MigrationCommand::MoveOut {
+            participant_id,
+            to,
+        }));
     }
     /*
             pub fn add_account(&self, acc: Account) {
@@ -107,16 +357,61 @@ impl Exchange {
             }
         }
     */
+    /// Journal a command and forward it to the risk engine shard of its participant
     pub fn trade(&mut self, order_command: OrderCommand) {
         debug!("Sending TradeOrderCommand {:?}", order_command);
-        let participant_id = match order_command {
-            OrderCommand::Trade(trade) => trade.participant_id,
-            OrderCommand::Cancel(cancel) => cancel.participant_id,
+        if let OrderCommand::Migration(command) = &order_command {
+            return warn!(
+                "Migrations are started with Exchange::migrate: {:?}",
+                command
+            );
+        }
+        self.submit(JournalRecord::Command(Box::new(order_command)));
+    }
+
+    /// Forward a command to the risk engine shard of its participant
+    fn forward(&mut self, order_command: OrderCommand) {
+        let participant_id = match &order_command {
+            OrderCommand::Admin(command) => return self.send_admin(*command),
+            OrderCommand::Query(query) => return self.send_query(*query),
+            command => command.participant_id().unwrap(),
+        };
+        let shard = match &order_command {
+            OrderCommand::Account(AccountCommand::CreateParticipant { .. }) => {
+                self.directory.place(participant_id)
+            }
+            _ => self.directory.shard(participant_id),
         };
-        let shard = risk_router(&self.settings, &participant_id);
         let s = self.order_senders[shard].send(order_command);
         // println!("{:?}", s);
     }
+
+    /// Send a command of the exchange operator to every risk engine shard
+    pub fn admin(&mut self, command: AdminCommand) {
+        self.trade(OrderCommand::Admin(command));
+    }
+
+    fn send_admin(&mut self, command: AdminCommand) {
+        debug!("Sending AdminCommand {:?}", command);
+        for sender in &self.order_senders {
+            let _ = sender.send(OrderCommand::Admin(command));
+        }
+    }
+
+    /// Send a query to the risk engine shard that can answer it, the answer arrives as a report
+    pub fn query(&mut self, query: QueryCommand) {
+        self.trade(OrderCommand::Query(query));
+    }
+
+    fn send_query(&mut self, query: QueryCommand) {
+        debug!("Sending QueryCommand {:?}", query);
+        let shard = match query {
+            // Every shard keeps the same funding history
+            QueryCommand::FundingHistory { .. } => 0,
+            QueryCommand::Balances { participant_id } => self.directory.shard(participant_id),
+        };
+        let _ = self.order_senders[shard].send(OrderCommand::Query(query));
+    }
     /*
     /// Check if an assets with that ticker exists on this exchange
     fn check_asset_existance<'b>(&'b self, asset: &str) -> Result<(), &str> {
@@ -128,3 +423,261 @@ impl Exchange {
     }
     */
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exchange::asset::{AssetId, SymbolType};
+    use crate::exchange::commands::{CancelCommand, MarginCommand, OrderType, TradeCommand};
+    use crate::journal::FsyncPolicy;
+    use crate::risk::rate_limiter::RateLimits;
+    use crate::risk::risk_engine::RiskEngineResult;
+    use std::time::Duration;
+
+    const PARTICIPANTS: u64 = 6;
+
+    fn settings() -> ExchangeSettings {
+        let pair = |quote_asset| Symbol {
+            symbol_type: SymbolType::ExchangePair,
+            base_asset: 0,
+            quote_asset,
+        };
+        ExchangeSettings {
+            symbols: vec![pair(1), pair(2)],
+            risk_engine_shards: 2,
+            ..ExchangeSettings::default()
+        }
+    }
+
+    /// Balances of every participant and the resting orders of every book
+    fn state(exchange: &mut Exchange) -> String {
+        while exchange.reports.try_recv().is_ok() {}
+        for participant_id in 0..PARTICIPANTS {
+            exchange.query(QueryCommand::Balances { participant_id });
+        }
+        let balances: Vec<Report> = exchange.reports.try_iter().collect();
+        let orders: Vec<_> = exchange
+            .sequencer
+            .as_ref()
+            .unwrap()
+            .books()
+            .map(OrderBook::resting_orders)
+            .collect();
+        format!("{:?} {:?}", balances, orders)
+    }
+
+    #[test]
+    fn threaded_exchange_answers_from_its_threads() {
+        let mut exchange = Exchange::new(settings());
+        assert!(exchange.sequencer.is_none());
+        exchange.trade(OrderCommand::Account(AccountCommand::CreateParticipant {
+            participant_id: 0,
+        }));
+        exchange.trade(OrderCommand::Account(AccountCommand::Deposit {
+            participant_id: 0,
+            asset: 1,
+            amount: 100,
+        }));
+        exchange.query(QueryCommand::Balances { participant_id: 0 });
+        let timeout = Duration::from_secs(5);
+        loop {
+            match exchange.reports.recv_timeout(timeout) {
+                Ok(Report::Balances { balances, .. }) => {
+                    let amounts: Vec<(AssetId, u64)> = balances
+                        .iter()
+                        .map(|(asset, balance)| (*asset, balance.available))
+                        .collect();
+                    return assert_eq!(amounts, vec![(1, 100)]);
+                }
+                Ok(Report::Account { result, .. }) => {
+                    assert_eq!(result, RiskEngineResult::ValidForMatchingEngine)
+                }
+                report => panic!("Unexpected report {:?}", report),
+            }
+        }
+    }
+
+    #[test]
+    fn margin_commands_are_answered() {
+        let mut exchange = Exchange::sequenced(settings(), None);
+        let command = MarginCommand::Transfer {
+            participant_id: 0,
+            from: None,
+            to: Some(0),
+            amount: 10,
+        };
+        exchange.trade(OrderCommand::Margin(command));
+        match exchange.reports.try_recv() {
+            Ok(Report::Margin { result, .. }) => assert_eq!(result, RiskEngineResult::UserNotFound),
+            report => panic!("Unexpected report {:?}", report),
+        }
+    }
+
+    #[test]
+    fn refused_orders_are_reported() {
+        let mut exchange = Exchange::sequenced(settings(), None);
+        exchange.trade(OrderCommand::Account(AccountCommand::CreateParticipant {
+            participant_id: 0,
+        }));
+        exchange.trade(OrderCommand::Trade(TradeCommand {
+            id: 1,
+            participant_id: 0,
+            symbol: 0,
+            side: OrderSide::BID,
+            volume: 1,
+            limit: 100,
+            immediate_or_cancel: false,
+            order_type: OrderType::Limit,
+            hidden: false,
+            min_quantity: 0,
+            reduce_only: false,
+            close_position: false,
+        }));
+        let results: Vec<RiskEngineResult> = exchange
+            .reports
+            .try_iter()
+            .filter_map(|report| match report {
+                Report::Rejected { result, .. } => Some(result),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(results, vec![RiskEngineResult::InsufficientFunds]);
+    }
+
+    #[test]
+    fn rate_limits_follow_a_migrated_participant() {
+        let settings = ExchangeSettings {
+            rate_limits: Some(RateLimits {
+                orders_per_second: 0,
+                order_burst: 2,
+                cancels_per_second: 0,
+                cancel_burst: 0,
+                max_order_to_trade_ratio: 0,
+                ratio_min_orders: 0,
+            }),
+            ..settings()
+        };
+        let mut exchange = Exchange::sequenced(settings, None);
+        let order = |id| {
+            OrderCommand::Trade(TradeCommand {
+                id,
+                participant_id: 0,
+                symbol: 0,
+                side: OrderSide::BID,
+                volume: 1,
+                limit: 100,
+                immediate_or_cancel: false,
+                order_type: OrderType::Limit,
+                hidden: false,
+                min_quantity: 0,
+                reduce_only: false,
+                close_position: false,
+            })
+        };
+        exchange.trade(OrderCommand::Account(AccountCommand::CreateParticipant {
+            participant_id: 0,
+        }));
+        exchange.trade(order(1));
+        exchange.trade(order(2));
+        let to = 1 - exchange.directory.shard(0);
+        exchange.migrate(0, to);
+        exchange.trade(order(3));
+        let results: Vec<RiskEngineResult> = exchange
+            .reports
+            .try_iter()
+            .filter_map(|report| match report {
+                Report::Rejected { result, .. } => Some(result),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            results,
+            vec![
+                RiskEngineResult::InsufficientFunds,
+                RiskEngineResult::InsufficientFunds,
+                RiskEngineResult::OrderRateExceeded
+            ]
+        );
+    }
+
+    #[test]
+    fn replaying_the_journal_rebuilds_the_state() {
+        let journal = JournalSettings {
+            directory: std::env::temp_dir().join(format!("exchange-{}", std::process::id())),
+            segment_size: 4096,
+            fsync: FsyncPolicy::Never,
+        };
+        let _ = std::fs::remove_dir_all(&journal.directory);
+        let mut exchange = Exchange::recover(settings(), None, journal.clone()).unwrap();
+        for participant_id in 0..PARTICIPANTS {
+            exchange.trade(OrderCommand::Account(AccountCommand::CreateParticipant {
+                participant_id,
+            }));
+            for asset in 0..3 {
+                exchange.trade(OrderCommand::Account(AccountCommand::Deposit {
+                    participant_id,
+                    asset,
+                    amount: 100_000,
+                }));
+            }
+        }
+        let mut seed = 7u64;
+        for id in 1..=300 {
+            seed = seed
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            let participant_id = (seed >> 33) % PARTICIPANTS;
+            let symbol = (seed >> 40) % 2;
+            exchange.trade(OrderCommand::Trade(TradeCommand {
+                id,
+                participant_id,
+                symbol,
+                side: if (seed >> 20) & 1 == 0 {
+                    OrderSide::BID
+                } else {
+                    OrderSide::ASK
+                },
+                volume: 1 + (seed >> 45) % 10,
+                limit: 90 + (seed >> 50) % 20,
+                immediate_or_cancel: false,
+                order_type: OrderType::Limit,
+                hidden: false,
+                min_quantity: 0,
+                reduce_only: false,
+                close_position: false,
+            }));
+            if id % 7 == 0 {
+                exchange.trade(OrderCommand::Cancel(CancelCommand {
+                    symbol,
+                    order_id: id - 3,
+                    participant_id,
+                    remaining: 0,
+                }));
+            }
+            match id {
+                100 => {
+                    exchange.migrate(3, 0);
+                    exchange.migrate(4, 1);
+                }
+                200 => assert_eq!(exchange.add_shard(), Some(2)),
+                _ => (),
+            }
+        }
+        exchange.admin(AdminCommand::MarkPrice {
+            symbol: 0,
+            price: 100,
+        });
+
+        let journaled = Journal::read(&journal.directory).unwrap().len();
+        let before = state(&mut exchange);
+        assert!(before.contains("Balances") && before.contains("BID"));
+        // The queries were not journaled
+        assert_eq!(Journal::read(&journal.directory).unwrap().len(), journaled);
+        drop(exchange);
+
+        let mut exchange = Exchange::recover(settings(), None, journal.clone()).unwrap();
+        assert!(exchange.reports.try_recv().is_err());
+        assert_eq!(state(&mut exchange), before);
+        std::fs::remove_dir_all(&journal.directory).unwrap();
+    }
+}
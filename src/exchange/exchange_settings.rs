@@ -1,12 +1,23 @@
 use std::{collections::HashMap, time::Duration};
 
+use crate::risk::{
+    fee_engine::FeeSchedule, portfolio_margin::ScenarioGrid, rate_limiter::RateLimits,
+};
+
 use super::asset::Symbol;
 
 #[derive(PartialEq, Eq, Default, Clone)]
 pub struct 
 ExchangeSettings {
     pub symbols: Vec<Symbol>,
+    /// Risk engine shards at start, more can be added with `Exchange::add_shard`
     pub risk_engine_shards: u64,
+    /// Margin cross margin accounts by their portfolio risk instead of per position
+    pub portfolio_margin: Option<ScenarioGrid>,
+    /// Fees by symbol id, symbols without a schedule trade for free
+    pub fees: HashMap<u64, FeeSchedule>,
+    /// Limits of the orders and cancels each participant may send, None for no limits
+    pub rate_limits: Option<RateLimits>,
 
 
     //Technical parameters
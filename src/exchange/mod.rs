@@ -3,4 +3,5 @@ pub mod asset;
 pub mod commands;
 pub mod exchange;
 pub mod orderbook_runner;
-pub mod exchange_settings;
\ No newline at end of file
+pub mod exchange_settings;
+pub mod report;
\ No newline at end of file
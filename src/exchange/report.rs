@@ -0,0 +1,70 @@
+use crate::exchange::{
+    asset::AssetId,
+    commands::{AccountCommand, MarginCommand, OrderCommand},
+};
+use crate::risk::{
+    exposure_limits::ExposureAlert,
+    funding_engine::FundingRecord,
+    participant::Balance,
+    risk_engine::{MarginAccount, MarginState, RiskEngineResult},
+};
+
+/// Reports sent from the risk engines back to the clients of the exchange
+#[derive(Debug, Clone)]
+pub enum Report {
+    MassQuoteAck(MassQuoteAck),
+    /// All funding payments of a perpetual swap, oldest first
+    FundingHistory {
+        symbol: u64,
+        records: Vec<FundingRecord>,
+    },
+    Liquidation(LiquidationReport),
+    /// Balances by asset id, empty for an unknown participant
+    Balances {
+        participant_id: u64,
+        balances: Vec<(AssetId, Balance)>,
+    },
+    /// An accepted order took a participant past a soft exposure limit
+    ExposureAlert(ExposureAlert),
+    /// A command was refused before it reached the order book, like one over the rate limit
+    Rejected {
+        command: OrderCommand,
+        result: RiskEngineResult,
+    },
+    /// Outcome of creating, funding or debiting a participant
+    Account {
+        command: AccountCommand,
+        result: RiskEngineResult,
+    },
+    /// Outcome of changing the margin mode or moving the collateral of a position
+    Margin {
+        command: MarginCommand,
+        result: RiskEngineResult,
+    },
+    /// Writing the events to the persistence failed this many times in a row,
+    /// `queued` events wait for the next write and `dropped` are lost
+    PersistenceFailure {
+        failures: u32,
+        queued: usize,
+        dropped: u64,
+    },
+}
+
+/// A participant fell below maintenance margin and its positions are being liquidated
+#[derive(Debug, Clone)]
+pub struct LiquidationReport {
+    pub participant_id: u64,
+    /// Collateral pool whose positions are liquidated
+    pub account: MarginAccount,
+    /// Margin at the moment the liquidation started
+    pub margin: MarginState,
+}
+
+/// Aggregated acknowledgement of a mass quote
+#[derive(Debug, Clone)]
+pub struct MassQuoteAck {
+    pub id: u64,
+    pub participant_id: u64,
+    /// Result for each quote of the set (symbol, result), in the order they were sent
+    pub results: Vec<(u64, RiskEngineResult)>,
+}
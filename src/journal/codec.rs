@@ -0,0 +1,655 @@
+use crate::exchange::commands::{
+    AccountCommand, AdminCommand, BracketCommand, CancelCommand, MarginCommand, MassQuoteCommand,
+    MigrationCommand, OcoCommand, OrderCommand, OrderType, Peg, PegReference, QueryCommand,
+    QuoteCommand, Stop, TradeCommand, TrailingOffset, TrailingStop,
+};
+use crate::order_handling::order::OrderSide;
+use crate::risk::exposure_limits::{ExposureLimit, ExposureLimits};
+use crate::risk::order_limits::OrderLimits;
+use crate::risk::position_record::MarginMode;
+
+use std::convert::TryInto;
+
+use super::{JournalEntry, JournalRecord};
+
+/// Payload of a journal entry, the sequence number and the time followed by the record,
+/// little endian
+pub fn encode(sequence: u64, time: u64, record: &JournalRecord) -> Vec<u8> {
+    let mut encoder = Encoder { bytes: Vec::new() };
+    encoder.u64(sequence);
+    encoder.u64(time);
+    encoder.record(record);
+    encoder.bytes
+}
+
+/// Read a payload written by `encode`, None if it is malformed
+pub fn decode(bytes: &[u8]) -> Option<JournalEntry> {
+    let mut decoder = Decoder { bytes, position: 0 };
+    let entry = JournalEntry {
+        sequence: decoder.u64()?,
+        time: decoder.u64()?,
+        record: decoder.record()?,
+    };
+    if decoder.position != bytes.len() {
+        return None;
+    }
+    Some(entry)
+}
+
+struct Encoder {
+    bytes: Vec<u8>,
+}
+
+impl Encoder {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn i64(&mut self, value: i64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    fn option(&mut self, value: Option<u64>) {
+        match value {
+            Some(value) => {
+                self.u8(1);
+                self.u64(value);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn record(&mut self, record: &JournalRecord) {
+        match record {
+            JournalRecord::Command(command) => {
+                self.u8(0);
+                self.command(command);
+            }
+            JournalRecord::Migrate { participant_id, to } => {
+                self.u8(1);
+                self.u64(*participant_id);
+                self.u64(*to as u64);
+            }
+            JournalRecord::AddShard => self.u8(2),
+        }
+    }
+
+    fn command(&mut self, command: &OrderCommand) {
+        match command {
+            OrderCommand::Trade(trade) => {
+                self.u8(0);
+                self.trade(trade);
+            }
+            OrderCommand::Cancel(cancel) => {
+                self.u8(1);
+                self.u64(cancel.symbol);
+                self.u64(cancel.order_id);
+                self.u64(cancel.participant_id);
+                self.u64(cancel.remaining);
+            }
+            OrderCommand::Quote(quote) => {
+                self.u8(2);
+                self.quote(quote);
+            }
+            OrderCommand::MassQuote(mass_quote) => {
+                self.u8(3);
+                self.u64(mass_quote.id);
+                self.u64(mass_quote.participant_id);
+                self.bool(mass_quote.allow_partial);
+                self.u64(mass_quote.quotes.len() as u64);
+                for quote in &mass_quote.quotes {
+                    self.quote(quote);
+                }
+            }
+            OrderCommand::Oco(oco) => {
+                self.u8(4);
+                self.oco(oco);
+            }
+            OrderCommand::Bracket(bracket) => {
+                self.u8(5);
+                self.trade(&bracket.entry);
+                self.oco(&bracket.exit);
+            }
+            OrderCommand::Admin(command) => {
+                self.u8(6);
+                self.admin(command);
+            }
+            OrderCommand::Query(query) => {
+                self.u8(7);
+                match *query {
+                    QueryCommand::FundingHistory { symbol } => {
+                        self.u8(0);
+                        self.u64(symbol);
+                    }
+                    QueryCommand::Balances { participant_id } => {
+                        self.u8(1);
+                        self.u64(participant_id);
+                    }
+                }
+            }
+            OrderCommand::Margin(command) => {
+                self.u8(8);
+                match *command {
+                    MarginCommand::SetMode {
+                        participant_id,
+                        symbol,
+                        mode,
+                    } => {
+                        self.u8(0);
+                        self.u64(participant_id);
+                        self.u64(symbol);
+                        self.u8(match mode {
+                            MarginMode::Cross => 0,
+                            MarginMode::Isolated => 1,
+                        });
+                    }
+                    MarginCommand::Transfer {
+                        participant_id,
+                        from,
+                        to,
+                        amount,
+                    } => {
+                        self.u8(1);
+                        self.u64(participant_id);
+                        self.option(from);
+                        self.option(to);
+                        self.u64(amount);
+                    }
+                }
+            }
+            OrderCommand::Account(command) => {
+                self.u8(9);
+                match *command {
+                    AccountCommand::CreateParticipant { participant_id } => {
+                        self.u8(0);
+                        self.u64(participant_id);
+                    }
+                    AccountCommand::Deposit {
+                        participant_id,
+                        asset,
+                        amount,
+                    } => {
+                        self.u8(1);
+                        self.u64(participant_id);
+                        self.u64(asset as u64);
+                        self.u64(amount);
+                    }
+                    AccountCommand::Withdraw {
+                        participant_id,
+                        asset,
+                        amount,
+                    } => {
+                        self.u8(2);
+                        self.u64(participant_id);
+                        self.u64(asset as u64);
+                        self.u64(amount);
+                    }
+                    AccountCommand::Adjust {
+                        participant_id,
+                        asset,
+                        amount,
+                    } => {
+                        self.u8(3);
+                        self.u64(participant_id);
+                        self.u64(asset as u64);
+                        self.i64(amount);
+                    }
+                }
+            }
+            OrderCommand::Migration(command) => {
+                self.u8(10);
+                let (tag, id, shard) = match *command {
+                    MigrationCommand::MoveIn {
+                        participant_id,
+                        from,
+                    } => (0, participant_id, from),
+                    MigrationCommand::MoveOut { participant_id, to } => (1, participant_id, to),
+                    MigrationCommand::Flush {
+                        participant_id,
+                        shard,
+                    } => (2, participant_id, shard),
+                    MigrationCommand::Seed { to, shards } => (3, shards as u64, to),
+                };
+                self.u8(tag);
+                // The participant, or the shard count when seeding
+                self.u64(id);
+                self.u64(shard as u64);
+            }
+        }
+    }
+
+    fn trade(&mut self, trade: &TradeCommand) {
+        self.u64(trade.id);
+        self.u64(trade.participant_id);
+        self.u64(trade.symbol);
+        self.u8(match trade.side {
+            OrderSide::BID => 0,
+            OrderSide::ASK => 1,
+        });
+        self.u64(trade.volume);
+        self.u64(trade.limit);
+        self.bool(trade.immediate_or_cancel);
+        match trade.order_type {
+            OrderType::Limit => self.u8(0),
+            OrderType::Pegged(peg) => {
+                self.u8(1);
+                self.u8(match peg.reference {
+                    PegReference::Primary => 0,
+                    PegReference::Market => 1,
+                    PegReference::Midpoint => 2,
+                });
+                self.i64(peg.offset);
+            }
+            OrderType::TrailingStop(trailing) => {
+                self.u8(2);
+                match trailing.offset {
+                    TrailingOffset::Fixed(offset) => {
+                        self.u8(0);
+                        self.u64(offset);
+                    }
+                    TrailingOffset::Percentage(basis_points) => {
+                        self.u8(1);
+                        self.u64(basis_points);
+                    }
+                }
+                self.bool(trailing.market);
+            }
+            OrderType::Stop(stop) => {
+                self.u8(3);
+                self.u64(stop.trigger);
+                self.bool(stop.market);
+            }
+        }
+        self.bool(trade.hidden);
+        self.u64(trade.min_quantity);
+        self.bool(trade.reduce_only);
+        self.bool(trade.close_position);
+    }
+
+    fn quote(&mut self, quote: &QuoteCommand) {
+        self.u64(quote.participant_id);
+        self.u64(quote.symbol);
+        self.u64(quote.bid_id);
+        self.u64(quote.bid_limit);
+        self.u64(quote.bid_volume);
+        self.u64(quote.ask_id);
+        self.u64(quote.ask_limit);
+        self.u64(quote.ask_volume);
+    }
+
+    fn oco(&mut self, oco: &OcoCommand) {
+        self.trade(&oco.first);
+        self.trade(&oco.second);
+    }
+
+    fn admin(&mut self, command: &AdminCommand) {
+        match *command {
+            AdminCommand::ExpireOption {
+                symbol,
+                settlement_price,
+            } => {
+                self.u8(0);
+                self.u64(symbol);
+                self.u64(settlement_price);
+            }
+            AdminCommand::MarkPrice { symbol, price } => {
+                self.u8(1);
+                self.u64(symbol);
+                self.u64(price);
+            }
+            AdminCommand::Funding {
+                symbol,
+                time,
+                mark_price,
+                index_price,
+            } => {
+                self.u8(2);
+                self.u64(symbol);
+                self.u64(time);
+                self.u64(mark_price);
+                self.u64(index_price);
+            }
+            AdminCommand::RollDay => self.u8(3),
+            AdminCommand::SymbolLimits { symbol, limits } => {
+                self.u8(4);
+                self.u64(symbol);
+                self.order_limits(&limits);
+            }
+            AdminCommand::ParticipantLimits {
+                participant_id,
+                limits,
+            } => {
+                self.u8(5);
+                self.u64(participant_id);
+                self.order_limits(&limits);
+            }
+            AdminCommand::ExposureLimits {
+                participant_id,
+                limits,
+            } => {
+                self.u8(6);
+                self.u64(participant_id);
+                for limit in [
+                    limits.net_position,
+                    limits.gross_position,
+                    limits.open_orders,
+                ] {
+                    self.option(limit.soft);
+                    self.option(limit.hard);
+                }
+            }
+            AdminCommand::InsuranceDeposit {
+                shard,
+                asset,
+                amount,
+            } => {
+                self.u8(7);
+                self.u64(shard);
+                self.u64(asset as u64);
+                self.u64(amount);
+            }
+            AdminCommand::Halt { symbol } => {
+                self.u8(8);
+                self.u64(symbol);
+            }
+        }
+    }
+
+    fn order_limits(&mut self, limits: &OrderLimits) {
+        self.option(limits.max_volume);
+        self.option(limits.max_notional);
+        self.option(limits.max_price_deviation);
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn u8(&mut self) -> Option<u8> {
+        let value = *self.bytes.get(self.position)?;
+        self.position += 1;
+        Some(value)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        let bytes = self.bytes.get(self.position..self.position + 8)?;
+        self.position += 8;
+        Some(u64::from_le_bytes(bytes.try_into().ok()?))
+    }
+
+    fn i64(&mut self) -> Option<i64> {
+        self.u64().map(|value| value as i64)
+    }
+
+    fn bool(&mut self) -> Option<bool> {
+        match self.u8()? {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+
+    fn option(&mut self) -> Option<Option<u64>> {
+        match self.u8()? {
+            0 => Some(None),
+            1 => Some(Some(self.u64()?)),
+            _ => None,
+        }
+    }
+
+    fn record(&mut self) -> Option<JournalRecord> {
+        Some(match self.u8()? {
+            0 => JournalRecord::Command(Box::new(self.command()?)),
+            1 => JournalRecord::Migrate {
+                participant_id: self.u64()?,
+                to: self.u64()? as usize,
+            },
+            2 => JournalRecord::AddShard,
+            _ => return None,
+        })
+    }
+
+    fn command(&mut self) -> Option<OrderCommand> {
+        Some(match self.u8()? {
+            0 => OrderCommand::Trade(self.trade()?),
+            1 => OrderCommand::Cancel(CancelCommand {
+                symbol: self.u64()?,
+                order_id: self.u64()?,
+                participant_id: self.u64()?,
+                remaining: self.u64()?,
+            }),
+            2 => OrderCommand::Quote(self.quote()?),
+            3 => {
+                let id = self.u64()?;
+                let participant_id = self.u64()?;
+                let allow_partial = self.bool()?;
+                let count = self.u64()?;
+                let mut quotes = Vec::new();
+                for _ in 0..count {
+                    quotes.push(self.quote()?);
+                }
+                OrderCommand::MassQuote(MassQuoteCommand {
+                    id,
+                    participant_id,
+                    allow_partial,
+                    quotes,
+                })
+            }
+            4 => OrderCommand::Oco(self.oco()?),
+            5 => OrderCommand::Bracket(BracketCommand {
+                entry: self.trade()?,
+                exit: self.oco()?,
+            }),
+            6 => OrderCommand::Admin(self.admin()?),
+            7 => OrderCommand::Query(match self.u8()? {
+                0 => QueryCommand::FundingHistory {
+                    symbol: self.u64()?,
+                },
+                1 => QueryCommand::Balances {
+                    participant_id: self.u64()?,
+                },
+                _ => return None,
+            }),
+            8 => OrderCommand::Margin(match self.u8()? {
+                0 => MarginCommand::SetMode {
+                    participant_id: self.u64()?,
+                    symbol: self.u64()?,
+                    mode: match self.u8()? {
+                        0 => MarginMode::Cross,
+                        1 => MarginMode::Isolated,
+                        _ => return None,
+                    },
+                },
+                1 => MarginCommand::Transfer {
+                    participant_id: self.u64()?,
+                    from: self.option()?,
+                    to: self.option()?,
+                    amount: self.u64()?,
+                },
+                _ => return None,
+            }),
+            9 => OrderCommand::Account(match self.u8()? {
+                0 => AccountCommand::CreateParticipant {
+                    participant_id: self.u64()?,
+                },
+                1 => AccountCommand::Deposit {
+                    participant_id: self.u64()?,
+                    asset: self.u64()? as usize,
+                    amount: self.u64()?,
+                },
+                2 => AccountCommand::Withdraw {
+                    participant_id: self.u64()?,
+                    asset: self.u64()? as usize,
+                    amount: self.u64()?,
+                },
+                3 => AccountCommand::Adjust {
+                    participant_id: self.u64()?,
+                    asset: self.u64()? as usize,
+                    amount: self.i64()?,
+                },
+                _ => return None,
+            }),
+            10 => {
+                let tag = self.u8()?;
+                let participant_id = self.u64()?;
+                let shard = self.u64()? as usize;
+                OrderCommand::Migration(match tag {
+                    0 => MigrationCommand::MoveIn {
+                        participant_id,
+                        from: shard,
+                    },
+                    1 => MigrationCommand::MoveOut {
+                        participant_id,
+                        to: shard,
+                    },
+                    2 => MigrationCommand::Flush {
+                        participant_id,
+                        shard,
+                    },
+                    3 => MigrationCommand::Seed {
+                        to: shard,
+                        shards: participant_id as usize,
+                    },
+                    _ => return None,
+                })
+            }
+            _ => return None,
+        })
+    }
+
+    fn trade(&mut self) -> Option<TradeCommand> {
+        Some(TradeCommand {
+            id: self.u64()?,
+            participant_id: self.u64()?,
+            symbol: self.u64()?,
+            side: match self.u8()? {
+                0 => OrderSide::BID,
+                1 => OrderSide::ASK,
+                _ => return None,
+            },
+            volume: self.u64()?,
+            limit: self.u64()?,
+            immediate_or_cancel: self.bool()?,
+            order_type: match self.u8()? {
+                0 => OrderType::Limit,
+                1 => OrderType::Pegged(Peg {
+                    reference: match self.u8()? {
+                        0 => PegReference::Primary,
+                        1 => PegReference::Market,
+                        2 => PegReference::Midpoint,
+                        _ => return None,
+                    },
+                    offset: self.i64()?,
+                }),
+                2 => OrderType::TrailingStop(TrailingStop {
+                    offset: match self.u8()? {
+                        0 => TrailingOffset::Fixed(self.u64()?),
+                        1 => TrailingOffset::Percentage(self.u64()?),
+                        _ => return None,
+                    },
+                    market: self.bool()?,
+                }),
+                3 => OrderType::Stop(Stop {
+                    trigger: self.u64()?,
+                    market: self.bool()?,
+                }),
+                _ => return None,
+            },
+            hidden: self.bool()?,
+            min_quantity: self.u64()?,
+            reduce_only: self.bool()?,
+            close_position: self.bool()?,
+        })
+    }
+
+    fn quote(&mut self) -> Option<QuoteCommand> {
+        Some(QuoteCommand {
+            participant_id: self.u64()?,
+            symbol: self.u64()?,
+            bid_id: self.u64()?,
+            bid_limit: self.u64()?,
+            bid_volume: self.u64()?,
+            ask_id: self.u64()?,
+            ask_limit: self.u64()?,
+            ask_volume: self.u64()?,
+        })
+    }
+
+    fn oco(&mut self) -> Option<OcoCommand> {
+        Some(OcoCommand {
+            first: self.trade()?,
+            second: self.trade()?,
+        })
+    }
+
+    fn admin(&mut self) -> Option<AdminCommand> {
+        Some(match self.u8()? {
+            0 => AdminCommand::ExpireOption {
+                symbol: self.u64()?,
+                settlement_price: self.u64()?,
+            },
+            1 => AdminCommand::MarkPrice {
+                symbol: self.u64()?,
+                price: self.u64()?,
+            },
+            2 => AdminCommand::Funding {
+                symbol: self.u64()?,
+                time: self.u64()?,
+                mark_price: self.u64()?,
+                index_price: self.u64()?,
+            },
+            3 => AdminCommand::RollDay,
+            4 => AdminCommand::SymbolLimits {
+                symbol: self.u64()?,
+                limits: self.order_limits()?,
+            },
+            5 => AdminCommand::ParticipantLimits {
+                participant_id: self.u64()?,
+                limits: self.order_limits()?,
+            },
+            6 => AdminCommand::ExposureLimits {
+                participant_id: self.u64()?,
+                limits: ExposureLimits {
+                    net_position: self.exposure_limit()?,
+                    gross_position: self.exposure_limit()?,
+                    open_orders: self.exposure_limit()?,
+                },
+            },
+            7 => AdminCommand::InsuranceDeposit {
+                shard: self.u64()?,
+                asset: self.u64()? as usize,
+                amount: self.u64()?,
+            },
+            8 => AdminCommand::Halt {
+                symbol: self.u64()?,
+            },
+            _ => return None,
+        })
+    }
+
+    fn order_limits(&mut self) -> Option<OrderLimits> {
+        Some(OrderLimits {
+            max_volume: self.option()?,
+            max_notional: self.option()?,
+            max_price_deviation: self.option()?,
+        })
+    }
+
+    fn exposure_limit(&mut self) -> Option<ExposureLimit> {
+        Some(ExposureLimit {
+            soft: self.option()?,
+            hard: self.option()?,
+        })
+    }
+}
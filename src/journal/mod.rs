@@ -0,0 +1,611 @@
+pub mod codec;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crossbeam::channel::{unbounded, RecvTimeoutError, Sender};
+use log::error;
+
+use crate::exchange::commands::OrderCommand;
+
+/// Length and checksum in front of every entry
+const FRAME_HEADER: usize = 8;
+const SEGMENT_EXTENSION: &str = "journal";
+
+/// When the journal forces its writes to disk
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// After every entry, no command that was forwarded is lost
+    Always,
+    /// Once per interval by a thread of the journal,
+    /// a crash of the machine loses the entries since the last sync
+    Interval(Duration),
+    /// Left to the operating system
+    Never,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JournalSettings {
+    pub directory: PathBuf,
+    /// Size in bytes after which the next entry starts a new segment file
+    pub segment_size: u64,
+    pub fsync: FsyncPolicy,
+}
+
+/// Something the exchange was asked to do
+#[derive(Clone, Debug)]
+pub enum JournalRecord {
+    Command(Box<OrderCommand>),
+    Migrate { participant_id: u64, to: usize },
+    AddShard,
+}
+
+impl JournalRecord {
+    /// Queries change nothing, replaying them would only answer them again
+    pub fn changes_state(&self) -> bool {
+        match self {
+            JournalRecord::Command(command) => !matches!(**command, OrderCommand::Query(_)),
+            JournalRecord::Migrate { .. } | JournalRecord::AddShard => true,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct JournalEntry {
+    pub sequence: u64,
+    /// Nanoseconds since the Unix epoch when the exchange was asked
+    pub time: u64,
+    pub record: JournalRecord,
+}
+
+#[derive(Debug)]
+pub enum JournalError {
+    Io(io::Error),
+    /// An entry before the end of the journal failed its checksum, could not be decoded
+    /// or is out of sequence
+    Corrupt {
+        segment: PathBuf,
+        offset: u64,
+    },
+    /// A write failed earlier and its torn entry could not be cut off,
+    /// entries behind it could not be read back
+    Poisoned,
+}
+
+impl From<io::Error> for JournalError {
+    fn from(error: io::Error) -> Self {
+        JournalError::Io(error)
+    }
+}
+
+/// Monotonic nanoseconds since the Unix epoch, the time records are journaled with.
+///
+/// The wall clock is read once at start, so a wall clock that is set back
+/// can not refill the rate limits.
+#[derive(Copy, Clone, Debug)]
+pub struct Clock {
+    epoch: u64,
+    started: Instant,
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Clock {
+            epoch: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_nanos() as u64),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn now(&self) -> u64 {
+        self.epoch + self.started.elapsed().as_nanos() as u64
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut crc = index as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    table
+}
+
+/// CRC-32 as used by zlib
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc = CRC_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+/// Entries of one segment and the length of its valid part.
+/// The last segment may end in a torn entry, left by a crash during a write.
+fn read_segment(
+    path: &Path,
+    first_sequence: u64,
+    last: bool,
+) -> Result<(Vec<JournalEntry>, u64), JournalError> {
+    let bytes = fs::read(path)?;
+    let corrupt = |offset: usize| JournalError::Corrupt {
+        segment: path.to_path_buf(),
+        offset: offset as u64,
+    };
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let header = match bytes.get(offset..offset + FRAME_HEADER) {
+            Some(header) => header,
+            None if last => break,
+            None => return Err(corrupt(offset)),
+        };
+        let length = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let checksum = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        let end = offset + FRAME_HEADER + length;
+        let payload = match bytes.get(offset + FRAME_HEADER..end) {
+            Some(payload) => payload,
+            None if last => break,
+            None => return Err(corrupt(offset)),
+        };
+        if crc32(payload) != checksum {
+            if last && end == bytes.len() {
+                break;
+            }
+            return Err(corrupt(offset));
+        }
+        match codec::decode(payload) {
+            Some(entry) if entry.sequence == first_sequence + entries.len() as u64 => {
+                entries.push(entry)
+            }
+            _ => return Err(corrupt(offset)),
+        }
+        offset = end;
+    }
+    Ok((entries, offset as u64))
+}
+
+/// Segment files of a journal directory by their first sequence number
+fn segments(directory: &Path) -> Result<Vec<(u64, PathBuf)>, JournalError> {
+    let mut segments = Vec::new();
+    for file in fs::read_dir(directory)? {
+        let path = file?.path();
+        if path
+            .extension()
+            .is_none_or(|extension| extension != SEGMENT_EXTENSION)
+        {
+            continue;
+        }
+        if let Some(sequence) = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse().ok())
+        {
+            segments.push((sequence, path));
+        }
+    }
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+fn segment_path(directory: &Path, first_sequence: u64) -> PathBuf {
+    directory.join(format!("{:020}.{}", first_sequence, SEGMENT_EXTENSION))
+}
+
+/// Sync the segments every interval until the journal is dropped.
+/// The journal sends the file of every segment it starts.
+fn spawn_syncer(file: File, interval: Duration) -> Sender<File> {
+    let (sender, receiver) = unbounded::<File>();
+    thread::spawn(move || {
+        let mut file = file;
+        loop {
+            let next = receiver.recv_timeout(interval);
+            if let Err(error) = file.sync_data() {
+                error!("Syncing the journal failed: {:?}", error);
+            }
+            match next {
+                Ok(segment) => file = segment,
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    });
+    sender
+}
+
+/// Write-ahead journal of everything the exchange was asked to do, numbered from 1.
+///
+/// The entries are split over segment files named after the sequence number of their first entry.
+/// Every entry is framed by the length and the CRC-32 of its payload.
+pub struct Journal {
+    settings: JournalSettings,
+    file: File,
+    /// Bytes in the current segment
+    segment_length: u64,
+    next_sequence: u64,
+    /// Takes the new segments to the thread syncing them, None unless the fsync policy
+    /// is an interval
+    syncer: Option<Sender<File>>,
+    poisoned: bool,
+}
+
+impl Journal {
+    /// Open the journal in a directory, created if it does not exist.
+    /// A torn entry at the end is cut off.
+    pub fn open(settings: JournalSettings) -> Result<Journal, JournalError> {
+        fs::create_dir_all(&settings.directory)?;
+        let (path, segment_length, next_sequence) = match segments(&settings.directory)?.pop() {
+            Some((first_sequence, path)) => {
+                let (entries, length) = read_segment(&path, first_sequence, true)?;
+                OpenOptions::new()
+                    .write(true)
+                    .open(&path)?
+                    .set_len(length)?;
+                (path, length, first_sequence + entries.len() as u64)
+            }
+            None => (segment_path(&settings.directory, 1), 0, 1),
+        };
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let syncer = match settings.fsync {
+            FsyncPolicy::Interval(interval) => Some(spawn_syncer(file.try_clone()?, interval)),
+            FsyncPolicy::Always | FsyncPolicy::Never => None,
+        };
+        Ok(Journal {
+            settings,
+            file,
+            segment_length,
+            next_sequence,
+            syncer,
+            poisoned: false,
+        })
+    }
+    /// Every entry of the journal in a directory, in sequence order
+    pub fn read<P: AsRef<Path>>(directory: P) -> Result<Vec<JournalEntry>, JournalError> {
+        let segments = segments(directory.as_ref())?;
+        let mut entries: Vec<JournalEntry> = Vec::new();
+        for (index, (first_sequence, path)) in segments.iter().enumerate() {
+            let expected = entries
+                .last()
+                .map_or(*first_sequence, |entry| entry.sequence + 1);
+            if *first_sequence != expected {
+                return Err(JournalError::Corrupt {
+                    segment: path.clone(),
+                    offset: 0,
+                });
+            }
+            let last = index + 1 == segments.len();
+            entries.extend(read_segment(path, *first_sequence, last)?.0);
+        }
+        Ok(entries)
+    }
+
+    /// Sequence number of the next entry
+    pub fn next_sequence(&self) -> u64 {
+        self.next_sequence
+    }
+
+    /// Append a record with its time and return its sequence number,
+    /// once it is written and synced as the fsync policy asks.
+    /// A record that could not be appended leaves nothing in the journal.
+    pub fn append(&mut self, time: u64, record: &JournalRecord) -> Result<u64, JournalError> {
+        if self.poisoned {
+            return Err(JournalError::Poisoned);
+        }
+        if self.segment_length >= self.settings.segment_size {
+            self.start_segment()?;
+        }
+        let sequence = self.next_sequence;
+        let payload = codec::encode(sequence, time, record);
+        let mut frame = Vec::with_capacity(FRAME_HEADER + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&crc32(&payload).to_le_bytes());
+        frame.extend_from_slice(&payload);
+        let written = self.file.write_all(&frame).and_then(|()| {
+            if self.settings.fsync == FsyncPolicy::Always {
+                self.file.sync_data()?;
+            }
+            Ok(())
+        });
+        if let Err(error) = written {
+            // Later entries would land behind the torn one
+            if self.file.set_len(self.segment_length).is_err() {
+                self.poisoned = true;
+            }
+            return Err(error.into());
+        }
+        self.segment_length += frame.len() as u64;
+        self.next_sequence += 1;
+        Ok(sequence)
+    }
+
+    /// Close the current segment and continue in a new one
+    fn start_segment(&mut self) -> Result<(), JournalError> {
+        if self.settings.fsync != FsyncPolicy::Never {
+            self.file.sync_data()?;
+        }
+        let path = segment_path(&self.settings.directory, self.next_sequence);
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        if self.settings.fsync != FsyncPolicy::Never {
+            // The new file name has to reach the disk as well
+            File::open(&self.settings.directory)?.sync_all()?;
+        }
+        if let Some(syncer) = &self.syncer {
+            let _ = syncer.send(file.try_clone()?);
+        }
+        self.file = file;
+        self.segment_length = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exchange::commands::*;
+    use crate::order_handling::order::OrderSide;
+    use crate::risk::exposure_limits::{ExposureLimit, ExposureLimits};
+    use crate::risk::order_limits::OrderLimits;
+    use crate::risk::position_record::MarginMode;
+
+    fn trade(id: u64, side: OrderSide, order_type: OrderType) -> TradeCommand {
+        TradeCommand {
+            id,
+            participant_id: 7,
+            symbol: 1,
+            side,
+            volume: 30,
+            limit: 120,
+            immediate_or_cancel: id.is_multiple_of(2),
+            order_type,
+            hidden: id.is_multiple_of(3),
+            min_quantity: 5,
+            reduce_only: id.is_multiple_of(5),
+            close_position: false,
+        }
+    }
+
+    /// A record of every kind, with every kind of order
+    fn records() -> Vec<JournalRecord> {
+        let stop = OrderType::Stop(Stop {
+            trigger: 110,
+            market: true,
+        });
+        let trailing = OrderType::TrailingStop(TrailingStop {
+            offset: TrailingOffset::Percentage(150),
+            market: false,
+        });
+        let pegged = OrderType::Pegged(Peg {
+            reference: PegReference::Midpoint,
+            offset: -3,
+        });
+        let quote = QuoteCommand {
+            participant_id: 7,
+            symbol: 2,
+            bid_id: 20,
+            bid_limit: 99,
+            bid_volume: 4,
+            ask_id: 21,
+            ask_limit: 101,
+            ask_volume: 0,
+        };
+        let oco = OcoCommand {
+            first: trade(11, OrderSide::ASK, OrderType::Limit),
+            second: trade(12, OrderSide::ASK, stop),
+        };
+        let commands = vec![
+            OrderCommand::Trade(trade(1, OrderSide::BID, OrderType::Limit)),
+            OrderCommand::Trade(trade(2, OrderSide::ASK, pegged)),
+            OrderCommand::Trade(trade(3, OrderSide::BID, trailing)),
+            OrderCommand::Cancel(CancelCommand {
+                symbol: 1,
+                order_id: 2,
+                participant_id: 7,
+                remaining: 10,
+            }),
+            OrderCommand::Quote(quote),
+            OrderCommand::MassQuote(MassQuoteCommand {
+                id: 4,
+                participant_id: 7,
+                allow_partial: true,
+                quotes: vec![quote, quote],
+            }),
+            OrderCommand::Oco(oco),
+            OrderCommand::Bracket(BracketCommand {
+                entry: trade(10, OrderSide::BID, OrderType::Limit),
+                exit: oco,
+            }),
+            OrderCommand::Admin(AdminCommand::ExpireOption {
+                symbol: 3,
+                settlement_price: 250,
+            }),
+            OrderCommand::Admin(AdminCommand::Funding {
+                symbol: 4,
+                time: 1_700_000_000,
+                mark_price: 101,
+                index_price: 100,
+            }),
+            OrderCommand::Admin(AdminCommand::RollDay),
+            OrderCommand::Admin(AdminCommand::Halt { symbol: 2 }),
+            OrderCommand::Admin(AdminCommand::InsuranceDeposit {
+                shard: 1,
+                asset: 2,
+                amount: 500,
+            }),
+            OrderCommand::Admin(AdminCommand::SymbolLimits {
+                symbol: 1,
+                limits: OrderLimits {
+                    max_volume: Some(100),
+                    max_notional: None,
+                    max_price_deviation: Some(500),
+                },
+            }),
+            OrderCommand::Admin(AdminCommand::ExposureLimits {
+                participant_id: 7,
+                limits: ExposureLimits {
+                    net_position: ExposureLimit {
+                        soft: Some(10),
+                        hard: Some(20),
+                    },
+                    ..ExposureLimits::default()
+                },
+            }),
+            OrderCommand::Margin(MarginCommand::SetMode {
+                participant_id: 7,
+                symbol: 4,
+                mode: MarginMode::Isolated,
+            }),
+            OrderCommand::Margin(MarginCommand::Transfer {
+                participant_id: 7,
+                from: None,
+                to: Some(4),
+                amount: 50,
+            }),
+            OrderCommand::Account(AccountCommand::Adjust {
+                participant_id: 7,
+                asset: 1,
+                amount: -25,
+            }),
+            OrderCommand::Migration(MigrationCommand::Seed { to: 2, shards: 3 }),
+        ];
+        let mut records: Vec<JournalRecord> = commands
+            .into_iter()
+            .map(|command| JournalRecord::Command(Box::new(command)))
+            .collect();
+        records.push(JournalRecord::Migrate {
+            participant_id: 7,
+            to: 1,
+        });
+        records.push(JournalRecord::AddShard);
+        records
+    }
+
+    fn settings(name: &str) -> JournalSettings {
+        let directory =
+            std::env::temp_dir().join(format!("journal-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        JournalSettings {
+            directory,
+            segment_size: 512,
+            fsync: FsyncPolicy::Never,
+        }
+    }
+
+    /// Write the records, the segments roll over on the way
+    fn write(settings: &JournalSettings) -> Vec<JournalRecord> {
+        let mut journal = Journal::open(settings.clone()).unwrap();
+        let records = records();
+        for (time, record) in records.iter().enumerate() {
+            assert_eq!(
+                journal.append(time as u64, record).unwrap(),
+                time as u64 + 1
+            );
+        }
+        records
+    }
+
+    #[test]
+    fn records_survive_encoding() {
+        for (sequence, record) in records().into_iter().enumerate() {
+            let entry = codec::decode(&codec::encode(sequence as u64, 42, &record)).unwrap();
+            assert_eq!(entry.sequence, sequence as u64);
+            assert_eq!(entry.time, 42);
+            assert_eq!(format!("{:?}", entry.record), format!("{:?}", record));
+        }
+        let payload = codec::encode(1, 42, &JournalRecord::AddShard);
+        assert!(codec::decode(&payload[..payload.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn journal_reads_back_what_was_appended() {
+        let settings = settings("read");
+        let records = write(&settings);
+        assert!(segments(&settings.directory).unwrap().len() > 1);
+
+        let entries = Journal::read(&settings.directory).unwrap();
+        assert_eq!(entries.len(), records.len());
+        for (index, (entry, record)) in entries.iter().zip(&records).enumerate() {
+            assert_eq!(entry.sequence, index as u64 + 1);
+            assert_eq!(entry.time, index as u64);
+            assert_eq!(format!("{:?}", entry.record), format!("{:?}", record));
+        }
+        assert_eq!(
+            Journal::open(settings.clone()).unwrap().next_sequence(),
+            records.len() as u64 + 1
+        );
+        fs::remove_dir_all(&settings.directory).unwrap();
+    }
+
+    #[test]
+    fn torn_tail_is_cut_off() {
+        let settings = settings("torn");
+        let records = write(&settings);
+        let (_, last) = segments(&settings.directory).unwrap().pop().unwrap();
+        let length = fs::metadata(&last).unwrap().len();
+        // A crash in the middle of the last entry
+        let mut file = OpenOptions::new().append(true).open(&last).unwrap();
+        file.write_all(&[40, 0, 0, 0, 1, 2]).unwrap();
+        drop(file);
+
+        assert_eq!(
+            Journal::read(&settings.directory).unwrap().len(),
+            records.len()
+        );
+        let mut journal = Journal::open(settings.clone()).unwrap();
+        assert_eq!(fs::metadata(&last).unwrap().len(), length);
+        journal.append(0, &JournalRecord::AddShard).unwrap();
+        drop(journal);
+        assert_eq!(
+            Journal::read(&settings.directory).unwrap().len(),
+            records.len() + 1
+        );
+        fs::remove_dir_all(&settings.directory).unwrap();
+    }
+
+    #[test]
+    fn corruption_before_the_end_is_reported() {
+        let settings = settings("corrupt");
+        write(&settings);
+        let (_, first) = segments(&settings.directory).unwrap().remove(0);
+        let mut bytes = fs::read(&first).unwrap();
+        bytes[FRAME_HEADER + 3] ^= 1;
+        fs::write(&first, bytes).unwrap();
+
+        assert!(matches!(
+            Journal::read(&settings.directory),
+            Err(JournalError::Corrupt { segment, offset: 0 }) if segment == first
+        ));
+        fs::remove_dir_all(&settings.directory).unwrap();
+    }
+
+    #[test]
+    fn queries_are_not_journaled() {
+        let query = JournalRecord::Command(Box::new(OrderCommand::Query(QueryCommand::Balances {
+            participant_id: 7,
+        })));
+        assert!(!query.changes_state());
+        assert!(JournalRecord::AddShard.changes_state());
+    }
+}
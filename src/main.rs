@@ -11,7 +11,9 @@
 
 //mod bit_set;
 pub mod exchange;
+pub mod journal;
 pub mod order_handling;
+pub mod persistence;
 pub mod processor;
 pub mod risk;
 use crate::exchange::asset::Symbol;
@@ -83,6 +85,7 @@ fn build_random_order_command(
             symbol: 0,
             order_id: unsafe { CANCELED },
             participant_id: 0,
+            remaining: 0,
         })
     } else {
         OrderCommand::Trade(TradeCommand {
@@ -97,11 +100,42 @@ fn build_random_order_command(
             },
             //immediate_or_cancel: rng.gen_range(0, 12) < 3,
             immediate_or_cancel: false,
+            order_type: OrderType::Limit,
+            hidden: false,
+            min_quantity: 0,
+            reduce_only: false,
+            close_position: false,
             id,
         })
     }
 }
 
+/// Log the reports of the exchange, so they do not pile up unread
+fn drain_reports(ex: &Exchange) {
+    let reports = ex.reports.clone();
+    thread::spawn(move || {
+        for report in reports.iter() {
+            log::debug!("Report: {:?}", report);
+        }
+    });
+}
+
+/// Create participants 0..count and deposit the amount of every asset for each of them
+fn fund_participants(ex: &mut Exchange, count: u64, assets: usize, amount: u64) {
+    for participant_id in 0..count {
+        ex.trade(OrderCommand::Account(AccountCommand::CreateParticipant {
+            participant_id,
+        }));
+        for asset in 0..assets {
+            ex.trade(OrderCommand::Account(AccountCommand::Deposit {
+                participant_id,
+                asset,
+                amount,
+            }));
+        }
+    }
+}
+
 fn benchmark_exchange() {
     let mut rng = thread_rng();
 
@@ -120,11 +154,16 @@ fn benchmark_exchange() {
     let settings = ExchangeSettings {
         symbols,
         risk_engine_shards: 4,
+        portfolio_margin: None,
+        fees: HashMap::new(),
+        rate_limits: None,
         db_sync_speed: Duration::from_micros(500),
         db_min_recv_timeout: Duration::from_micros(100),
     };
 
     let mut ex = Exchange::new(settings);
+    drain_reports(&ex);
+    fund_participants(&mut ex, 4, 3, 1000);
 
     let normal_bid = Normal::new(200f64, 15f64).unwrap();
     let normal_ask = Normal::new(230f64, 15f64).unwrap();
@@ -155,9 +194,14 @@ fn test() {
             quote_asset: 1,
         }],
         risk_engine_shards: 1,
+        portfolio_margin: None,
+        fees: HashMap::new(),
+        rate_limits: None,
         db_sync_speed: Duration::from_micros(500),
         db_min_recv_timeout: Duration::from_micros(100),
     });
+    drain_reports(&ex);
+    fund_participants(&mut ex, 4, 3, 1000);
 
     let t = OrderCommand::Trade(TradeCommand {
         id: 0,
@@ -167,6 +211,11 @@ fn test() {
         volume: 10,
         limit: 5,
         immediate_or_cancel: false,
+        order_type: OrderType::Limit,
+        hidden: false,
+        min_quantity: 0,
+        reduce_only: false,
+        close_position: false,
     });
     ex.trade(t);
 
@@ -178,6 +227,11 @@ fn test() {
         volume: 5,
         limit: 3,
         immediate_or_cancel: false,
+        order_type: OrderType::Limit,
+        hidden: false,
+        min_quantity: 0,
+        reduce_only: false,
+        close_position: false,
     });
 
     ex.trade(t);
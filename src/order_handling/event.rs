@@ -1,26 +1,87 @@
 use crate::exchange::asset::AssetId;
+use crate::order_handling::order::OrderSide;
+use crate::risk::participant::Balance;
 
 /// Events triggered by the matching engine and sent to the risk engines
 #[derive(Debug, Copy, Clone)]
 pub enum MatchingEngineEvent {
     ///How much volume was filled and how much Value was payed for it
-    /// order_id, volume, value
-    Filled(u64, u64, u64),
+    /// order_id, volume, value, whether the order was resting or incoming
+    Filled(u64, u64, u64, Liquidity),
     /// id
     Canceled(u64),
+    /// The book took volume out of an order that stays in it
+    /// order_id, volume
+    Reduced(u64, u64),
+    /// Fair price of a book, sent to every risk engine shard when it changes
+    /// symbol_id, price
+    FairPrice(u64, u64),
+    /// The order book sent every earlier event of a participant's orders,
+    /// answers a `MigrationCommand::Flush`
+    /// participant_id
+    Flushed(u64),
 }
 
-/// Events triggered by the matching engine and sent to the DB,
+/// Whether a fill was on the resting or the incoming side of a trade
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Liquidity {
+    Maker,
+    Taker,
+}
+
+/// Events triggered by the matching engine and the risk engines and sent to the DB,
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum DbEvent {
     Trade(Trade),
+    Order(OrderEvent),
+    Balance(BalanceChange),
+}
+
+/// Something that happened to an order, seen by the risk engine of its participant
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct OrderEvent {
+    pub order_id: u64,
+    pub participant_id: u64,
+    pub kind: OrderEventKind,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OrderEventKind {
+    /// Accepted by the risk engine and sent to its order book
+    Placed {
+        symbol: u64,
+        side: OrderSide,
+        limit: u64,
+        volume: u64,
+    },
+    Filled {
+        volume: u64,
+        value: u64,
+        liquidity: Liquidity,
+    },
+    /// Volume taken out of the order, the rest stays in the book
+    Reduced {
+        volume: u64,
+    },
+    Canceled,
+}
+
+/// Balance of a participant in one asset after it changed
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BalanceChange {
+    pub participant_id: u64,
+    pub asset: AssetId,
+    pub balance: Balance,
 }
 
 /// If volume is positive, the taker receives volume and pays value,
 /// If volume is negative, the taker receives that negative volume and pays that (hopefully negative) value
+/// Every execution is reported, including those against hidden orders
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Trade {
-    symbol: usize,
-    volume: i64,
-    value: i64,
-    taker_participant: usize,
-    maker_participant: usize,
+    pub symbol: usize,
+    pub volume: i64,
+    pub value: i64,
+    pub taker_participant: usize,
+    pub maker_participant: usize,
 }
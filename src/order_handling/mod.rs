@@ -2,4 +2,5 @@ pub mod order;
 pub mod order_book;
 pub mod order_bucket;
 pub mod public_list;
-pub mod event;
\ No newline at end of file
+pub mod event;
+pub mod stop_order;
\ No newline at end of file
@@ -4,10 +4,10 @@ use std::ops::Neg;
 use std::ptr::NonNull;
 use crossbeam::channel::Sender;
 
-use crate::exchange::commands::TradeCommand;
+use crate::exchange::commands::{OrderType, Peg, TradeCommand};
 use crate::risk::participant;
 
-use super::event::MatchingEngineEvent;
+use super::event::{Liquidity, MatchingEngineEvent};
 use super::order_bucket::OrderBucket;
 
 // #[derive(Debug)]
@@ -49,6 +49,15 @@ pub struct StandingOrder {
     pub id: u64,
     pub participant_id: u64,
 
+    /// Reference the limit of a pegged order follows
+    pub peg: Option<Peg>,
+    /// Limit given by the participant, a pegged order never crosses it
+    pub cap: u64,
+    /// Not displayed, matched after the displayed orders of the same price
+    pub hidden: bool,
+    /// Smallest volume this order trades at once, zero for no minimum
+    pub min_quantity: u64,
+
     pub next: Option<NonNull<Box<StandingOrder>>>,
     pub prev: Option<NonNull<Box<StandingOrder>>>,
 }
@@ -74,12 +83,23 @@ impl StandingOrder {
             id,
             participant_id,
 
+            peg: None,
+            cap: limit,
+            hidden: false,
+            min_quantity: 0,
+
             next: None,
             prev: None,
         }
     }
 
     pub fn remove_from_bucket(&mut self, bucket: &mut OrderBucket) {
+        if bucket
+            .first_hidden
+            .is_some_and(|hidden| unsafe { hidden.as_ref().id } == self.id)
+        {
+            bucket.first_hidden = self.next;
+        }
         match self.next {
             None => bucket.tail = self.prev,
             Some(mut n) => {
@@ -95,6 +115,9 @@ impl StandingOrder {
             }
         }
         bucket.len -= 1;
+        if !self.is_reference() {
+            bucket.unreferenced_len -= 1;
+        }
     }
 
     pub fn remaining_volume(&self) -> u64 {
@@ -105,12 +128,13 @@ impl StandingOrder {
     //Price ist just to set the filled_value correctly
     pub fn fill(&mut self, taker: &StandingOrder, price: u64, sender: &Sender<MatchingEngineEvent>) -> u64 {
         //println!("Own volume: {}, Incoming volume: {}", *self.remaining_volume(), *volume);
+        debug_assert!(self.accepts(taker.volume));
 
         if self.remaining_volume() <= taker.volume {
             let old_volume = self.remaining_volume();
             //Fill order completely
             self.volume = 0;
-            self.notify(old_volume, old_volume * price, sender);
+            self.notify(old_volume, old_volume * price, Liquidity::Maker, sender);
 
             //Return what did fit in
             old_volume
@@ -118,12 +142,25 @@ impl StandingOrder {
             //Fill as much as possible
             self.volume -= taker.volume;
 
-            self.notify(taker.volume, taker.volume * price, sender);
+            self.notify(taker.volume, taker.volume * price, Liquidity::Maker, sender);
             //Return volume, because everything fit in
             taker.volume
         }
     }
 
+    /// Whether pegged orders can refer to this order, only displayed orders that are not pegged can
+    pub fn is_reference(&self) -> bool {
+        self.peg.is_none() && !self.hidden
+    }
+
+    /// Whether this resting order may trade with a taker of the given volume
+    ///
+    /// An order with a minimum quantity only trades if the taker can fill at least the minimum,
+    /// or all of it once less than the minimum is left.
+    pub fn accepts(&self, taker_volume: u64) -> bool {
+        taker_volume >= std::cmp::min(self.min_quantity, self.remaining_volume())
+    }
+
     pub fn is_filled(&self) -> bool {
         self.remaining_volume() == 0
     }
@@ -141,23 +178,36 @@ impl StandingOrder {
 
     /// Notify the risk engine about any change to the order
     /// Execute this whenever the order state changes
-    pub fn notify(&self, fill_volume: u64, fill_value: u64, sender: &Sender<MatchingEngineEvent>) {
+    pub fn notify(
+        &self,
+        fill_volume: u64,
+        fill_value: u64,
+        liquidity: Liquidity,
+        sender: &Sender<MatchingEngineEvent>,
+    ) {
         let _ = sender.send(MatchingEngineEvent::Filled(
             self.id,
             fill_volume,
             fill_value,
+            liquidity,
         ));
     }
 }
 
 impl From<TradeCommand> for StandingOrder {
     fn from(value: TradeCommand) -> Self {
-        Self::new(
+        let mut order = Self::new(
             value.id,
             value.participant_id,
             value.limit,
             value.volume,
             value.side,
-        )
+        );
+        if let OrderType::Pegged(peg) = value.order_type {
+            order.peg = Some(peg);
+        }
+        order.hidden = value.hidden;
+        order.min_quantity = value.min_quantity;
+        order
     }
 }
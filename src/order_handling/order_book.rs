@@ -1,13 +1,16 @@
-use crate::exchange::commands::TradeCommand;
+use crate::exchange::commands::{
+    BracketCommand, OcoCommand, OrderCommand, OrderType, Peg, PegReference, QuoteCommand,
+    TradeCommand,
+};
 use crate::exchange::exchange_settings::ExchangeSettings;
 use crate::order_handling::order::*;
 use crate::order_handling::order_bucket::*;
-use crate::risk::router::risk_router;
+use crate::risk::router::ShardDirectory;
 extern crate libc;
 
 use crossbeam::channel::Sender;
 use fxhash::FxBuildHasher;
-use linked_hash_map::VacantEntry;
+use linked_hash_map::{LinkedHashMap, VacantEntry};
 use log::{debug, error, info, trace, warn};
 use std::alloc::{alloc, dealloc, Layout};
 use std::boxed;
@@ -21,15 +24,17 @@ use std::mem;
 use std::mem::MaybeUninit;
 use std::ops::Drop;
 use std::rc::Rc;
+use std::sync::Arc;
 use std::result::Result::*;
 use std::{collections::HashMap, ptr::NonNull};
 
 use crate::order_handling::public_list::*;
 
 use super::event::DbEvent;
-use super::event::MatchingEngineEvent;
+use super::event::{Liquidity, MatchingEngineEvent};
 use super::event::Trade;
 use super::order_bucket;
+use super::stop_order::StopOrder;
 
 const MAX_NUMBER_OF_ORDERS: usize = 10_000_000;
 const MAX_PRICE: usize = 2_000;
@@ -50,13 +55,50 @@ pub struct OrderBook {
     // bid_depth_fenwick_tree: Vec<u64>
     order_map: HashMap<u64, Box<StandingOrder>, FxBuildHasher>,
 
+    /// Ids of the resting pegged orders, in the order they were (re-)priced
+    pegged: LinkedHashMap<u64, (), FxBuildHasher>,
+    /// Reference prices (bid, ask) the pegged orders were last priced at
+    peg_reference: (Option<u64>, Option<u64>),
+
+    /// Stop orders waiting for their trigger, in the order they arrived
+    stops: LinkedHashMap<u64, StopOrder, FxBuildHasher>,
+    /// Price of the last trade on this book
+    pub last_trade_price: Option<u64>,
+    /// Prices traded since the stop orders were last updated, in order
+    trade_prices: Vec<u64>,
+
+    /// Both directions of every one-cancels-other link between two orders
+    links: HashMap<u64, u64, FxBuildHasher>,
+    /// Exit pairs of bracket entries that are not completely filled yet,
+    /// with the volume the entry filled so far
+    brackets: HashMap<u64, (OcoCommand, u64), FxBuildHasher>,
+    /// Exit pairs whose entry was filled, waiting to be inserted
+    activated: Vec<OcoCommand>,
+
+    /// Order ids of the standing quote of each participant (bid_id, ask_id)
+    quotes: HashMap<u64, (u64, u64), FxBuildHasher>,
+
+    /// The symbol stopped trading, every new order is canceled
+    pub closed: bool,
+    /// Fair price last sent to the risk engines
+    fair_price: Option<u64>,
+    /// Best displayed bid and ask the fair price was last computed from
+    displayed_top: (Option<u64>, Option<u64>),
+    /// The best displayed levels or the last trade may have changed since then
+    top_changed: bool,
+
     /// Next Order ID
     highest_id: u64,
 
     /// Store orders sorted by price
     pub bucket_array: [Box<OrderBucket>; MAX_PRICE],
 
-    event_senders: Vec<Sender<MatchingEngineEvent>>,
+    /// Risk engine shards of the participants
+    directory: Arc<ShardDirectory>,
+    /// Event channel of every shard the book knows of
+    senders: Vec<Sender<MatchingEngineEvent>>,
+    /// Shard of every participant the book saw, moved on by the flush handshake
+    shards: HashMap<u64, usize, FxBuildHasher>,
     db_sender: Sender<DbEvent>,
 
     settings: ExchangeSettings,
@@ -66,7 +108,7 @@ impl OrderBook {
     pub fn new(
         symbol_id: usize,
         settings: ExchangeSettings,
-        event_senders: Vec<Sender<MatchingEngineEvent>>,
+        directory: Arc<ShardDirectory>,
         db_sender: Sender<DbEvent>,
     ) -> OrderBook {
         let mut price = 0;
@@ -100,16 +142,72 @@ impl OrderBook {
                 MAX_NUMBER_OF_ORDERS,
                 FxBuildHasher::default(),
             ),
+            pegged: LinkedHashMap::with_hasher(FxBuildHasher::default()),
+            peg_reference: (None, None),
+            stops: LinkedHashMap::with_hasher(FxBuildHasher::default()),
+            last_trade_price: None,
+            trade_prices: Vec::new(),
+            links: HashMap::default(),
+            brackets: HashMap::default(),
+            activated: Vec::new(),
+            quotes: HashMap::default(),
+            closed: false,
+            fair_price: None,
+            displayed_top: (None, None),
+            top_changed: false,
             highest_id: 0,
             bucket_array: orders_array,
-            event_senders,
+            senders: directory.event_senders(),
+            shards: HashMap::default(),
+            directory,
             db_sender,
             settings,
         }
     }
 
+    /// Sender of the matching engine events of the participant's orders
     pub fn get_sender(&self, participant_id: u64) -> &Sender<MatchingEngineEvent> {
-        &self.event_senders[risk_router(&self.settings, &participant_id)]
+        match self.shards.get(&participant_id) {
+            Some(shard) => &self.senders[*shard],
+            None => panic!("Participant {} is not registered", participant_id),
+        }
+    }
+
+    /// Look up the shard of a participant the book has not seen yet
+    pub fn register(&mut self, participant_id: u64) {
+        if !self.shards.contains_key(&participant_id) {
+            let shard = self.directory.shard(participant_id);
+            self.follow(participant_id, shard);
+        }
+    }
+
+    fn follow(&mut self, participant_id: u64, shard: usize) {
+        if shard >= self.senders.len() {
+            self.refresh_senders();
+        }
+        self.shards.insert(participant_id, shard);
+    }
+
+    /// Pick up the shards added since, they get the current fair price right away
+    fn refresh_senders(&mut self) {
+        let known = self.senders.len();
+        self.senders = self.directory.event_senders();
+        if let Some(price) = self.fair_price {
+            for sender in &self.senders[known..] {
+                let _ = sender.send(MatchingEngineEvent::FairPrice(self.symbol_id as u64, price));
+            }
+        }
+    }
+
+    /// Tell the shard a participant moves away from that it got every event of its orders.
+    /// Later events go to the shard the directory points to now.
+    pub fn flush(&mut self, participant_id: u64, shard: usize) {
+        if shard >= self.senders.len() {
+            self.refresh_senders();
+        }
+        let _ = self.senders[shard].send(MatchingEngineEvent::Flushed(participant_id));
+        let shard = self.directory.shard(participant_id);
+        self.follow(participant_id, shard);
     }
 
     /// Try to instantly match an order as it is coming in
@@ -122,48 +220,64 @@ impl OrderBook {
         let mut filled_value = 0;
         let original_volume = order.volume;
 
-        while order.volume > 0 {
-            let best_price = match order.side {
-                OrderSide::ASK => {
-                    if self.max_bid_price < order.limit {
-                        break;
-                    } else {
-                        self.max_bid_price
-                    }
-                }
-                OrderSide::BID => {
-                    if self.min_ask_price > order.limit {
-                        break;
-                    } else {
-                        self.min_ask_price
-                    }
-                }
-            };
+        // An order with a minimum only trades if at least that much can be executed right now
+        if order.min_quantity > 0
+            && self.executable_volume(order) < min(order.min_quantity, order.volume)
+        {
+            return;
+        }
+
+        let mut best_price = match order.side {
+            OrderSide::ASK => self.max_bid_price,
+            OrderSide::BID => self.min_ask_price,
+        };
+        while order.volume > 0 && self.crosses(order, best_price) {
             // println!(
             //     "Best price: {}, Limit: {}, Side: {:?}",
             //     best_price, order.limit, order.side
             // );
-            let mut empty = true;
-            //Loop until bucket is empty
-            while let Some((matched_volume, canceled_order)) =
+            //Loop until no order of the bucket accepts the taker any more
+            while let Some((matched_volume, maker_id, maker_filled)) =
                 OrderBucket::match_orders(&order, self, best_price)
             {
-                //Loop gets entered at least once, so bucket is not empty
-                empty = false;
+                let maker_participant = self.order_map[&maker_id].participant_id;
+                self.publish_trade(order, maker_participant, matched_volume, best_price);
                 // println!("Matched volume: {}", matched_volume);
                 order.volume -= matched_volume;
                 filled_value += matched_volume * best_price;
-                if let Some(canceled_order) = canceled_order {
-                    self.cancel_order(canceled_order)
+                if self.last_trade_price != Some(best_price) {
+                    self.last_trade_price = Some(best_price);
+                    self.trade_prices.push(best_price);
+                    self.top_changed = true;
                 }
+                if maker_filled {
+                    self.remove_order(maker_id);
+                }
+                self.order_filled(maker_id, matched_volume, maker_filled);
                 if order.volume == 0 {
                     break;
                 }
             }
-            if empty {
-                match order.side {
-                    OrderSide::ASK => self.max_bid_price -= 1,
-                    OrderSide::BID => self.min_ask_price += 1,
+
+            // Orders whose minimum the taker could not meet keep their place,
+            // the best price only moves past empty buckets
+            let empty = self.bucket_array[best_price as usize].is_empty();
+            match order.side {
+                OrderSide::ASK => {
+                    if empty && best_price == self.max_bid_price {
+                        self.max_bid_price = best_price.saturating_sub(1);
+                    }
+                    // Market asks can run out of bids
+                    if best_price == 0 {
+                        break;
+                    }
+                    best_price -= 1;
+                }
+                OrderSide::BID => {
+                    if empty && best_price == self.min_ask_price {
+                        self.min_ask_price = best_price + 1;
+                    }
+                    best_price += 1;
                 }
             }
         }
@@ -173,20 +287,165 @@ impl OrderBook {
             order.notify(
                 original_volume - order.volume,
                 filled_value,
+                Liquidity::Taker,
                 self.get_sender(order.participant_id),
             );
+            let filled = original_volume - order.volume;
+            self.order_filled(order.id, filled, order.is_filled());
+        }
+    }
+
+    /// Whether an incoming order can trade with the other side at this price
+    fn crosses(&self, order: &StandingOrder, price: u64) -> bool {
+        price < self.max_price
+            && match order.side {
+                OrderSide::ASK => price >= order.limit,
+                OrderSide::BID => price <= order.limit,
+            }
+    }
+
+    /// Whether orders of the other side rest at a price the order can trade at
+    fn crossed_by(&self, order: &StandingOrder) -> bool {
+        self.prices(-order.side)
+            .take_while(|price| self.crosses(order, *price))
+            .any(|price| !self.bucket_array[price as usize].is_empty())
+    }
+
+    /// How much of an incoming order could be executed right now
+    fn executable_volume(&self, order: &StandingOrder) -> u64 {
+        let mut remaining = order.volume;
+        for price in self.prices(-order.side) {
+            if !self.crosses(order, price) {
+                break;
+            }
+            for maker in self.bucket_array[price as usize].orders() {
+                if maker.accepts(remaining) {
+                    remaining -= min(remaining, maker.volume);
+                    if remaining == 0 {
+                        return order.volume;
+                    }
+                }
+            }
+        }
+        order.volume - remaining
+    }
+
+    /// Report an execution to the database, seen from the taker
+    fn publish_trade(&self, taker: &StandingOrder, maker_participant: u64, volume: u64, price: u64) {
+        let sign = match taker.side {
+            OrderSide::BID => 1,
+            OrderSide::ASK => -1,
+        };
+        let _ = self.db_sender.send(DbEvent::Trade(Trade {
+            symbol: self.symbol_id,
+            volume: sign * volume as i64,
+            value: sign * (volume * price) as i64,
+            taker_participant: taker.participant_id as usize,
+            maker_participant: maker_participant as usize,
+        }));
+    }
+
+    /// Displayed volume of the best price levels of one side, best first (price, volume)
+    ///
+    /// Hidden orders are left out.
+    pub fn depth(&self, side: OrderSide, levels: usize) -> Vec<(u64, u64)> {
+        self.displayed_levels(side)
+            .map(|bucket| {
+                let volume = bucket.displayed_orders().map(|order| order.volume).sum();
+                (bucket.price, volume)
+            })
+            .take(levels)
+            .collect()
+    }
+
+    /// Displayed orders of the best price levels of one side, best first and in time priority
+    /// (price, order_id, volume)
+    ///
+    /// Hidden orders are left out.
+    pub fn displayed_orders(&self, side: OrderSide, levels: usize) -> Vec<(u64, u64, u64)> {
+        self.displayed_levels(side)
+            .take(levels)
+            .flat_map(|bucket| {
+                bucket
+                    .displayed_orders()
+                    .map(move |order| (bucket.price, order.id, order.volume))
+            })
+            .collect()
+    }
+
+    /// Prices at which orders of one side can rest, best first
+    fn prices(&self, side: OrderSide) -> Box<dyn Iterator<Item = u64>> {
+        match side {
+            OrderSide::BID => Box::new((0..=min(self.max_bid_price, self.max_price - 1)).rev()),
+            OrderSide::ASK => Box::new(self.min_ask_price..self.max_price),
+        }
+    }
+
+    /// Buckets of one side that contain displayed orders, best first
+    fn displayed_levels(&self, side: OrderSide) -> Box<dyn Iterator<Item = &OrderBucket> + '_> {
+        Box::new(
+            self.prices(side)
+                .map(move |price| &*self.bucket_array[price as usize])
+                .filter(|bucket| bucket.displayed_orders().next().is_some()),
+        )
+    }
+
+    /// Cancel the other leg of a filled one-cancels-other order
+    /// and queue the exit pair of a completely filled bracket entry
+    fn order_filled(&mut self, id: u64, volume: u64, complete: bool) {
+        if let Some(linked) = self.links.remove(&id) {
+            self.links.remove(&linked);
+            self.cancel_order(linked);
+        }
+        if complete {
+            if let Some((exit, _)) = self.brackets.remove(&id) {
+                self.activated.push(exit);
+            }
+        } else if let Some((_, filled)) = self.brackets.get_mut(&id) {
+            *filled += volume;
         }
     }
 
     pub fn insert_order(&mut self, trade: &TradeCommand) {
-        let mut order = StandingOrder::new(
-            trade.id,
-            trade.participant_id,
-            trade.limit,
-            trade.volume,
-            trade.side,
-        );
+        if trade.order_type.is_stop() {
+            let mut stop = StopOrder::new(*trade);
+            // A stop the last trade already went past is triggered right away
+            match self.last_trade_price {
+                Some(price) if stop.update(price) => self.insert_order(&stop.triggered_command()),
+                _ => {
+                    self.stops.insert(trade.id, stop);
+                }
+            }
+            return;
+        }
+
+        let mut order = StandingOrder::from(*trade);
+
+        if let Some(peg) = order.peg {
+            match self.pegged_limit(&peg, order.side, order.cap, self.reference_prices()) {
+                Some(limit) => order.limit = limit,
+                None => {
+                    // Nothing to peg to, the order can not be priced
+                    self.canceled(order.participant_id, order.id);
+                    return;
+                }
+            }
+        }
 
+        // Whatever an immediate-or-cancel order can not fill right away is canceled instead
+        // of resting, this is how triggered market stops enter the book without a price
+        if trade.immediate_or_cancel {
+            self.match_order(&mut order);
+            if !order.is_filled() {
+                self.canceled(order.participant_id, order.id);
+            }
+            return;
+        }
+        self.place_order(order);
+    }
+
+    /// Match an order and put whatever is left of it into the book
+    fn place_order(&mut self, mut order: StandingOrder) {
         // println!(
         //     "Insert order: {}, Limit: {}, Side: {:?}, Volume: {:?}",
         //     order.id, order.limit, order.side, order.volume
@@ -194,6 +453,12 @@ impl OrderBook {
         self.match_order(&mut order);
         // println!("Matched, order: {:?}", order);
 
+        // The rest of an order that could not meet its own minimum or the minimums of the
+        // orders it crosses is canceled, it must not rest on the other side of the book
+        if !order.is_filled() && self.crossed_by(&order) {
+            self.canceled(order.participant_id, order.id);
+            return;
+        }
         if !order.is_filled() {
             match order.side {
                 OrderSide::ASK => self.min_ask_price = min(self.min_ask_price, order.limit),
@@ -201,6 +466,10 @@ impl OrderBook {
             }
             let id = order.id;
             let limit = order.limit;
+            let order_is_pegged = order.peg.is_some();
+            if !order.hidden {
+                self.level_changed(order.side, limit);
+            }
 
             //Get a raw pointer to the order and put it into order_map´
 
@@ -211,18 +480,362 @@ impl OrderBook {
             self.bucket_array[limit as usize].insert_order(occupied_entry.get().into());
 
             //Set pointer to the HashMap Entry into the Orde
+
+            if order_is_pegged {
+                self.pegged.insert(id, ());
+            }
         }
     }
 
+    /// Best bid and best ask of all orders that are not pegged.
+    /// Pegged orders are left out, so they can not peg to each other.
+    fn reference_prices(&self) -> (Option<u64>, Option<u64>) {
+        let bid = (0..=min(self.max_bid_price, self.max_price - 1))
+            .rev()
+            .find(|price| self.bucket_array[*price as usize].has_reference_liquidity());
+        let ask = (self.min_ask_price..self.max_price)
+            .find(|price| self.bucket_array[*price as usize].has_reference_liquidity());
+        (bid, ask)
+    }
+
+    /// Effective limit of a pegged order, never crossing its cap.
+    /// None if the reference price does not exist.
+    fn pegged_limit(
+        &self,
+        peg: &Peg,
+        side: OrderSide,
+        cap: u64,
+        (bid, ask): (Option<u64>, Option<u64>),
+    ) -> Option<u64> {
+        let reference = match (peg.reference, side) {
+            (PegReference::Primary, OrderSide::BID) | (PegReference::Market, OrderSide::ASK) => bid?,
+            (PegReference::Primary, OrderSide::ASK) | (PegReference::Market, OrderSide::BID) => ask?,
+            // Round the midpoint away from the opposite side
+            (PegReference::Midpoint, OrderSide::BID) => (bid? + ask?) / 2,
+            (PegReference::Midpoint, OrderSide::ASK) => (bid? + ask?).div_ceil(2),
+        };
+        let price = (reference as i64 + peg.offset).clamp(0, self.max_price as i64 - 1) as u64;
+        Some(match side {
+            OrderSide::BID => min(price, cap),
+            OrderSide::ASK => max(price, cap),
+        })
+    }
+
+    /// Re-price pegged orders and trigger stop orders until the book is stable
+    ///
+    /// Has to be called after every change to the book.
+    pub fn update_dependent_orders(&mut self) {
+        loop {
+            self.reprice_pegged_orders();
+            let activated = self.activate_exits();
+            let triggered = self.trigger_stop_orders();
+            if !activated && !triggered {
+                break;
+            }
+        }
+        self.publish_fair_price();
+    }
+
+    /// Median of the best displayed bid, the best displayed ask and the last traded price.
+    /// The mid price without trades, the last traded price without a two-sided book.
+    pub fn fair_price(&self) -> Option<u64> {
+        Self::fair_price_of(self.best_displayed(), self.last_trade_price)
+    }
+
+    /// Prices of the best displayed bid and ask
+    fn best_displayed(&self) -> (Option<u64>, Option<u64>) {
+        let bid = self.displayed_levels(OrderSide::BID).next().map(|b| b.price);
+        let ask = self.displayed_levels(OrderSide::ASK).next().map(|b| b.price);
+        (bid, ask)
+    }
+
+    fn fair_price_of((bid, ask): (Option<u64>, Option<u64>), last: Option<u64>) -> Option<u64> {
+        match (bid, ask, last) {
+            (Some(bid), Some(ask), Some(last)) => {
+                let mut prices = [bid, ask, last];
+                prices.sort_unstable();
+                Some(prices[1])
+            }
+            (Some(bid), Some(ask), None) => Some((bid + ask) / 2),
+            (_, _, last) => last,
+        }
+    }
+
+    /// Note a change of a displayed order at a price.
+    /// Only changes at or above the best displayed level can move the fair price.
+    fn level_changed(&mut self, side: OrderSide, price: u64) {
+        self.top_changed |= match (side, self.displayed_top) {
+            (OrderSide::BID, (Some(bid), _)) => price >= bid,
+            (OrderSide::ASK, (_, Some(ask))) => price <= ask,
+            (_, _) => true,
+        };
+    }
+
+    /// Send the fair price to every risk engine shard if it changed.
+    /// The displayed levels are only looked at if the best of them may have changed.
+    fn publish_fair_price(&mut self) {
+        if !mem::take(&mut self.top_changed) {
+            return;
+        }
+        self.displayed_top = self.best_displayed();
+        let fair_price = Self::fair_price_of(self.displayed_top, self.last_trade_price);
+        if fair_price == self.fair_price {
+            return;
+        }
+        self.fair_price = fair_price;
+        if let Some(price) = fair_price {
+            for sender in &self.senders {
+                let _ = sender.send(MatchingEngineEvent::FairPrice(self.symbol_id as u64, price));
+            }
+        }
+    }
+
+    /// Insert the exit pairs of filled bracket entries.
+    /// Returns whether there were any.
+    fn activate_exits(&mut self) -> bool {
+        if self.activated.is_empty() {
+            return false;
+        }
+        for exit in mem::take(&mut self.activated) {
+            self.insert_oco(&exit);
+        }
+        true
+    }
+
+    /// Let the stop orders follow the prices traded since the last call
+    /// and insert the triggered ones in the order they arrived.
+    /// Returns whether there was anything to follow.
+    fn trigger_stop_orders(&mut self) -> bool {
+        if self.trade_prices.is_empty() {
+            return false;
+        }
+        let prices = mem::take(&mut self.trade_prices);
+
+        let mut triggered = Vec::new();
+        for (id, stop) in self.stops.iter_mut() {
+            if prices.iter().any(|price| stop.update(*price)) {
+                triggered.push(*id);
+            }
+        }
+        for id in triggered {
+            let stop = self.stops.remove(&id).unwrap();
+            self.insert_order(&stop.triggered_command());
+        }
+        true
+    }
+
+    /// Move all pegged orders to the price their reference points to
+    ///
+    /// A re-priced order loses its time priority and can match right away,
+    /// which may move the reference prices again.
+    fn reprice_pegged_orders(&mut self) {
+        // The reference prices are only looked up for orders that follow them
+        if self.pegged.is_empty() {
+            return;
+        }
+        loop {
+            let reference = self.reference_prices();
+            if reference == self.peg_reference {
+                break;
+            }
+            self.peg_reference = reference;
+
+            let ids: Vec<u64> = self.pegged.keys().copied().collect();
+            for id in ids {
+                // The order might have been filled by an order re-priced before it
+                let (peg, side, cap, limit) = match self.order_map.get(&id) {
+                    Some(order) => (order.peg.unwrap(), order.side, order.cap, order.limit),
+                    None => continue,
+                };
+                match self.pegged_limit(&peg, side, cap, reference) {
+                    Some(new_limit) if new_limit != limit => {
+                        let mut order = *self.remove_order(id).unwrap();
+                        order.limit = new_limit;
+                        self.place_order(order);
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    /// Atomically replace the standing quote of a participant
+    ///
+    /// Both sides of the previous quote are canceled before the new sides are inserted.
+    pub fn replace_quote(&mut self, quote: &QuoteCommand) {
+        if let Some((bid_id, ask_id)) = self.quotes.remove(&quote.participant_id) {
+            self.cancel_order(bid_id);
+            self.cancel_order(ask_id);
+        }
+        for leg in quote.legs() {
+            self.insert_order(&leg);
+        }
+        self.quotes
+            .insert(quote.participant_id, (quote.bid_id, quote.ask_id));
+    }
+
+    /// Insert both legs of a one-cancels-other pair
+    pub fn insert_oco(&mut self, oco: &OcoCommand) {
+        self.links.insert(oco.first.id, oco.second.id);
+        self.links.insert(oco.second.id, oco.first.id);
+        self.insert_order(&oco.first);
+
+        // The first leg might already be filled or canceled
+        if self.links.contains_key(&oco.second.id) {
+            self.insert_order(&oco.second);
+        } else {
+            self.canceled(oco.second.participant_id, oco.second.id);
+        }
+    }
+
+    /// Insert the entry of a bracket, its exit pair follows once the entry is filled
+    pub fn insert_bracket(&mut self, bracket: &BracketCommand) {
+        self.brackets.insert(bracket.entry.id, (bracket.exit, 0));
+        self.insert_order(&bracket.entry);
+    }
+
+    /// Cancel a standing order and notify the risk engine about the released volume
     pub fn cancel_order(&mut self, id: u64) {
+        let participant_id = match self.remove_order(id) {
+            Some(order) => order.participant_id,
+            None => match self.stops.remove(&id) {
+                Some(stop) => stop.command.participant_id,
+                None => return,
+            },
+        };
+        self.canceled(participant_id, id);
+    }
+
+    /// Take volume out of a standing or waiting order so it keeps `remaining`,
+    /// cancel it completely if nothing remains. Orders that hold less already stay as they are.
+    pub fn reduce_order(&mut self, id: u64, remaining: u64) {
+        if remaining == 0 {
+            return self.cancel_order(id);
+        }
+        let (participant_id, volume) = match self.order_map.get_mut(&id) {
+            Some(order) => (order.participant_id, &mut order.volume),
+            None => match self.stops.get_mut(&id) {
+                Some(stop) => (stop.command.participant_id, &mut stop.command.volume),
+                None => return,
+            },
+        };
+        if *volume <= remaining {
+            return;
+        }
+        let reduced = *volume - remaining;
+        *volume = remaining;
+        let _ = self
+            .get_sender(participant_id)
+            .send(MatchingEngineEvent::Reduced(id, reduced));
+    }
+
+    /// Notify the risk engine that an order is gone for good,
+    /// together with the orders that depend on it
+    fn canceled(&mut self, participant_id: u64, id: u64) {
+        let _ = self
+            .get_sender(participant_id)
+            .send(MatchingEngineEvent::Canceled(id));
+
+        if let Some(linked) = self.links.remove(&id) {
+            self.links.remove(&linked);
+            self.cancel_order(linked);
+        }
+        match self.brackets.remove(&id) {
+            // The exit pair of a partially filled entry only closes what the entry filled
+            Some((mut exit, filled)) if filled > 0 && !self.closed => {
+                for leg in [&mut exit.first, &mut exit.second] {
+                    let _ = self
+                        .get_sender(participant_id)
+                        .send(MatchingEngineEvent::Reduced(leg.id, leg.volume - filled));
+                    leg.volume = filled;
+                }
+                self.activated.push(exit);
+            }
+            Some((exit, _)) => {
+                self.canceled(participant_id, exit.first.id);
+                self.canceled(participant_id, exit.second.id);
+            }
+            None => (),
+        }
+    }
+
+    /// Orders resting in the book (id, participant, side, limit, volume) in id order,
+    /// without the stops waiting for their trigger
+    pub fn resting_orders(&self) -> Vec<(u64, u64, OrderSide, u64, u64)> {
+        let mut orders: Vec<(u64, u64, OrderSide, u64, u64)> = self
+            .order_map
+            .values()
+            .map(|order| {
+                (
+                    order.id,
+                    order.participant_id,
+                    order.side,
+                    order.limit,
+                    order.volume,
+                )
+            })
+            .collect();
+        orders.sort_unstable_by_key(|(id, ..)| *id);
+        orders
+    }
+
+    /// Stop trading on this book and cancel every order in it
+    pub fn close(&mut self) {
+        if self.closed {
+            return;
+        }
+        self.closed = true;
+        let resting: Vec<u64> = self
+            .bucket_array
+            .iter()
+            .flat_map(|bucket| bucket.orders().map(|order| order.id))
+            .collect();
+        let waiting: Vec<u64> = self.stops.keys().copied().collect();
+        for id in resting.into_iter().chain(waiting) {
+            self.cancel_order(id);
+        }
+        self.quotes.clear();
+    }
+
+    /// Cancel all orders of a command that reached the book after it was closed
+    pub fn reject(&mut self, command: &OrderCommand) {
+        let orders: Vec<TradeCommand> = match command {
+            OrderCommand::Trade(trade) => vec![*trade],
+            OrderCommand::Quote(quote) => quote.legs().collect(),
+            OrderCommand::Oco(oco) => vec![oco.first, oco.second],
+            OrderCommand::Bracket(bracket) => {
+                vec![bracket.entry, bracket.exit.first, bracket.exit.second]
+            }
+            OrderCommand::Cancel(_)
+            | OrderCommand::MassQuote(_)
+            | OrderCommand::Admin(_)
+            | OrderCommand::Query(_)
+            | OrderCommand::Margin(_)
+            | OrderCommand::Account(_)
+            | OrderCommand::Migration(_) => Vec::new(),
+        };
+        for order in orders {
+            self.canceled(order.participant_id, order.id);
+        }
+    }
+
+    /// Remove an order from the book without notifying anyone
+    fn remove_order(&mut self, id: u64) -> Option<Box<StandingOrder>> {
         match self.order_map.entry(id) {
-            Vacant(v) => (),
+            Vacant(v) => None,
             Occupied(entry) => {
                 //Remove from hashmap
                 let mut order = entry.remove();
 
                 //Remove from linked list
-                order.remove_from_bucket(&mut self.bucket_array[order.limit as usize])
+                order.remove_from_bucket(&mut self.bucket_array[order.limit as usize]);
+                if order.peg.is_some() {
+                    self.pegged.remove(&id);
+                }
+                if !order.hidden {
+                    self.level_changed(order.side, order.limit);
+                }
+                Some(order)
             }
         }
     }
@@ -242,3 +855,275 @@ fn first_entry(vec: BitVec<u32>) -> Option<u32> {
         .next()
 }
 */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exchange::commands::Stop;
+    use crossbeam::channel::{unbounded, Receiver};
+
+    const PARTICIPANT: u64 = 1;
+
+    /// A book with a single risk engine shard, and the events that shard receives
+    fn book() -> (OrderBook, Receiver<MatchingEngineEvent>) {
+        let (event_sender, events) = unbounded();
+        let (handoff_sender, _) = unbounded();
+        let directory = Arc::new(ShardDirectory::default());
+        directory.add_shard(event_sender, handoff_sender);
+        let (db_sender, _) = unbounded();
+        let mut book = OrderBook::new(0, ExchangeSettings::default(), directory, db_sender);
+        book.register(PARTICIPANT);
+        (book, events)
+    }
+
+    fn limit(id: u64, side: OrderSide, volume: u64, limit: u64) -> TradeCommand {
+        TradeCommand {
+            id,
+            participant_id: PARTICIPANT,
+            symbol: 0,
+            side,
+            volume,
+            limit,
+            immediate_or_cancel: false,
+            order_type: OrderType::Limit,
+            hidden: false,
+            min_quantity: 0,
+            reduce_only: false,
+            close_position: false,
+        }
+    }
+
+    fn insert(book: &mut OrderBook, trade: TradeCommand) {
+        book.insert_order(&trade);
+        book.update_dependent_orders();
+    }
+
+    /// Limits of the resting orders (id, limit)
+    fn limits(book: &OrderBook) -> Vec<(u64, u64)> {
+        book.resting_orders()
+            .into_iter()
+            .map(|(id, _, _, limit, _)| (id, limit))
+            .collect()
+    }
+
+    fn canceled(events: &Receiver<MatchingEngineEvent>) -> Vec<u64> {
+        events
+            .try_iter()
+            .filter_map(|event| match event {
+                MatchingEngineEvent::Canceled(id) => Some(id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The best bid is below the best ask
+    fn assert_not_crossed(book: &OrderBook) {
+        let orders = book.resting_orders();
+        let side = |side: OrderSide| {
+            orders
+                .iter()
+                .filter(move |(_, _, order_side, ..)| *order_side == side)
+                .map(|(_, _, _, limit, _)| *limit)
+        };
+        if let (Some(bid), Some(ask)) = (side(OrderSide::BID).max(), side(OrderSide::ASK).min()) {
+            assert!(bid < ask, "Bid {} crosses ask {}", bid, ask);
+        }
+    }
+
+    #[test]
+    fn stop_crossed_when_it_arrives_is_triggered_right_away() {
+        let (mut book, _) = book();
+        insert(&mut book, limit(0, OrderSide::BID, 10, 100));
+        insert(&mut book, limit(1, OrderSide::ASK, 5, 100));
+        let stop = TradeCommand {
+            order_type: OrderType::Stop(Stop {
+                trigger: 101,
+                market: true,
+            }),
+            ..limit(2, OrderSide::ASK, 3, 90)
+        };
+        insert(&mut book, stop);
+        assert!(book.stops.is_empty());
+        assert_eq!(
+            book.resting_orders(),
+            vec![(0, PARTICIPANT, OrderSide::BID, 100, 2)]
+        );
+    }
+
+    #[test]
+    fn immediate_or_cancel_rest_does_not_rest() {
+        let (mut book, events) = book();
+        insert(&mut book, limit(0, OrderSide::BID, 5, 100));
+        let ioc = TradeCommand {
+            immediate_or_cancel: true,
+            ..limit(1, OrderSide::ASK, 8, 100)
+        };
+        insert(&mut book, ioc);
+        assert!(book.resting_orders().is_empty());
+        assert_eq!(canceled(&events), vec![1]);
+    }
+
+    #[test]
+    fn canceled_bracket_entry_activates_the_exits_for_what_it_filled() {
+        let (mut book, events) = book();
+        insert(&mut book, limit(0, OrderSide::ASK, 4, 100));
+        let bracket = BracketCommand {
+            entry: limit(1, OrderSide::BID, 10, 100),
+            exit: OcoCommand {
+                first: limit(2, OrderSide::ASK, 10, 120),
+                second: limit(3, OrderSide::ASK, 10, 150),
+            },
+        };
+        book.insert_bracket(&bracket);
+        book.update_dependent_orders();
+        assert_eq!(limits(&book), vec![(1, 100)]);
+
+        book.cancel_order(1);
+        book.update_dependent_orders();
+        assert_eq!(
+            book.resting_orders(),
+            vec![
+                (2, PARTICIPANT, OrderSide::ASK, 120, 4),
+                (3, PARTICIPANT, OrderSide::ASK, 150, 4)
+            ]
+        );
+        let reduced: Vec<(u64, u64)> = events
+            .try_iter()
+            .filter_map(|event| match event {
+                MatchingEngineEvent::Reduced(id, volume) => Some((id, volume)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(reduced, vec![(2, 6), (3, 6)]);
+    }
+
+    #[test]
+    fn canceled_bracket_entry_without_fills_cancels_the_exits() {
+        let (mut book, events) = book();
+        let bracket = BracketCommand {
+            entry: limit(0, OrderSide::BID, 10, 100),
+            exit: OcoCommand {
+                first: limit(1, OrderSide::ASK, 10, 120),
+                second: limit(2, OrderSide::ASK, 10, 150),
+            },
+        };
+        book.insert_bracket(&bracket);
+        book.cancel_order(0);
+        book.update_dependent_orders();
+        assert!(book.resting_orders().is_empty());
+        assert_eq!(canceled(&events), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn hidden_order_is_not_displayed_and_matched_after_displayed_ones() {
+        let (mut book, _) = book();
+        let hidden = TradeCommand {
+            hidden: true,
+            ..limit(0, OrderSide::BID, 5, 100)
+        };
+        insert(&mut book, hidden);
+        insert(&mut book, limit(1, OrderSide::BID, 3, 100));
+        insert(&mut book, limit(2, OrderSide::BID, 3, 99));
+        assert_eq!(book.depth(OrderSide::BID, 2), vec![(100, 3), (99, 3)]);
+        assert_eq!(book.displayed_orders(OrderSide::BID, 1), vec![(100, 1, 3)]);
+
+        insert(&mut book, limit(3, OrderSide::ASK, 4, 100));
+        assert_eq!(
+            book.resting_orders(),
+            vec![
+                (0, PARTICIPANT, OrderSide::BID, 100, 4),
+                (2, PARTICIPANT, OrderSide::BID, 99, 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn order_that_can_not_trade_at_a_crossing_price_does_not_rest_there() {
+        let (mut book, events) = book();
+        insert(&mut book, limit(0, OrderSide::ASK, 3, 100));
+        let minimum = TradeCommand {
+            min_quantity: 5,
+            ..limit(1, OrderSide::BID, 10, 105)
+        };
+        insert(&mut book, minimum);
+        assert_not_crossed(&book);
+
+        let resting_minimum = TradeCommand {
+            min_quantity: 10,
+            ..limit(2, OrderSide::ASK, 10, 101)
+        };
+        insert(&mut book, resting_minimum);
+        insert(&mut book, limit(3, OrderSide::BID, 6, 101));
+        assert_not_crossed(&book);
+
+        let below = TradeCommand {
+            min_quantity: 5,
+            ..limit(4, OrderSide::BID, 10, 99)
+        };
+        insert(&mut book, below);
+        assert_eq!(limits(&book), vec![(2, 101), (4, 99)]);
+        assert_eq!(canceled(&events), vec![1, 3]);
+    }
+
+    #[test]
+    fn pegged_order_follows_its_reference_up_to_the_cap() {
+        let (mut book, _) = book();
+        insert(&mut book, limit(0, OrderSide::BID, 5, 100));
+        insert(&mut book, limit(1, OrderSide::ASK, 5, 110));
+        let peg = Peg {
+            reference: PegReference::Primary,
+            offset: 1,
+        };
+        let pegged = TradeCommand {
+            order_type: OrderType::Pegged(peg),
+            ..limit(2, OrderSide::BID, 5, 102)
+        };
+        insert(&mut book, pegged);
+        assert_eq!(limits(&book), vec![(0, 100), (1, 110), (2, 101)]);
+
+        // The best bid plus the offset is above the cap
+        insert(&mut book, limit(3, OrderSide::BID, 5, 102));
+        assert_eq!(limits(&book)[2], (2, 102));
+
+        book.cancel_order(3);
+        book.update_dependent_orders();
+        assert_eq!(limits(&book)[2], (2, 101));
+    }
+
+    #[test]
+    fn midpoint_peg_rounds_away_from_the_other_side() {
+        let (mut book, _) = book();
+        insert(&mut book, limit(0, OrderSide::BID, 5, 100));
+        insert(&mut book, limit(1, OrderSide::ASK, 5, 105));
+        let midpoint = Peg {
+            reference: PegReference::Midpoint,
+            offset: 0,
+        };
+        let bid = TradeCommand {
+            order_type: OrderType::Pegged(midpoint),
+            ..limit(2, OrderSide::BID, 5, 200)
+        };
+        let ask = TradeCommand {
+            order_type: OrderType::Pegged(midpoint),
+            ..limit(3, OrderSide::ASK, 5, 0)
+        };
+        insert(&mut book, bid);
+        insert(&mut book, ask);
+        assert_eq!(limits(&book), vec![(0, 100), (1, 105), (2, 102), (3, 103)]);
+    }
+
+    #[test]
+    fn pegged_order_without_reference_is_canceled() {
+        let (mut book, events) = book();
+        let pegged = TradeCommand {
+            order_type: OrderType::Pegged(Peg {
+                reference: PegReference::Primary,
+                offset: 0,
+            }),
+            ..limit(0, OrderSide::BID, 5, 100)
+        };
+        insert(&mut book, pegged);
+        assert!(book.resting_orders().is_empty());
+        assert_eq!(canceled(&events), vec![0]);
+    }
+}
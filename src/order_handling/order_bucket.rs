@@ -23,9 +23,13 @@ use super::order_book::OrderBook;
 pub struct OrderBucket {
     pub price: u64,
     pub len: usize,
+    /// How many of the orders are pegged or hidden, pegged orders can not refer to those
+    pub unreferenced_len: usize,
 
     pub head: Option<NonNull<Box<StandingOrder>>>,
     pub tail: Option<NonNull<Box<StandingOrder>>>,
+    /// Hidden orders are queued behind all displayed orders, starting here
+    pub first_hidden: Option<NonNull<Box<StandingOrder>>>,
 }
 
 impl PartialOrd for OrderBucket {
@@ -45,8 +49,10 @@ impl OrderBucket {
         OrderBucket {
             price,
             len: 0,
+            unreferenced_len: 0,
             head: None,
             tail: None, //map   order_map: HashMap::with_capacity(DEFAULT_CAPACITY),
+            first_hidden: None,
         }
     }
 
@@ -67,6 +73,21 @@ impl OrderBucket {
         }
     }
 
+    /// Insert a displayed order in front of the hidden orders
+    fn insert_before_hidden(&mut self, mut order: NonNull<Box<StandingOrder>>) {
+        let mut hidden = self.first_hidden.unwrap();
+        unsafe {
+            order.as_mut().next = Some(hidden);
+            order.as_mut().prev = hidden.as_ref().prev;
+            match hidden.as_ref().prev {
+                None => self.head = Some(order),
+                Some(mut prev) => prev.as_mut().next = Some(order),
+            }
+            hidden.as_mut().prev = Some(order);
+        }
+        self.len += 1;
+    }
+
     fn pop_front(&mut self) {
         self.head.map(|node| unsafe {
             let node = node.as_ref();
@@ -88,39 +109,68 @@ impl OrderBucket {
         self.head.is_none()
     }
 
+    /// Whether there are orders in this bucket that pegged orders can refer to
+    pub fn has_reference_liquidity(&self) -> bool {
+        self.len > self.unreferenced_len
+    }
+
+    /// All orders in the order they are matched
+    pub fn orders(&self) -> impl Iterator<Item = &StandingOrder> {
+        std::iter::successors(self.head, |order| unsafe { order.as_ref().next })
+            .map(|order| unsafe { &**order.as_ref() })
+    }
+
+    /// Orders that are displayed to the market, in time priority
+    pub fn displayed_orders(&self) -> impl Iterator<Item = &StandingOrder> {
+        self.orders().take_while(|order| !order.hidden)
+    }
+
     pub fn insert_order(&mut self, order: NonNull<Box<StandingOrder>>) {
-        self.push_back(order);
+        let (is_reference, hidden) = unsafe { (order.as_ref().is_reference(), order.as_ref().hidden) };
+        if !is_reference {
+            self.unreferenced_len += 1;
+        }
+        if hidden {
+            self.push_back(order);
+            if self.first_hidden.is_none() {
+                self.first_hidden = Some(order);
+            }
+        } else if self.first_hidden.is_some() {
+            self.insert_before_hidden(order);
+        } else {
+            self.push_back(order);
+        }
 
         //  println!("Order insertion: Queue: {}, Map: {}", t1.as_nanos(), (now.elapsed()-t1).as_nanos());
     }
 
-    /// Match as many orders as possible with a given amount of volume
+    /// Match the first order of the bucket that accepts the taker's volume
+    ///
+    /// Orders with a minimum quantity the taker can not meet are skipped and keep their place.
     ///
-    /// #Returns how much volume was matched
+    /// #Returns how much volume was matched, the id of the matched order and whether it is filled now
     pub fn match_orders(
         taker: &StandingOrder,
         book: &mut OrderBook,
         best_price: u64,
-    ) -> Option<(u64, Option<u64>)> {
+    ) -> Option<(u64, u64, bool)> {
         let bucket = &mut book.bucket_array[best_price as usize];
 
-        if bucket.is_empty() {
-            return None;
-        }
+        let mut node = bucket.head;
+        let order = loop {
+            let order = unsafe { node?.as_mut() };
+            if order.accepts(taker.volume) {
+                break order;
+            }
+            node = order.next;
+        };
         // std::thread::sleep(time::Duration::from_millis(100));
-        let order = unsafe { bucket.head.unwrap().as_mut() };
         // println!("Matching with: {:?}", order);
 
         let filled_volume = order.fill(taker, bucket.price, book.get_sender(order.participant_id));
         // println!("Matched with: {:?}", order);
-        let canceled_order = if order.is_filled() {
-            // println!("Marked as filled, removing from bucket");
-            Some(order.id)
-        } else {
-            None
-        };
 
-        Some((filled_volume, canceled_order))
+        Some((filled_volume, order.id, order.is_filled()))
     }
 
     // pub fn print_list(&self) -> String {
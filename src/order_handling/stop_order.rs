@@ -0,0 +1,73 @@
+use std::cmp::{max, min};
+
+use crate::exchange::commands::{OrderType, TradeCommand};
+
+use super::order::OrderSide;
+
+/// An order waiting in the book until its trigger price is traded
+#[derive(Debug)]
+pub struct StopOrder {
+    pub command: TradeCommand,
+    /// None until the first trade has been seen
+    pub trigger: Option<u64>,
+}
+
+impl StopOrder {
+    pub fn new(command: TradeCommand) -> Self {
+        let trigger = match command.order_type {
+            OrderType::Stop(stop) => Some(stop.trigger),
+            _ => None,
+        };
+        Self { command, trigger }
+    }
+
+    /// Follow a traded price and return whether the stop was triggered by it
+    ///
+    /// A trailing trigger only ever moves in the favourable direction:
+    /// up for asks and down for bids.
+    pub fn update(&mut self, price: u64) -> bool {
+        let trailing = match self.command.order_type {
+            OrderType::TrailingStop(trailing) => trailing,
+            OrderType::Stop(stop) => {
+                return match self.command.side {
+                    OrderSide::ASK => price <= stop.trigger,
+                    OrderSide::BID => price >= stop.trigger,
+                }
+            }
+            _ => unreachable!("Only stop orders wait for a trigger"),
+        };
+        let offset = trailing.offset.at(price);
+        match (self.command.side, self.trigger) {
+            (OrderSide::ASK, Some(trigger)) if price <= trigger => true,
+            (OrderSide::BID, Some(trigger)) if price >= trigger => true,
+            (OrderSide::ASK, trigger) => {
+                let candidate = price.saturating_sub(offset);
+                self.trigger = Some(trigger.map_or(candidate, |t| max(t, candidate)));
+                false
+            }
+            (OrderSide::BID, trigger) => {
+                let candidate = price + offset;
+                self.trigger = Some(trigger.map_or(candidate, |t| min(t, candidate)));
+                false
+            }
+        }
+    }
+
+    /// The order that enters the book once the stop is triggered
+    pub fn triggered_command(&self) -> TradeCommand {
+        let mut command = self.command;
+        command.order_type = OrderType::Limit;
+        let market = match self.command.order_type {
+            OrderType::TrailingStop(trailing) => trailing.market,
+            OrderType::Stop(stop) => stop.market,
+            _ => false,
+        };
+        if market {
+            command.immediate_or_cancel = true;
+            if command.side == OrderSide::ASK {
+                command.limit = 0;
+            }
+        }
+        command
+    }
+}
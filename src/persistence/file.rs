@@ -0,0 +1,91 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+use crate::order_handling::event::DbEvent;
+
+use super::{decode, encode, Persistence, PersistenceError};
+
+/// Appends the events to a text file, one line per event
+pub struct FilePersistence {
+    file: File,
+    /// Length of the complete lines in the file
+    length: u64,
+    /// Events at the start of the batch being written that are in the file already
+    written: usize,
+}
+
+impl FilePersistence {
+    /// Append to a file, created if it does not exist
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, PersistenceError> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(FilePersistence {
+            length: file.metadata()?.len(),
+            file,
+            written: 0,
+        })
+    }
+
+    /// Events stored in a file, in the order they were written
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<DbEvent>, PersistenceError> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut events = Vec::new();
+        for (index, line) in reader.lines().enumerate() {
+            events.push(decode(&line?).ok_or(PersistenceError::Corrupt(index + 1))?);
+        }
+        Ok(events)
+    }
+}
+
+impl Persistence for FilePersistence {
+    /// The batch is on disk when this returns.
+    /// A failed attempt leaves no partial line, the events it wrote are skipped when the batch
+    /// is written again.
+    fn write(&mut self, events: &[DbEvent]) -> Result<(), PersistenceError> {
+        let mut lines = String::new();
+        for event in &events[self.written..] {
+            lines.push_str(&encode(event));
+            lines.push('\n');
+        }
+        if let Err(error) = self.file.write_all(lines.as_bytes()) {
+            // Cut what was written of the lines, they are all written again
+            let _ = self.file.set_len(self.length);
+            return Err(error.into());
+        }
+        self.length += lines.len() as u64;
+        self.written = events.len();
+        self.file.sync_data()?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::events;
+    use super::*;
+
+    #[test]
+    fn events_are_read_back_in_order() {
+        let path = std::env::temp_dir().join(format!("persistence-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let events = events();
+
+        let mut persistence = FilePersistence::open(&path).unwrap();
+        persistence.write(&events[..2]).unwrap();
+        // A batch that failed after writing its first events is not written twice
+        persistence.written = 2;
+        persistence.write(&events[..5]).unwrap();
+        drop(persistence);
+        let mut persistence = FilePersistence::open(&path).unwrap();
+        persistence.write(&events[5..]).unwrap();
+
+        assert_eq!(FilePersistence::load(&path).unwrap(), events);
+        std::fs::write(&path, "canceled\n").unwrap();
+        assert!(matches!(
+            FilePersistence::load(&path),
+            Err(PersistenceError::Corrupt(1))
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
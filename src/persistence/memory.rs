@@ -0,0 +1,26 @@
+use std::sync::{Arc, Mutex};
+
+use crate::order_handling::event::DbEvent;
+
+use super::{Persistence, PersistenceError};
+
+/// Keeps the events in memory. Clones share the events,
+/// so a clone kept outside the database thread can read what was written.
+#[derive(Clone, Default)]
+pub struct MemoryPersistence {
+    events: Arc<Mutex<Vec<DbEvent>>>,
+}
+
+impl MemoryPersistence {
+    /// Events written so far, in the order they happened
+    pub fn events(&self) -> Vec<DbEvent> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+impl Persistence for MemoryPersistence {
+    fn write(&mut self, events: &[DbEvent]) -> Result<(), PersistenceError> {
+        self.events.lock().unwrap().extend_from_slice(events);
+        Ok(())
+    }
+}
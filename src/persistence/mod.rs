@@ -0,0 +1,242 @@
+pub mod file;
+pub mod memory;
+#[cfg(feature = "redis")]
+pub mod redis_store;
+
+use std::io;
+
+use crate::order_handling::event::{
+    BalanceChange, DbEvent, Liquidity, OrderEvent, OrderEventKind, Trade,
+};
+use crate::order_handling::order::OrderSide;
+use crate::risk::participant::Balance;
+
+/// Storage of the trades, order events and balance changes of the exchange
+pub trait Persistence: Send {
+    /// Store a batch of events in the order they happened.
+    /// A batch that failed is written again with the later events appended,
+    /// so stores should tolerate a partial first attempt.
+    fn write(&mut self, events: &[DbEvent]) -> Result<(), PersistenceError>;
+}
+
+#[derive(Debug)]
+pub enum PersistenceError {
+    Io(io::Error),
+    /// A stored event could not be read back, line number from 1
+    Corrupt(usize),
+    #[cfg(feature = "redis")]
+    Redis(::redis::RedisError),
+}
+
+impl From<io::Error> for PersistenceError {
+    fn from(error: io::Error) -> Self {
+        PersistenceError::Io(error)
+    }
+}
+
+#[cfg(feature = "redis")]
+impl From<::redis::RedisError> for PersistenceError {
+    fn from(error: ::redis::RedisError) -> Self {
+        PersistenceError::Redis(error)
+    }
+}
+
+/// One line of text per event, fields separated by spaces
+pub fn encode(event: &DbEvent) -> String {
+    match event {
+        DbEvent::Trade(trade) => format!(
+            "trade {} {} {} {} {}",
+            trade.symbol,
+            trade.volume,
+            trade.value,
+            trade.taker_participant,
+            trade.maker_participant
+        ),
+        DbEvent::Order(order) => {
+            let kind = match order.kind {
+                OrderEventKind::Placed {
+                    symbol,
+                    side,
+                    limit,
+                    volume,
+                } => {
+                    let side = match side {
+                        OrderSide::BID => "bid",
+                        OrderSide::ASK => "ask",
+                    };
+                    format!("placed {} {} {} {}", symbol, side, limit, volume)
+                }
+                OrderEventKind::Filled {
+                    volume,
+                    value,
+                    liquidity,
+                } => {
+                    let liquidity = match liquidity {
+                        Liquidity::Maker => "maker",
+                        Liquidity::Taker => "taker",
+                    };
+                    format!("filled {} {} {}", volume, value, liquidity)
+                }
+                OrderEventKind::Reduced { volume } => format!("reduced {}", volume),
+                OrderEventKind::Canceled => "canceled".to_string(),
+            };
+            format!("order {} {} {}", order.order_id, order.participant_id, kind)
+        }
+        DbEvent::Balance(change) => format!(
+            "balance {} {} {} {}",
+            change.participant_id, change.asset, change.balance.available, change.balance.held
+        ),
+    }
+}
+
+/// Read an event written by `encode`
+pub fn decode(line: &str) -> Option<DbEvent> {
+    let fields: Vec<&str> = line.split(' ').collect();
+    let number = |index: usize| fields.get(index)?.parse::<u64>().ok();
+    let signed = |index: usize| fields.get(index)?.parse::<i64>().ok();
+    match fields[..] {
+        ["trade", _, _, _, _, _] => Some(DbEvent::Trade(Trade {
+            symbol: number(1)? as usize,
+            volume: signed(2)?,
+            value: signed(3)?,
+            taker_participant: number(4)? as usize,
+            maker_participant: number(5)? as usize,
+        })),
+        ["order", _, _, ref kind @ ..] => {
+            let kind = match *kind {
+                ["placed", _, side, _, _] => OrderEventKind::Placed {
+                    symbol: number(4)?,
+                    side: match side {
+                        "bid" => OrderSide::BID,
+                        "ask" => OrderSide::ASK,
+                        _ => return None,
+                    },
+                    limit: number(6)?,
+                    volume: number(7)?,
+                },
+                ["filled", _, _, liquidity] => OrderEventKind::Filled {
+                    volume: number(4)?,
+                    value: number(5)?,
+                    liquidity: match liquidity {
+                        "maker" => Liquidity::Maker,
+                        "taker" => Liquidity::Taker,
+                        _ => return None,
+                    },
+                },
+                ["reduced", _] => OrderEventKind::Reduced { volume: number(4)? },
+                ["canceled"] => OrderEventKind::Canceled,
+                _ => return None,
+            };
+            Some(DbEvent::Order(OrderEvent {
+                order_id: number(1)?,
+                participant_id: number(2)?,
+                kind,
+            }))
+        }
+        ["balance", _, _, _, _] => {
+            let available = number(3)?;
+            let held = number(4)?;
+            Some(DbEvent::Balance(BalanceChange {
+                participant_id: number(1)?,
+                asset: number(2)? as usize,
+                balance: Balance {
+                    available,
+                    held,
+                    total: available + held,
+                },
+            }))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::memory::MemoryPersistence;
+    use super::*;
+
+    /// One event of every kind
+    pub fn events() -> Vec<DbEvent> {
+        let order = |order_id, kind| {
+            DbEvent::Order(OrderEvent {
+                order_id,
+                participant_id: 3,
+                kind,
+            })
+        };
+        vec![
+            order(
+                1,
+                OrderEventKind::Placed {
+                    symbol: 2,
+                    side: OrderSide::BID,
+                    limit: 105,
+                    volume: 10,
+                },
+            ),
+            order(
+                2,
+                OrderEventKind::Placed {
+                    symbol: 2,
+                    side: OrderSide::ASK,
+                    limit: 104,
+                    volume: 4,
+                },
+            ),
+            DbEvent::Trade(Trade {
+                symbol: 2,
+                volume: -4,
+                value: -416,
+                taker_participant: 3,
+                maker_participant: 5,
+            }),
+            order(
+                1,
+                OrderEventKind::Filled {
+                    volume: 4,
+                    value: 416,
+                    liquidity: Liquidity::Maker,
+                },
+            ),
+            order(
+                2,
+                OrderEventKind::Filled {
+                    volume: 4,
+                    value: 416,
+                    liquidity: Liquidity::Taker,
+                },
+            ),
+            order(1, OrderEventKind::Reduced { volume: 2 }),
+            order(1, OrderEventKind::Canceled),
+            DbEvent::Balance(BalanceChange {
+                participant_id: 3,
+                asset: 1,
+                balance: Balance {
+                    available: 584,
+                    held: 0,
+                    total: 584,
+                },
+            }),
+        ]
+    }
+
+    #[test]
+    fn events_survive_encoding() {
+        for event in events() {
+            assert_eq!(decode(&encode(&event)), Some(event));
+        }
+        assert_eq!(decode("order 1 3 placed 2 bid 105"), None);
+        assert_eq!(decode("trade 2 x 1 3 5"), None);
+        assert_eq!(decode(""), None);
+    }
+
+    #[test]
+    fn memory_clones_share_the_events() {
+        let reader = MemoryPersistence::default();
+        let mut writer = reader.clone();
+        let events = events();
+        writer.write(&events[..3]).unwrap();
+        writer.write(&events[3..]).unwrap();
+        assert_eq!(reader.events(), events);
+    }
+}
@@ -0,0 +1,163 @@
+use redis::ConnectionLike;
+
+use crate::order_handling::event::DbEvent;
+
+use super::{encode, Persistence, PersistenceError};
+
+/// Stores the events in Redis. Trades and order events are appended to the `events` list,
+/// the latest balances are kept in one hash per participant, `balances:<participant_id>`.
+pub struct RedisPersistence<C = redis::Connection> {
+    connection: C,
+}
+
+impl RedisPersistence {
+    /// Connect to a server, like `redis://127.0.0.1/`
+    pub fn open(url: &str) -> Result<Self, PersistenceError> {
+        let client = redis::Client::open(url)?;
+        Ok(RedisPersistence::new(client.get_connection()?))
+    }
+}
+
+impl<C: ConnectionLike> RedisPersistence<C> {
+    /// Store through any connection, like a stand-in for a local server
+    pub fn new(connection: C) -> Self {
+        RedisPersistence { connection }
+    }
+}
+
+impl<C: ConnectionLike + Send> Persistence for RedisPersistence<C> {
+    /// The batch is written in one transaction
+    fn write(&mut self, events: &[DbEvent]) -> Result<(), PersistenceError> {
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for event in events {
+            match event {
+                DbEvent::Balance(change) => pipe
+                    .hset(
+                        format!("balances:{}", change.participant_id),
+                        change.asset,
+                        format!("{} {}", change.balance.available, change.balance.held),
+                    )
+                    .ignore(),
+                event => pipe.rpush("events", encode(event)).ignore(),
+            };
+        }
+        pipe.query::<()>(&mut self.connection)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use redis::{RedisResult, Value};
+
+    use super::super::{decode, tests::events};
+    use super::*;
+
+    /// Stands in for a local server, keeping the lists and hashes the store writes
+    #[derive(Default)]
+    struct LocalRedis {
+        lists: HashMap<String, Vec<String>>,
+        hashes: HashMap<String, HashMap<String, String>>,
+    }
+
+    impl LocalRedis {
+        /// Split packed commands into their arguments
+        fn commands(mut bytes: &[u8]) -> Vec<Vec<String>> {
+            fn line<'a>(bytes: &mut &'a [u8]) -> &'a str {
+                let end = bytes.windows(2).position(|pair| pair == b"\r\n").unwrap();
+                let line = std::str::from_utf8(&bytes[..end]).unwrap();
+                *bytes = &bytes[end + 2..];
+                line
+            }
+            let mut commands = Vec::new();
+            while !bytes.is_empty() {
+                let count: usize = line(&mut bytes)[1..].parse().unwrap();
+                let arguments = (0..count)
+                    .map(|_| {
+                        let length: usize = line(&mut bytes)[1..].parse().unwrap();
+                        let argument = String::from_utf8(bytes[..length].to_vec()).unwrap();
+                        bytes = &bytes[length + 2..];
+                        argument
+                    })
+                    .collect();
+                commands.push(arguments);
+            }
+            commands
+        }
+
+        fn apply(&mut self, command: &[String]) -> Value {
+            match command[0].as_str() {
+                "RPUSH" => {
+                    let list = self.lists.entry(command[1].clone()).or_default();
+                    list.extend(command[2..].iter().cloned());
+                    Value::Int(list.len() as i64)
+                }
+                "HSET" => {
+                    let hash = self.hashes.entry(command[1].clone()).or_default();
+                    for pair in command[2..].chunks(2) {
+                        hash.insert(pair[0].clone(), pair[1].clone());
+                    }
+                    Value::Int(1)
+                }
+                _ => Value::Okay,
+            }
+        }
+    }
+
+    impl ConnectionLike for LocalRedis {
+        fn req_packed_command(&mut self, cmd: &[u8]) -> RedisResult<Value> {
+            Ok(self.apply(&Self::commands(cmd)[0]))
+        }
+
+        /// Answers a transaction with the replies of its commands
+        fn req_packed_commands(
+            &mut self,
+            cmd: &[u8],
+            offset: usize,
+            count: usize,
+        ) -> RedisResult<Vec<Value>> {
+            let replies: Vec<Value> = Self::commands(cmd)
+                .iter()
+                .filter(|command| !matches!(command[0].as_str(), "MULTI" | "EXEC"))
+                .map(|command| self.apply(command))
+                .collect();
+            assert_eq!((offset, count), (replies.len() + 1, 1));
+            Ok(vec![Value::Bulk(replies)])
+        }
+
+        fn get_db(&self) -> i64 {
+            0
+        }
+
+        fn check_connection(&mut self) -> bool {
+            true
+        }
+
+        fn is_open(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn events_go_to_the_list_and_balances_to_the_hashes() {
+        let events = events();
+        let mut persistence = RedisPersistence::new(LocalRedis::default());
+        persistence.write(&events).unwrap();
+        let redis = persistence.connection;
+
+        let stored: Vec<DbEvent> = redis.lists["events"]
+            .iter()
+            .map(|line| decode(line).unwrap())
+            .collect();
+        let expected: Vec<DbEvent> = events
+            .iter()
+            .filter(|event| !matches!(event, DbEvent::Balance(_)))
+            .cloned()
+            .collect();
+        assert_eq!(stored, expected);
+        assert_eq!(redis.hashes["balances:3"]["1"], "584 0");
+    }
+}
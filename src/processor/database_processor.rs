@@ -1,60 +1,181 @@
 use crate::exchange::exchange_settings::ExchangeSettings;
-use crate::exchange::{asset::AssetId, commands::OrderCommand};
-use crate::order_handling::event::MatchingEngineEvent;
-use crate::order_handling::order::*;
-use crate::order_handling::order_book::OrderBook;
-use crossbeam::channel::{Receiver, Sender};
-use tokio::sync::mpsc;
-
-use redis;
-use std::cmp::max;
-use std::collections::{HashMap, VecDeque};
+use crate::exchange::report::Report;
+use crate::order_handling::event::DbEvent;
+use crate::persistence::Persistence;
+use crossbeam::channel::{Receiver, RecvTimeoutError, Sender, TrySendError};
+use log::{error, warn};
+
+use std::cmp::{max, min};
 use std::time::{Duration, Instant};
-use tokio::sync::RwLock;
 
-fn connect() -> redis::RedisResult<redis::Connection> {
-    let client = redis::Client::open("redis://127.0.0.1/")?;
-    client.get_connection()
-}
+/// Events kept for another write while the persistence fails, the oldest are dropped beyond it
+const MAX_QUEUED_EVENTS: usize = 1_000_000;
+/// The wait between two writes doubles with every failure up to this many times
+const MAX_BACKOFF_DOUBLINGS: u32 = 6;
 
-// Keep score of all of the positions of each market participant
+/// Writes the events of the order books and the risk engines to the persistence in batches
 pub struct DatabaseProcessor {
-    connection: redis::Connection,
+    persistence: Box<dyn Persistence>,
     last_sync: Instant,
     settings: ExchangeSettings,
-    event_queue: VecDeque<MatchingEngineEvent>,
+    /// Events not written yet, kept after a failed write to try again
+    event_queue: Vec<DbEvent>,
+    max_queued: usize,
+    /// Writes that failed in a row
+    failures: u32,
+    /// Events given up since the persistence started failing
+    dropped: u64,
+    report_sender: Sender<Report>,
 }
 
 impl DatabaseProcessor {
-    fn new(settings: ExchangeSettings) -> Self {
+    pub fn new(
+        settings: ExchangeSettings,
+        persistence: Box<dyn Persistence>,
+        report_sender: Sender<Report>,
+    ) -> Self {
         Self {
-            connection: connect().unwrap(),
+            persistence,
             last_sync: Instant::now(),
             settings,
-            event_queue: VecDeque::new(),
+            event_queue: Vec::new(),
+            max_queued: MAX_QUEUED_EVENTS,
+            failures: 0,
+            dropped: 0,
+            report_sender,
         }
     }
 
-    pub fn run(&mut self, receiver: Receiver<MatchingEngineEvent>) {
-        let conn = connect();
-
+    /// Collect events and write them every `db_sync_speed` until every sender is gone.
+    /// After a failed write the next one waits twice as long.
+    pub fn run(&mut self, receiver: Receiver<DbEvent>) {
         loop {
-            if let Ok(order_event) = receiver.recv_timeout(max(
+            match receiver.recv_timeout(max(
                 self.settings.db_min_recv_timeout,
-                self.settings.db_sync_speed - (Instant::now() - self.last_sync),
+                self.sync_interval()
+                    .saturating_sub(self.last_sync.elapsed()),
             )) {
-                self.event_queue.push_back(order_event);
-                //If enough time has passed, send all of the event to the DB
-                if Instant::now() - self.last_sync > self.settings.db_sync_speed {
-                    self.sync_database(self);
-                }
-            } else {
+                Ok(event) => self.event_queue.push(event),
                 // Timeout reached
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.sync_database();
+                    return;
+                }
+            }
+            //If enough time has passed, send all of the event to the DB
+            if self.last_sync.elapsed() >= self.sync_interval() {
+                self.sync_database();
             }
         }
     }
+
+    fn sync_interval(&self) -> Duration {
+        self.settings.db_sync_speed * (1 << min(self.failures, MAX_BACKOFF_DOUBLINGS))
+    }
+
     fn sync_database(&mut self) {
-        let pipe = redis.pipe();
-        for event in 
+        self.last_sync = Instant::now();
+        if self.event_queue.is_empty() {
+            return;
+        }
+        match self.persistence.write(&self.event_queue) {
+            Ok(()) => {
+                self.event_queue.clear();
+                if self.failures > 0 {
+                    warn!(
+                        "Persistence recovered after {} failed writes",
+                        self.failures
+                    );
+                }
+                self.failures = 0;
+                self.dropped = 0;
+            }
+            Err(error) => {
+                self.failures += 1;
+                let excess = self.event_queue.len().saturating_sub(self.max_queued);
+                self.event_queue.drain(..excess);
+                self.dropped += excess as u64;
+                error!(
+                    "Writing {} events failed {} times in a row, {} events dropped: {:?}",
+                    self.event_queue.len(),
+                    self.failures,
+                    self.dropped,
+                    error
+                );
+                self.report(Report::PersistenceFailure {
+                    failures: self.failures,
+                    queued: self.event_queue.len(),
+                    dropped: self.dropped,
+                });
+            }
+        }
+    }
+
+    /// Reports are dropped like those of the risk engines if they are not read
+    fn report(&self, report: Report) {
+        if let Err(TrySendError::Full(report)) = self.report_sender.try_send(report) {
+            warn!("Report dropped, the reports are not read: {:?}", report);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::tests::events;
+    use crate::persistence::PersistenceError;
+    use crossbeam::channel::unbounded;
+    use std::io;
+
+    /// Fails until it is told to work
+    struct Flaky(std::sync::Arc<std::sync::Mutex<bool>>);
+
+    impl Persistence for Flaky {
+        fn write(&mut self, _: &[DbEvent]) -> Result<(), PersistenceError> {
+            match *self.0.lock().unwrap() {
+                true => Ok(()),
+                false => Err(io::Error::from(io::ErrorKind::ConnectionRefused).into()),
+            }
+        }
+    }
+
+    #[test]
+    fn failing_writes_are_reported_and_keep_the_newest_events() {
+        let works = std::sync::Arc::new(std::sync::Mutex::new(false));
+        let (report_sender, reports) = unbounded();
+        let mut processor = DatabaseProcessor::new(
+            ExchangeSettings {
+                db_sync_speed: Duration::from_millis(10),
+                ..ExchangeSettings::default()
+            },
+            Box::new(Flaky(works.clone())),
+            report_sender,
+        );
+        processor.max_queued = 3;
+        let events = events();
+        processor.event_queue.extend(events.iter().cloned());
+        processor.sync_database();
+        processor.sync_database();
+        assert_eq!(processor.event_queue, events[events.len() - 3..]);
+        assert_eq!(processor.sync_interval(), Duration::from_millis(40));
+        let failures: Vec<(u32, usize, u64)> = reports
+            .try_iter()
+            .map(|report| match report {
+                Report::PersistenceFailure {
+                    failures,
+                    queued,
+                    dropped,
+                } => (failures, queued, dropped),
+                report => panic!("Unexpected report {:?}", report),
+            })
+            .collect();
+        let dropped = events.len() as u64 - 3;
+        assert_eq!(failures, vec![(1, 3, dropped), (2, 3, dropped)]);
+
+        *works.lock().unwrap() = true;
+        processor.sync_database();
+        assert!(processor.event_queue.is_empty());
+        assert_eq!(processor.sync_interval(), Duration::from_millis(10));
     }
 }
@@ -1,3 +1,4 @@
 pub mod database_processor;
 pub mod order_book_processor;
-pub mod risk_engine_processor;
\ No newline at end of file
+pub mod risk_engine_processor;
+pub mod sequencer;
@@ -1,14 +1,15 @@
-use crate::exchange::commands::OrderCommand;
+use crate::exchange::commands::{AdminCommand, MigrationCommand, OrderCommand};
 use crate::exchange::exchange_settings::ExchangeSettings;
-use crate::order_handling::event::MatchingEngineEvent;
+use crate::order_handling::event::{DbEvent, MatchingEngineEvent};
 use crate::order_handling::order::{self, *};
 use crate::order_handling::order_book::OrderBook;
-use crate::risk::router::{self, risk_router};
+use crate::risk::router::{self, ShardDirectory};
 use crossbeam::channel::{Receiver, Sender};
 use log::debug;
 use tokio::sync::mpsc;
 
 use std::collections::HashMap;
+use std::sync::Arc;
 use tokio::sync::RwLock;
 
 #[derive(Default)]
@@ -25,20 +26,65 @@ impl OrderBookProcessor {
     pub fn run(
         &mut self,
         receiver: Receiver<OrderCommand>,
-        senders: Vec<Sender<MatchingEngineEvent>>,
+        directory: Arc<ShardDirectory>,
+        db_sender: Sender<DbEvent>,
     ) {
-        let mut book = OrderBook::new(self.settings.clone(), senders);
+        let mut book = OrderBook::new(self.symbol_id, self.settings.clone(), directory, db_sender);
 
         while let Ok(order_command) = receiver.recv() {
-            debug!("Order book received command: {:?}", order_command);
-            match order_command {
-                OrderCommand::Trade(trade) => {
-                    book.insert_order(&trade);
-                }
-                OrderCommand::Cancel(cancel) => {
-                    book.cancel_order(cancel.order_id);
-                }
+            Self::process(&mut book, order_command);
+        }
+    }
+
+    /// Apply a command to the order book
+    pub fn process(book: &mut OrderBook, order_command: OrderCommand) {
+        debug!("Order book received command: {:?}", order_command);
+        // Closed books still answer, the shard waits for every book
+        if let OrderCommand::Migration(MigrationCommand::Flush {
+            participant_id,
+            shard,
+        }) = order_command
+        {
+            book.flush(participant_id, shard);
+            return;
+        }
+        if let Some(participant_id) = order_command.participant_id() {
+            book.register(participant_id);
+        }
+        if book.closed {
+            book.reject(&order_command);
+            return;
+        }
+        match order_command {
+            OrderCommand::Trade(trade) => {
+                book.insert_order(&trade);
+            }
+            OrderCommand::Cancel(cancel) => {
+                book.reduce_order(cancel.order_id, cancel.remaining);
+            }
+            OrderCommand::Quote(quote) => {
+                book.replace_quote(&quote);
+            }
+            OrderCommand::Oco(oco) => {
+                book.insert_oco(&oco);
+            }
+            OrderCommand::Bracket(bracket) => {
+                book.insert_bracket(&bracket);
+            }
+            OrderCommand::MassQuote(_) => {
+                unreachable!("Mass quotes are fanned out by the risk engine")
+            }
+            OrderCommand::Admin(AdminCommand::ExpireOption { .. } | AdminCommand::Halt { .. }) => {
+                book.close();
+            }
+            OrderCommand::Admin(_)
+            | OrderCommand::Query(_)
+            | OrderCommand::Margin(_)
+            | OrderCommand::Account(_)
+            | OrderCommand::Migration(_) => {
+                unreachable!("Only the risk engine handles these commands")
             }
         }
+        book.update_dependent_orders();
     }
 }
@@ -1,48 +1,96 @@
-use crate::exchange::commands::OrderCommand;
+use crate::exchange::asset::AssetId;
+use crate::exchange::commands::{
+    AdminCommand, MassQuoteCommand, MigrationCommand, OrderCommand, QueryCommand, TradeCommand,
+};
 use crate::exchange::exchange_settings::ExchangeSettings;
-use crate::order_handling::event::{self, MatchingEngineEvent};
+use crate::exchange::report::{MassQuoteAck, Report};
+use crate::journal::Clock;
+use crate::order_handling::event::{
+    self, BalanceChange, DbEvent, MatchingEngineEvent, OrderEvent, OrderEventKind,
+};
 use crate::order_handling::order::*;
 use crate::order_handling::order_book::OrderBook;
-use crate::risk::participant::Participant;
-use crate::risk::risk_engine::RiskEngine;
-use log::debug;
+use crate::risk::migration::{Handoff, ShardState};
+use crate::risk::participant::Balance;
+use crate::risk::rate_limiter::RateLimiter;
+use crate::risk::risk_engine::{
+    RiskEngine, RiskEngineResult, FEE_ACCOUNT_ID, INSURANCE_FUND_ID,
+};
+use crate::risk::router::ShardDirectory;
+use log::{debug, warn};
 use tokio::sync::mpsc;
-use crossbeam::channel::{Receiver, Sender, RecvError, Select};
+use crossbeam::channel::{Receiver, Sender, RecvError, Select, TrySendError};
 
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
 use tokio::sync::RwLock;
 
 pub struct RiskEngineProcessor {
     risk_engine: RiskEngine,
+    report_sender: Sender<Report>,
+    /// None if the participants are not throttled
+    rate_limiter: Option<RateLimiter>,
+    shard: usize,
+    directory: Arc<ShardDirectory>,
+    /// Participants moving to this shard, with their commands held back until they arrive
+    incoming: HashMap<u64, Vec<OrderCommand>>,
+    /// Events of orders this shard does not know yet, while participants move here
+    parked: Vec<MatchingEngineEvent>,
+    /// Older shards whose state did not arrive yet, if this shard was added later
+    seeds: usize,
+    /// Commands that arrived before the state of the older shards
+    held: Vec<OrderCommand>,
+    /// Participants moving away (shard they move to, order books that did not flush yet)
+    outgoing: HashMap<u64, (usize, usize)>,
+    /// None if nothing is persisted
+    db_sender: Option<Sender<DbEvent>>,
+    /// Balances last sent to the database
+    persisted_balances: HashMap<u64, HashMap<AssetId, Balance>>,
+    /// Participants whose balances may have changed since they were last persisted
+    touched: BTreeSet<u64>,
+    /// Journaled time of the commands in nanoseconds since the Unix epoch, None for the clock
+    time: Option<u64>,
+    clock: Clock,
 }
 
 impl RiskEngineProcessor {
-    pub fn new(settings: ExchangeSettings) -> Self {
-        let mut risk_engine = RiskEngine::new(settings);
-        let mut part = Participant::default();
-        part.assets.insert(0, 1000);
-        part.assets.insert(1, 1000);
-        part.assets.insert(2, 1000);
-        risk_engine.add_participant(part);
-        let mut part2 = Participant::default();
-        part2.assets.insert(0, 1000);
-        part2.assets.insert(1, 1000);
-        part2.assets.insert(2, 1000);
-        part2.id = 1;
-        risk_engine.add_participant(part2);
-        let mut part3 = Participant::default();
-        part3.assets.insert(0, 1000);
-        part3.assets.insert(1, 1000);
-        part3.assets.insert(2, 1000);
-        part3.id = 2;
-        risk_engine.add_participant(part3);
-        let mut part4 = Participant::default();
-        part4.assets.insert(0, 1000);
-        part4.assets.insert(1, 1000);
-        part4.assets.insert(2, 1000);
-        part4.id = 3;
-        risk_engine.add_participant(part4);
-        RiskEngineProcessor { risk_engine }
+    pub fn new(
+        shard: u64,
+        settings: ExchangeSettings,
+        report_sender: Sender<Report>,
+        directory: Arc<ShardDirectory>,
+        db_sender: Option<Sender<DbEvent>>,
+    ) -> Self {
+        let rate_limiter = settings.rate_limits.map(RateLimiter::new);
+        let risk_engine = RiskEngine::new(shard, settings);
+        RiskEngineProcessor {
+            risk_engine,
+            report_sender,
+            rate_limiter,
+            shard: shard as usize,
+            directory,
+            incoming: HashMap::new(),
+            parked: Vec::new(),
+            seeds: 0,
+            held: Vec::new(),
+            outgoing: HashMap::new(),
+            db_sender,
+            persisted_balances: HashMap::new(),
+            touched: BTreeSet::new(),
+            time: None,
+            clock: Clock::new(),
+        }
+    }
+
+    /// Take the time of the next commands from the journal instead of the clock,
+    /// so replaying them throttles the same way
+    pub fn set_time(&mut self, time: u64) {
+        self.time = Some(time);
+    }
+
+    /// Wait for the state of the shards that were there before this one
+    pub fn await_seeds(&mut self, shards: usize) {
+        self.seeds = shards;
     }
 
     pub fn run(
@@ -50,14 +98,17 @@ impl RiskEngineProcessor {
         command_receiver: Receiver<OrderCommand>,
         senders: Vec<Sender<OrderCommand>>,
         event_receiver: Receiver<MatchingEngineEvent>,
+        handoff_receiver: Receiver<Handoff>,
     ) {
 
         let select = Select::new();
         loop {
             crossbeam::channel::select! {
                 recv(command_receiver) -> order_command => self.run_pre(&senders, order_command),
-                recv(event_receiver) -> event => self.run_post(event),
+                recv(event_receiver) -> event => self.run_post(&senders, event),
+                recv(handoff_receiver) -> handoff => self.run_handoff(&senders, handoff),
             }
+            self.persist_balances();
         }
     }
 
@@ -66,37 +117,452 @@ impl RiskEngineProcessor {
         senders: &[Sender<OrderCommand>],
         order_command: Result<OrderCommand, RecvError>,
     ) {
-        if let Ok(order_command) = order_command {
+        if let Ok(mut order_command) = order_command {
             debug!("Risk on: {:?}", order_command);
-            let result = self.risk_engine.process_command(order_command);
+            if let OrderCommand::Migration(MigrationCommand::MoveIn { participant_id, .. }) =
+                order_command
+            {
+                self.incoming.entry(participant_id).or_default();
+                return;
+            }
+            if self.seeds > 0 {
+                self.held.push(order_command);
+                return;
+            }
+            let participant_id = match &order_command {
+                OrderCommand::Query(QueryCommand::Balances { participant_id }) => {
+                    Some(*participant_id)
+                }
+                command => command.participant_id(),
+            };
+            if let Some(held) = participant_id.and_then(|id| self.incoming.get_mut(&id)) {
+                held.push(order_command);
+                return;
+            }
+            if let Some(participant_id) = participant_id {
+                self.touch(participant_id);
+            }
+            let now = self.time.unwrap_or_else(|| self.clock.now());
+            if let Some(rate_limiter) = self.rate_limiter.as_mut() {
+                let result = rate_limiter.check(&order_command, now);
+                if result != RiskEngineResult::ValidForMatchingEngine {
+                    debug!("Rate limit exceeded: {:?}", result);
+                    match order_command {
+                        OrderCommand::MassQuote(mass_quote) => {
+                            let results = vec![result; mass_quote.quotes.len()];
+                            self.send_mass_quote_ack(&mass_quote, results);
+                        }
+                        command => self.report(Report::Rejected { command, result }),
+                    }
+                    return;
+                }
+            }
+            match order_command {
+                OrderCommand::MassQuote(mass_quote) => {
+                    return self.run_mass_quote(senders, mass_quote)
+                }
+                OrderCommand::Admin(command) => return self.run_admin(senders, command),
+                OrderCommand::Query(query) => return self.run_query(query),
+                OrderCommand::Margin(command) => {
+                    let result = self.risk_engine.process_margin(&command);
+                    debug!("Margin command: {:?}", result);
+                    return self.report(Report::Margin { command, result });
+                }
+                OrderCommand::Account(command) => {
+                    let result = self.risk_engine.process_account(&command);
+                    debug!("Account command: {:?}", result);
+                    self.report(Report::Account { command, result });
+                    return self.run_liquidations(senders);
+                }
+                OrderCommand::Migration(command) => return self.run_migration(senders, command),
+                _ => (),
+            }
+            let result = self.risk_engine.process_command(&mut order_command);
+            self.send_alerts();
             match result {
-                crate::risk::risk_engine::RiskEngineResult::ValidForMatchingEngine => {
+                RiskEngineResult::ValidForMatchingEngine => {
                     debug!("Order is valid");
-                  Self::send_to_matching_engine(order_command, senders)
+                    self.send_to_matching_engine(order_command, senders)
                 }
-                crate::risk::risk_engine::RiskEngineResult::InsufficientFunds => {
-                    debug!("Insufficient funds!")
-                }
-                crate::risk::risk_engine::RiskEngineResult::SymbolNotFound => todo!(),
-                crate::risk::risk_engine::RiskEngineResult::UserNotFound => todo!(),
-                crate::risk::risk_engine::RiskEngineResult::OrderNotFound => {
-                    debug!("Order not Found")
+                result => {
+                    debug!("Order rejected: {:?}", result);
+                    self.report(Report::Rejected {
+                        command: order_command,
+                        result,
+                    })
                 }
             }
         } 
     }
-    pub fn run_post(&mut self, event: Result<MatchingEngineEvent, RecvError>) {
+    /// Check a mass quote as a whole, fan the accepted quotes out to their order books
+    /// in the order they were sent and report the results in one acknowledgement
+    fn run_mass_quote(&mut self, senders: &[Sender<OrderCommand>], mass_quote: MassQuoteCommand) {
+        let results = self.risk_engine.process_mass_quote(&mass_quote);
+        self.send_alerts();
+        for (quote, result) in mass_quote.quotes.iter().zip(&results) {
+            if *result == crate::risk::risk_engine::RiskEngineResult::ValidForMatchingEngine {
+                self.send_to_matching_engine(OrderCommand::Quote(*quote), senders);
+            }
+        }
+        self.send_mass_quote_ack(&mass_quote, results);
+    }
+
+    /// Send a report to the clients, it is dropped if they do not keep up
+    fn report(&self, report: Report) {
+        if let Err(TrySendError::Full(report)) = self.report_sender.try_send(report) {
+            warn!("Report dropped, the reports are not read: {:?}", report);
+        }
+    }
+
+    /// Report the soft exposure limits the last orders went past
+    fn send_alerts(&mut self) {
+        for alert in self.risk_engine.take_alerts() {
+            self.report(Report::ExposureAlert(alert));
+        }
+    }
+
+    fn send_mass_quote_ack(&self, mass_quote: &MassQuoteCommand, results: Vec<RiskEngineResult>) {
+        self.report(Report::MassQuoteAck(MassQuoteAck {
+            id: mass_quote.id,
+            participant_id: mass_quote.participant_id,
+            results: mass_quote
+                .quotes
+                .iter()
+                .map(|quote| quote.symbol)
+                .zip(results)
+                .collect(),
+        }));
+    }
+
+    /// Apply an operator command to this shard and pass it on to the order book that needs it
+    fn run_admin(&mut self, senders: &[Sender<OrderCommand>], command: AdminCommand) {
+        self.risk_engine.process_admin(&command);
+        match command {
+            // Settlements and funding pay between any participants of the shard
+            AdminCommand::ExpireOption { .. } | AdminCommand::Funding { .. } => {
+                if self.db_sender.is_some() {
+                    self.touched.extend(self.risk_engine.participant_ids());
+                }
+            }
+            AdminCommand::InsuranceDeposit { .. } => self.touch(INSURANCE_FUND_ID),
+            AdminCommand::MarkPrice { .. }
+            | AdminCommand::RollDay
+            | AdminCommand::Halt { .. }
+            | AdminCommand::SymbolLimits { .. }
+            | AdminCommand::ParticipantLimits { .. }
+            | AdminCommand::ExposureLimits { .. } => (),
+        }
+        self.run_liquidations(senders);
+        if let (AdminCommand::RollDay, Some(rate_limiter)) = (command, self.rate_limiter.as_mut()) {
+            rate_limiter.roll_day();
+        }
+        match command {
+            AdminCommand::ExpireOption { .. } | AdminCommand::Halt { .. } => {
+                self.send_to_matching_engine(OrderCommand::Admin(command), senders)
+            }
+            AdminCommand::MarkPrice { .. }
+            | AdminCommand::Funding { .. }
+            | AdminCommand::RollDay
+            | AdminCommand::InsuranceDeposit { .. }
+            | AdminCommand::SymbolLimits { .. }
+            | AdminCommand::ParticipantLimits { .. }
+            | AdminCommand::ExposureLimits { .. } => (),
+        }
+    }
+
+    fn run_query(&mut self, query: QueryCommand) {
+        self.report(self.risk_engine.process_query(&query));
+    }
+
+    /// Start moving a participant away. Its orders may still be filled, so it is handed over
+    /// only once every order book confirmed that no earlier event is on its way here.
+    fn run_migration(&mut self, senders: &[Sender<OrderCommand>], command: MigrationCommand) {
+        match command {
+            MigrationCommand::MoveOut { participant_id, to } => {
+                if senders.is_empty() {
+                    return self.hand_off(participant_id, to);
+                }
+                self.outgoing.insert(participant_id, (to, senders.len()));
+                let flush = MigrationCommand::Flush {
+                    participant_id,
+                    shard: self.shard,
+                };
+                for sender in senders {
+                    let _ = sender.send(OrderCommand::Migration(flush));
+                }
+            }
+            MigrationCommand::Seed { to, shards } => {
+                debug!("Seeding shard {}", to);
+                let state = self.risk_engine.export_shard(shards as u64);
+                self.touch(INSURANCE_FUND_ID);
+                self.touch(FEE_ACCOUNT_ID);
+                let _ = self
+                    .directory
+                    .handoff_sender(to)
+                    .send(Handoff::Shard(state));
+            }
+            MigrationCommand::MoveIn { .. } => {
+                unreachable!("Participants moving here are held back before")
+            }
+            MigrationCommand::Flush { .. } => {
+                unreachable!("Only the order books answer flushes")
+            }
+        }
+    }
+
+    /// An order book sent every event of the participant's orders that was meant for this shard
+    fn flushed(&mut self, participant_id: u64) {
+        let to = match self.outgoing.get_mut(&participant_id) {
+            Some((to, books)) => {
+                *books -= 1;
+                if *books > 0 {
+                    return;
+                }
+                *to
+            }
+            None => return,
+        };
+        self.outgoing.remove(&participant_id);
+        self.hand_off(participant_id, to);
+    }
+
+    /// Send the participant with its open orders and its rate limits to the shard it moves to
+    fn hand_off(&mut self, participant_id: u64, to: usize) {
+        debug!("Moving participant {} to shard {}", participant_id, to);
+        let mut state = self.risk_engine.export_participant(participant_id);
+        if let (Some(state), Some(rate_limiter)) = (state.as_mut(), self.rate_limiter.as_mut()) {
+            state.rate_limits = rate_limiter.take(participant_id);
+        }
+        self.persisted_balances.remove(&participant_id);
+        let _ = self
+            .directory
+            .handoff_sender(to)
+            .send(Handoff::Participant {
+                participant_id,
+                state,
+            });
+    }
+
+    /// Take over a participant from another shard, then apply the events of its orders
+    /// and the commands that were held back
+    pub fn run_handoff(
+        &mut self,
+        senders: &[Sender<OrderCommand>],
+        handoff: Result<Handoff, RecvError>,
+    ) {
+        let (participant_id, state) = match handoff {
+            Ok(Handoff::Participant {
+                participant_id,
+                state,
+            }) => (participant_id, state),
+            Ok(Handoff::Shard(state)) => return self.seeded(senders, state),
+            Err(_) => return,
+        };
+        debug!("Participant {} moved here", participant_id);
+        self.touch(participant_id);
+        if let Some(mut state) = state {
+            if let (Some(limits), Some(rate_limiter)) =
+                (state.rate_limits.take(), self.rate_limiter.as_mut())
+            {
+                rate_limiter.insert(participant_id, limits);
+            }
+            self.risk_engine.import_participant(state);
+        }
+        let commands = self.incoming.remove(&participant_id).unwrap_or_default();
+        for event in std::mem::take(&mut self.parked) {
+            self.run_post(senders, Ok(event));
+        }
+        self.run_liquidations(senders);
+        for command in commands {
+            self.run_pre(senders, Ok(command));
+        }
+    }
+
+    /// Take over the state an older shard sent. Once every older shard sent it,
+    /// apply the events and the commands that arrived before.
+    fn seeded(&mut self, senders: &[Sender<OrderCommand>], state: ShardState) {
+        self.risk_engine.import_shard(state);
+        self.touch(INSURANCE_FUND_ID);
+        self.touch(FEE_ACCOUNT_ID);
+        self.seeds -= 1;
+        if self.seeds > 0 {
+            return;
+        }
+        debug!("Shard {} is seeded", self.shard);
+        for event in std::mem::take(&mut self.parked) {
+            self.run_post(senders, Ok(event));
+        }
+        self.run_liquidations(senders);
+        for command in std::mem::take(&mut self.held) {
+            self.run_pre(senders, Ok(command));
+        }
+    }
+
+    pub fn run_post(
+        &mut self,
+        senders: &[Sender<OrderCommand>],
+        event: Result<MatchingEngineEvent, RecvError>,
+    ) {
         if let Ok(event) = event {
             debug!("Risk off:     {:?}", event);
+            match event {
+                MatchingEngineEvent::Flushed(participant_id) => {
+                    return self.flushed(participant_id)
+                }
+                _ if self.seeds > 0 => {
+                    self.parked.push(event);
+                    return;
+                }
+                MatchingEngineEvent::Filled(id, ..)
+                | MatchingEngineEvent::Canceled(id)
+                | MatchingEngineEvent::Reduced(id, _)
+                    if !self.incoming.is_empty()
+                        && self.risk_engine.order_participant(id).is_none() =>
+                {
+                    self.parked.push(event);
+                    return;
+                }
+                _ => (),
+            }
+            self.persist_order_event(event);
+            if let (MatchingEngineEvent::Filled(id, ..), Some(rate_limiter)) =
+                (event, self.rate_limiter.as_mut())
+            {
+                if let Some(participant_id) = self.risk_engine.order_participant(id) {
+                    rate_limiter.record_trade(participant_id);
+                }
+            }
             self.risk_engine.process_matcher_event(event);
+            for cancel in self.risk_engine.take_cancels() {
+                self.send_to_matching_engine(OrderCommand::Cancel(cancel), senders);
+            }
+            self.run_liquidations(senders);
+        }
+    }
+
+    /// Note a participant whose balances may change
+    fn touch(&mut self, participant_id: u64) {
+        if self.db_sender.is_some() {
+            self.touched.insert(participant_id);
+        }
+    }
+
+    /// Send a fill or cancel of an order to the database, before the risk engine forgets the order
+    fn persist_order_event(&mut self, event: MatchingEngineEvent) {
+        let (order_id, kind) = match event {
+            MatchingEngineEvent::Filled(id, volume, value, liquidity) => (
+                id,
+                OrderEventKind::Filled {
+                    volume,
+                    value,
+                    liquidity,
+                },
+            ),
+            MatchingEngineEvent::Reduced(id, volume) => (id, OrderEventKind::Reduced { volume }),
+            MatchingEngineEvent::Canceled(id) => (id, OrderEventKind::Canceled),
+            MatchingEngineEvent::FairPrice(..) | MatchingEngineEvent::Flushed(_) => return,
+        };
+        let participant_id = match self.risk_engine.order_participant(order_id) {
+            Some(participant_id) => participant_id,
+            None => return,
+        };
+        self.touch(participant_id);
+        if let Some(db_sender) = &self.db_sender {
+            let _ = db_sender.send(DbEvent::Order(OrderEvent {
+                order_id,
+                participant_id,
+                kind,
+            }));
+        }
+    }
+
+    /// Send the balances that changed since they were last persisted.
+    /// Fees and liquidations move funds of the fee account and the insurance fund as well.
+    pub fn persist_balances(&mut self) {
+        let db_sender = match &self.db_sender {
+            Some(db_sender) => db_sender,
+            None => return,
+        };
+        let touched = std::mem::take(&mut self.touched);
+        for participant_id in touched
+            .into_iter()
+            .chain([FEE_ACCOUNT_ID, INSURANCE_FUND_ID])
+        {
+            let persisted = self.persisted_balances.entry(participant_id).or_default();
+            for (asset, balance) in self.risk_engine.balances(participant_id) {
+                if persisted.get(&asset) != Some(&balance) {
+                    persisted.insert(asset, balance);
+                    let _ = db_sender.send(DbEvent::Balance(BalanceChange {
+                        participant_id,
+                        asset,
+                        balance,
+                    }));
+                }
+            }
         }
     }
 
-    fn send_to_matching_engine(command: OrderCommand, senders: &[Sender<OrderCommand>]) {
+    /// Start liquidating the participants that fell below maintenance margin
+    fn run_liquidations(&mut self, senders: &[Sender<OrderCommand>]) {
+        for (report, commands) in self.risk_engine.check_margins() {
+            for command in commands {
+                self.send_to_matching_engine(command, senders);
+            }
+            self.report(Report::Liquidation(report));
+        }
+        // Losses beyond the insurance fund stop trading in their symbol on every shard
+        for symbol in self.risk_engine.take_halts() {
+            self.send_to_matching_engine(
+                OrderCommand::Admin(AdminCommand::Halt { symbol }),
+                senders,
+            );
+        }
+    }
+
+    fn send_to_matching_engine(&self, command: OrderCommand, senders: &[Sender<OrderCommand>]) {
         // println!("Sending to matching engine: {:?}", command);
+        if let Some(db_sender) = &self.db_sender {
+            let orders: Vec<TradeCommand> = match &command {
+                OrderCommand::Trade(trade) => vec![*trade],
+                OrderCommand::Quote(quote) => quote.legs().collect(),
+                OrderCommand::Oco(oco) => vec![oco.first, oco.second],
+                OrderCommand::Bracket(bracket) => {
+                    vec![bracket.entry, bracket.exit.first, bracket.exit.second]
+                }
+                _ => Vec::new(),
+            };
+            for order in orders {
+                let _ = db_sender.send(DbEvent::Order(OrderEvent {
+                    order_id: order.id,
+                    participant_id: order.participant_id,
+                    kind: OrderEventKind::Placed {
+                        symbol: order.symbol,
+                        side: order.side,
+                        limit: order.limit,
+                        volume: order.volume,
+                    },
+                }));
+            }
+        }
         let symbol_id = match command {
             OrderCommand::Trade(command) => command.symbol,
             OrderCommand::Cancel(command) => command.symbol,
+            OrderCommand::Quote(command) => command.symbol,
+            OrderCommand::Oco(command) => command.first.symbol,
+            OrderCommand::Bracket(command) => command.entry.symbol,
+            OrderCommand::MassQuote(_) => {
+                unreachable!("Mass quotes are fanned out into single quotes")
+            }
+            OrderCommand::Admin(
+                AdminCommand::ExpireOption { symbol, .. } | AdminCommand::Halt { symbol },
+            ) => symbol,
+            OrderCommand::Admin(_)
+            | OrderCommand::Query(_)
+            | OrderCommand::Margin(_)
+            | OrderCommand::Account(_)
+            | OrderCommand::Migration(_) => {
+                unreachable!("Only the risk engine handles these commands")
+            }
         };
         let _ = senders[symbol_id as usize].send(command);
     }
@@ -0,0 +1,157 @@
+use crate::exchange::commands::OrderCommand;
+use crate::exchange::report::Report;
+use crate::order_handling::event::{DbEvent, MatchingEngineEvent};
+use crate::order_handling::order_book::OrderBook;
+use crate::risk::migration::Handoff;
+use crossbeam::channel::{unbounded, Receiver, Sender, TrySendError};
+use log::warn;
+
+use super::order_book_processor::OrderBookProcessor;
+use super::risk_engine_processor::RiskEngineProcessor;
+
+/// A risk engine shard with the channels it reads from
+struct Shard {
+    processor: RiskEngineProcessor,
+    commands: Receiver<OrderCommand>,
+    events: Receiver<MatchingEngineEvent>,
+    handoffs: Receiver<Handoff>,
+}
+
+/// Runs the risk engine shards and the order books on the caller's thread, one message at a time.
+///
+/// The messages are taken in a fixed order until every channel is empty, so the same commands
+/// always lead to the same state, which is what makes a journal replay exact.
+pub struct Sequencer {
+    shards: Vec<Shard>,
+    books: Vec<(OrderBook, Receiver<OrderCommand>)>,
+    book_senders: Vec<Sender<OrderCommand>>,
+    /// Journaled time of the current record in nanoseconds since the Unix epoch
+    time: u64,
+    /// Reports of the shards, passed on once a record is done
+    reports: (Sender<Report>, Receiver<Report>),
+    /// Database events of the shards and books, passed on once a record is done
+    db_events: (Sender<DbEvent>, Receiver<DbEvent>),
+    report_sender: Sender<Report>,
+    /// None if nothing is persisted
+    db_sender: Option<Sender<DbEvent>>,
+    /// Replayed records sent their reports and database events before, they are dropped
+    replaying: bool,
+}
+
+impl Sequencer {
+    pub fn new(report_sender: Sender<Report>, db_sender: Option<Sender<DbEvent>>) -> Self {
+        Sequencer {
+            shards: Vec::new(),
+            books: Vec::new(),
+            book_senders: Vec::new(),
+            time: 0,
+            reports: unbounded(),
+            db_events: unbounded(),
+            report_sender,
+            db_sender,
+            replaying: false,
+        }
+    }
+
+    /// Sender the shards report to
+    pub fn report_sender(&self) -> Sender<Report> {
+        self.reports.0.clone()
+    }
+
+    /// Sender the shards and books persist to, None if nothing is persisted
+    pub fn db_sender(&self) -> Option<Sender<DbEvent>> {
+        self.db_sender.as_ref().map(|_| self.db_events.0.clone())
+    }
+
+    /// Add the order book of the next symbol. Returns the sender of its commands.
+    pub fn add_book(&mut self, book: OrderBook) -> Sender<OrderCommand> {
+        let (sender, receiver) = unbounded();
+        self.books.push((book, receiver));
+        self.book_senders.push(sender.clone());
+        sender
+    }
+
+    /// Add a risk engine shard with the channels its commands, matching engine events
+    /// and migrated participants arrive on
+    pub fn add_shard(
+        &mut self,
+        processor: RiskEngineProcessor,
+        commands: Receiver<OrderCommand>,
+        events: Receiver<MatchingEngineEvent>,
+        handoffs: Receiver<Handoff>,
+    ) {
+        self.shards.push(Shard {
+            processor,
+            commands,
+            events,
+            handoffs,
+        });
+    }
+
+    /// Order books in symbol order
+    pub fn books(&self) -> impl Iterator<Item = &OrderBook> {
+        self.books.iter().map(|(book, _)| book)
+    }
+
+    /// Set the time of the next record
+    pub fn set_time(&mut self, time: u64) {
+        self.time = time;
+    }
+
+    /// Drop the reports and database events while the journal is replayed
+    pub fn set_replaying(&mut self, replaying: bool) {
+        self.replaying = replaying;
+    }
+
+    /// Handle messages until every channel is empty, then pass on the reports and database events.
+    ///
+    /// Each round the order books go first in symbol order, then every shard takes its handoffs,
+    /// its matching engine events and its commands in shard order.
+    pub fn run(&mut self) {
+        for shard in &mut self.shards {
+            shard.processor.set_time(self.time);
+        }
+        loop {
+            let mut idle = true;
+            for (book, receiver) in &mut self.books {
+                for command in receiver.try_iter() {
+                    OrderBookProcessor::process(book, command);
+                    idle = false;
+                }
+            }
+            for shard in &mut self.shards {
+                for handoff in shard.handoffs.try_iter() {
+                    shard.processor.run_handoff(&self.book_senders, Ok(handoff));
+                    shard.processor.persist_balances();
+                    idle = false;
+                }
+                for event in shard.events.try_iter() {
+                    shard.processor.run_post(&self.book_senders, Ok(event));
+                    shard.processor.persist_balances();
+                    idle = false;
+                }
+                for command in shard.commands.try_iter() {
+                    shard.processor.run_pre(&self.book_senders, Ok(command));
+                    shard.processor.persist_balances();
+                    idle = false;
+                }
+            }
+            if idle {
+                break;
+            }
+        }
+        for report in self.reports.1.try_iter() {
+            if self.replaying {
+                continue;
+            }
+            if let Err(TrySendError::Full(report)) = self.report_sender.try_send(report) {
+                warn!("Report dropped, the reports are not read: {:?}", report);
+            }
+        }
+        for event in self.db_events.1.try_iter() {
+            if let (false, Some(db_sender)) = (self.replaying, &self.db_sender) {
+                let _ = db_sender.send(event);
+            }
+        }
+    }
+}
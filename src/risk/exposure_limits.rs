@@ -0,0 +1,73 @@
+/// A limit that rejects orders above `hard` and only raises an alert above `soft`,
+/// None for no limit
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExposureLimit {
+    pub soft: Option<u64>,
+    pub hard: Option<u64>,
+}
+
+impl ExposureLimit {
+    fn is_hard_breach(&self, value: u64) -> bool {
+        self.hard.is_some_and(|hard| value > hard)
+    }
+
+    fn soft_breach(&self, value: u64) -> Option<u64> {
+        self.soft.filter(|soft| value > *soft)
+    }
+}
+
+/// Exposure limits of a participant
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExposureLimits {
+    /// Contracts long or short on one symbol if all open orders on one side were filled
+    pub net_position: ExposureLimit,
+    /// Contracts of the position plus all open order volume on one symbol
+    pub gross_position: ExposureLimit,
+    /// Volume times limit price of all open orders
+    pub open_orders: ExposureLimit,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ExposureKind {
+    NetPosition,
+    GrossPosition,
+    OpenOrders,
+}
+
+/// A participant went past a soft exposure limit
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ExposureAlert {
+    pub participant_id: u64,
+    /// None for the open orders on all symbols
+    pub symbol: Option<u64>,
+    pub kind: ExposureKind,
+    pub value: u64,
+    pub limit: u64,
+}
+
+impl ExposureLimits {
+    /// The kind of exposure whose hard limit the value is past, or the alert for a soft limit
+    pub fn check(
+        &self,
+        participant_id: u64,
+        symbol: Option<u64>,
+        kind: ExposureKind,
+        value: u64,
+    ) -> Result<Option<ExposureAlert>, ExposureKind> {
+        let limit = match kind {
+            ExposureKind::NetPosition => self.net_position,
+            ExposureKind::GrossPosition => self.gross_position,
+            ExposureKind::OpenOrders => self.open_orders,
+        };
+        if limit.is_hard_breach(value) {
+            return Err(kind);
+        }
+        Ok(limit.soft_breach(value).map(|limit| ExposureAlert {
+            participant_id,
+            symbol,
+            kind,
+            value,
+            limit,
+        }))
+    }
+}
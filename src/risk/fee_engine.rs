@@ -0,0 +1,138 @@
+use fxhash::FxHashMap;
+use std::collections::VecDeque;
+
+use crate::exchange::asset::AssetId;
+use crate::order_handling::event::Liquidity;
+
+/// Days of trading volume that decide the fee tier of a participant
+pub const VOLUME_DAYS: usize = 30;
+
+/// Fee rates in basis points of the fill value, negative rates are rebates
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub struct FeeRates {
+    pub maker: i64,
+    pub taker: i64,
+}
+
+/// Rates for participants that traded at least `min_volume` of a symbol in the last 30 days
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub struct FeeTier {
+    pub min_volume: u64,
+    pub rates: FeeRates,
+}
+
+/// Fees of one symbol
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct FeeSchedule {
+    /// Rates of participants that reach no tier
+    pub rates: FeeRates,
+    pub tiers: Vec<FeeTier>,
+    /// Asset the fees are charged in, None for the asset the fill is paid in.
+    /// Fees in another asset are converted at the mark price of the pair trading it for the paid asset.
+    pub asset: Option<AssetId>,
+}
+
+impl FeeSchedule {
+    /// Rates of the highest tier reached with the volume
+    pub fn rates(&self, volume: u64) -> FeeRates {
+        self.tiers
+            .iter()
+            .filter(|tier| tier.min_volume <= volume)
+            .max_by_key(|tier| tier.min_volume)
+            .map_or(self.rates, |tier| tier.rates)
+    }
+
+    /// Highest rate any tier charges makers or takers, zero if all of them are rebates
+    pub fn max_rate(&self) -> u64 {
+        self.tiers
+            .iter()
+            .map(|tier| tier.rates)
+            .chain(std::iter::once(self.rates))
+            .flat_map(|rates| [rates.maker, rates.taker])
+            .max()
+            .map_or(0, |rate| std::cmp::max(rate, 0) as u64)
+    }
+}
+
+/// Keeps the daily trading volume of the participants for their fee tiers.
+///
+/// Days are rolled by an operator command, so every shard rolls at the same point.
+#[derive(Default)]
+pub struct FeeEngine {
+    /// Traded value per participant and symbol and day, the current day last
+    volumes: FxHashMap<(u64, u64), VecDeque<u64>>,
+}
+
+impl FeeEngine {
+    /// Fee of a fill in the asset it was paid in, negative for a rebate.
+    /// Charges are rounded up and rebates down.
+    pub fn fee(
+        &self,
+        schedule: &FeeSchedule,
+        participant_id: u64,
+        symbol: u64,
+        value: u64,
+        liquidity: Liquidity,
+    ) -> i64 {
+        let rates = schedule.rates(self.volume(participant_id, symbol));
+        let rate = match liquidity {
+            Liquidity::Maker => rates.maker,
+            Liquidity::Taker => rates.taker,
+        };
+        let fee = value as i64 * rate;
+        if fee > 0 {
+            (fee + 9_999) / 10_000
+        } else {
+            fee / 10_000
+        }
+    }
+
+    /// Add the value of a fill to the current day
+    pub fn record(&mut self, participant_id: u64, symbol: u64, value: u64) {
+        let days = self
+            .volumes
+            .entry((participant_id, symbol))
+            .or_insert_with(|| VecDeque::from(vec![0]));
+        *days.back_mut().unwrap() += value;
+    }
+
+    /// Value traded in the last 30 days including the current day
+    pub fn volume(&self, participant_id: u64, symbol: u64) -> u64 {
+        self.volumes
+            .get(&(participant_id, symbol))
+            .map_or(0, |days| days.iter().sum())
+    }
+
+    /// Remove the volume of a participant that moves to another shard
+    pub fn take_volumes(&mut self, participant_id: u64) -> Vec<(u64, VecDeque<u64>)> {
+        let mut symbols: Vec<(u64, u64)> = self
+            .volumes
+            .keys()
+            .filter(|(participant, _)| *participant == participant_id)
+            .copied()
+            .collect();
+        symbols.sort_unstable();
+        symbols
+            .into_iter()
+            .map(|key| (key.1, self.volumes.remove(&key).unwrap()))
+            .collect()
+    }
+
+    /// Add the volume of a participant that moved here from another shard
+    pub fn insert_volumes(&mut self, participant_id: u64, volumes: Vec<(u64, VecDeque<u64>)>) {
+        for (symbol, days) in volumes {
+            self.volumes.insert((participant_id, symbol), days);
+        }
+    }
+
+    /// Start a new day and forget the volume that left the 30 day window
+    pub fn roll_day(&mut self) {
+        self.volumes.retain(|_, days| {
+            days.push_back(0);
+            if days.len() > VOLUME_DAYS {
+                days.pop_front();
+            }
+            days.iter().any(|volume| *volume > 0)
+        });
+    }
+}
@@ -0,0 +1,104 @@
+use fxhash::FxHashMap;
+
+use crate::exchange::asset::PerpetualSpec;
+
+/// One funding payment of a perpetual swap
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FundingRecord {
+    /// Unix timestamp in seconds
+    pub time: u64,
+    pub mark_price: u64,
+    pub index_price: u64,
+    /// Basis points of the notional value that longs pay to shorts, negative if shorts pay longs
+    pub rate: i64,
+}
+
+/// Computes the funding rates of the perpetual swaps and keeps their history.
+///
+/// The rate only depends on the funding command, so every risk engine shard
+/// computes the same rate and keeps the same history.
+#[derive(Clone, Default)]
+pub struct FundingEngine {
+    history: FxHashMap<u64, Vec<FundingRecord>>,
+}
+
+impl FundingEngine {
+    /// Premium of the mark price over the index price, capped by the symbol's maximum rate
+    pub fn rate(spec: &PerpetualSpec, mark_price: u64, index_price: u64) -> i64 {
+        if index_price == 0 {
+            return 0;
+        }
+        let premium = (mark_price as i64 - index_price as i64) * 10_000 / index_price as i64;
+        let max = spec.max_funding_rate as i64;
+        premium.clamp(-max, max)
+    }
+
+    /// Record a funding payment if the funding interval has passed since the last one
+    pub fn fund(
+        &mut self,
+        symbol: u64,
+        spec: &PerpetualSpec,
+        time: u64,
+        mark_price: u64,
+        index_price: u64,
+    ) -> Option<FundingRecord> {
+        let history = self.history.entry(symbol).or_default();
+        if let Some(last) = history.last() {
+            if time < last.time + spec.funding_interval {
+                return None;
+            }
+        }
+        let record = FundingRecord {
+            time,
+            mark_price,
+            index_price,
+            rate: Self::rate(spec, mark_price, index_price),
+        };
+        history.push(record);
+        Some(record)
+    }
+
+    /// All funding payments of a symbol, oldest first
+    pub fn history(&self, symbol: u64) -> &[FundingRecord] {
+        self.history
+            .get(&symbol)
+            .map_or(&[], |history| history.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exchange::asset::FuturesSpec;
+
+    const SPEC: PerpetualSpec = PerpetualSpec {
+        margin: FuturesSpec {
+            initial_margin: 1_000,
+            maintenance_margin: 500,
+        },
+        funding_interval: 3_600,
+        max_funding_rate: 75,
+    };
+
+    #[test]
+    fn rate_is_the_premium_capped_in_both_directions() {
+        assert_eq!(FundingEngine::rate(&SPEC, 10_050, 10_000), 50);
+        assert_eq!(FundingEngine::rate(&SPEC, 9_980, 10_000), -20);
+        assert_eq!(FundingEngine::rate(&SPEC, 11_000, 10_000), 75);
+        assert_eq!(FundingEngine::rate(&SPEC, 9_000, 10_000), -75);
+        assert_eq!(FundingEngine::rate(&SPEC, 9_000, 0), 0);
+    }
+
+    #[test]
+    fn funding_waits_for_the_interval() {
+        let mut funding = FundingEngine::default();
+        assert!(funding.fund(0, &SPEC, 1_000, 10_050, 10_000).is_some());
+        assert!(funding.fund(0, &SPEC, 4_599, 10_050, 10_000).is_none());
+        // Other symbols have their own interval
+        assert!(funding.fund(1, &SPEC, 4_599, 10_050, 10_000).is_some());
+        let record = funding.fund(0, &SPEC, 4_600, 10_000, 10_020).unwrap();
+        assert_eq!(record.rate, -19);
+        let times: Vec<u64> = funding.history(0).iter().map(|r| r.time).collect();
+        assert_eq!(times, vec![1_000, 4_600]);
+    }
+}
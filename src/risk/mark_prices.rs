@@ -0,0 +1,30 @@
+use fxhash::FxHashMap;
+
+/// Mark prices of the symbols, used to value positions
+///
+/// The index price set by the operator takes precedence,
+/// symbols without an index are marked at the fair price of their order book.
+#[derive(Clone, Default)]
+pub struct MarkPrices {
+    /// Prices set by the operator, standing in for an external index
+    index: FxHashMap<u64, u64>,
+    /// Fair prices published by the order books
+    fair: FxHashMap<u64, u64>,
+}
+
+impl MarkPrices {
+    pub fn set_index(&mut self, symbol: u64, price: u64) {
+        self.index.insert(symbol, price);
+    }
+
+    pub fn set_fair(&mut self, symbol: u64, price: u64) {
+        self.fair.insert(symbol, price);
+    }
+
+    pub fn get(&self, symbol: u64) -> Option<u64> {
+        self.index
+            .get(&symbol)
+            .or_else(|| self.fair.get(&symbol))
+            .copied()
+    }
+}
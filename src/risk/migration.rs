@@ -0,0 +1,52 @@
+use std::collections::VecDeque;
+
+use crate::exchange::asset::AssetId;
+
+use super::{
+    exposure_limits::ExposureLimits, funding_engine::FundingEngine, mark_prices::MarkPrices,
+    order_limits::OrderLimitBook, participant::Participant, rate_limiter::ParticipantLimits,
+    risk_engine::MarginAccount, risk_order::RiskOrder,
+};
+
+/// Everything a risk engine shard keeps about one participant
+pub struct ParticipantState {
+    pub participant: Participant,
+    /// Open orders (order id, symbol, order)
+    pub orders: Vec<(u64, u64, RiskOrder)>,
+    /// Volume times limit price of the open orders
+    pub open_exposure: u64,
+    /// Traded value per symbol and day for the fee tiers
+    pub volumes: Vec<(u64, VecDeque<u64>)>,
+    /// Collateral pools being liquidated
+    pub liquidating: Vec<MarginAccount>,
+    /// Open liquidation orders (order id, account, bankruptcy price)
+    pub liquidation_orders: Vec<(u64, MarginAccount, u64)>,
+    /// None if the participant is not throttled or sent nothing yet
+    pub rate_limits: Option<ParticipantLimits>,
+}
+
+/// What a risk engine shard keeps apart from its participants, for a shard added later
+pub struct ShardState {
+    /// Settlement prices of the expired options
+    pub expired: Vec<(u64, u64)>,
+    /// Symbols that stopped trading, in id order
+    pub halted: Vec<u64>,
+    pub funding: FundingEngine,
+    pub mark_prices: MarkPrices,
+    pub order_limits: OrderLimitBook,
+    pub exposure_limits: Vec<(u64, ExposureLimits)>,
+    /// Balances given away by the insurance fund and the fee account
+    pub insurance: Vec<(AssetId, u64)>,
+    pub fees: Vec<(AssetId, u64)>,
+}
+
+/// State sent from one risk engine shard to another
+pub enum Handoff {
+    /// A participant moved to the shard, None if the shard it came from did not know it
+    Participant {
+        participant_id: u64,
+        state: Option<ParticipantState>,
+    },
+    /// Sent by every older shard to a shard that was just added
+    Shard(ShardState),
+}
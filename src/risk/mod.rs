@@ -2,4 +2,12 @@ pub mod participant;
 pub mod risk_engine;
 pub mod risk_order;
 pub mod router;
-pub mod position_record;
\ No newline at end of file
+pub mod position_record;
+pub mod funding_engine;
+pub mod mark_prices;
+pub mod portfolio_margin;
+pub mod fee_engine;
+pub mod rate_limiter;
+pub mod order_limits;
+pub mod exposure_limits;
+pub mod migration;
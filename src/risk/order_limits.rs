@@ -0,0 +1,74 @@
+use fxhash::FxHashMap;
+
+use crate::exchange::commands::TradeCommand;
+
+use super::risk_engine::RiskEngineResult;
+
+/// Limits of a single order, None for no limit
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct OrderLimits {
+    pub max_volume: Option<u64>,
+    /// Highest volume times limit price
+    pub max_notional: Option<u64>,
+    /// Highest distance of the limit price from the reference price in basis points
+    pub max_price_deviation: Option<u64>,
+}
+
+impl OrderLimits {
+    fn check(&self, order: &TradeCommand, reference_price: Option<u64>) -> RiskEngineResult {
+        if self.max_volume.is_some_and(|max| order.volume > max) {
+            return RiskEngineResult::VolumeLimitExceeded;
+        }
+        // A notional that does not fit is above every limit
+        if self.max_notional.is_some_and(|max| {
+            order
+                .volume
+                .checked_mul(order.limit)
+                .is_none_or(|notional| notional > max)
+        }) {
+            return RiskEngineResult::NotionalLimitExceeded;
+        }
+        if let (Some(max), Some(reference)) = (self.max_price_deviation, reference_price) {
+            let deviation = order.limit.abs_diff(reference) as u128;
+            if deviation * 10_000 > reference as u128 * max as u128 {
+                return RiskEngineResult::PriceDeviationExceeded;
+            }
+        }
+        RiskEngineResult::ValidForMatchingEngine
+    }
+}
+
+/// Fat finger limits by symbol and by participant, an order has to pass both
+#[derive(Clone, Default)]
+pub struct OrderLimitBook {
+    symbols: FxHashMap<u64, OrderLimits>,
+    participants: FxHashMap<u64, OrderLimits>,
+}
+
+impl OrderLimitBook {
+    pub fn set_symbol(&mut self, symbol: u64, limits: OrderLimits) {
+        self.symbols.insert(symbol, limits);
+    }
+
+    pub fn set_participant(&mut self, participant_id: u64, limits: OrderLimits) {
+        self.participants.insert(participant_id, limits);
+    }
+
+    /// Check an order against the limits of its symbol and its participant.
+    /// Without a reference price the deviation is not checked,
+    /// like for orders that only trade once they are triggered.
+    pub fn check(&self, order: &TradeCommand, reference_price: Option<u64>) -> RiskEngineResult {
+        let limits = self
+            .symbols
+            .get(&order.symbol)
+            .into_iter()
+            .chain(self.participants.get(&order.participant_id));
+        for limits in limits {
+            let result = limits.check(order, reference_price);
+            if result != RiskEngineResult::ValidForMatchingEngine {
+                return result;
+            }
+        }
+        RiskEngineResult::ValidForMatchingEngine
+    }
+}
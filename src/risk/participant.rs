@@ -1,7 +1,12 @@
 use crate::exchange::asset::*;
-use std::{collections::HashMap, default};
+use fxhash::FxHashMap;
+use std::default;
 
-use super::risk_order::RiskOrder;
+use super::{
+    position_record::{MarginMode, PositionRecord},
+    risk_engine::MarginAccount,
+    risk_order::RiskOrder,
+};
 
 /// A market participant holding assets and position
 ///
@@ -10,9 +15,129 @@ use super::risk_order::RiskOrder;
 pub struct Participant {
     /// A unique id representing the account
     pub id: u64,
-    /// How much of each asset an account holds.
-    pub assets: HashMap<AssetId, u64>,
+    /// How much of each asset an account holds and can spend.
+    pub assets: FxHashMap<AssetId, u64>,
+    /// How much of each asset is held by open orders
+    pub held: FxHashMap<AssetId, u64>,
+    /// Part of `held` taken from the collateral of isolated positions
+    pub isolated_held: FxHashMap<AssetId, u64>,
 
     /// Standing orders of this participant
-    pub orders: HashMap<(u64, u64), Box<RiskOrder>>,
+    pub orders: FxHashMap<(u64, u64), Box<RiskOrder>>,
+
+    /// Open futures positions by symbol
+    pub positions: FxHashMap<usize, PositionRecord>,
+
+    /// Order ids of the last quote on each symbol (bid_id, ask_id)
+    pub quotes: FxHashMap<u64, (u64, u64)>,
+}
+
+impl Participant {
+    /// Collateral backing the position of a symbol,
+    /// the collateral allocated to the position in isolated mode
+    pub fn collateral(&mut self, symbol: usize, asset: AssetId) -> &mut u64 {
+        match self.positions.get_mut(&symbol) {
+            Some(position) if position.mode == MarginMode::Isolated => &mut position.collateral,
+            _ => self.assets.entry(asset).or_insert(0),
+        }
+    }
+
+    /// Collateral of a pool, the free funds of an asset for the cross margin positions
+    /// or the collateral allocated to an isolated position.
+    /// The isolated position has to exist.
+    pub fn pool(&mut self, account: MarginAccount) -> &mut u64 {
+        match account {
+            MarginAccount::Cross(asset) => self.assets.entry(asset).or_insert(0),
+            MarginAccount::Isolated(symbol) => {
+                &mut self
+                    .positions
+                    .get_mut(&(symbol as usize))
+                    .expect("Isolated pool without a position")
+                    .collateral
+            }
+        }
+    }
+
+    /// Collateral backing the position of a symbol that is free to hold
+    pub fn available(&self, symbol: usize, asset: AssetId) -> u64 {
+        match self.positions.get(&symbol) {
+            Some(position) if position.mode == MarginMode::Isolated => position.collateral,
+            _ => self.assets.get(&asset).copied().unwrap_or(0),
+        }
+    }
+
+    /// Hold funds for an order on a symbol, taken from the collateral backing the symbol.
+    /// Returns false and holds nothing if the funds are not available.
+    #[must_use]
+    pub fn hold(&mut self, symbol: usize, asset: AssetId, amount: u64) -> bool {
+        let available = self.collateral(symbol, asset);
+        if *available < amount {
+            return false;
+        }
+        *available -= amount;
+        *self.held.entry(asset).or_insert(0) += amount;
+        if self.is_isolated(symbol) {
+            *self.isolated_held.entry(asset).or_insert(0) += amount;
+        }
+        true
+    }
+
+    /// Return held funds an order no longer needs to the collateral backing the symbol
+    pub fn release(&mut self, symbol: usize, asset: AssetId, amount: u64) {
+        self.settle(asset, amount);
+        if self.is_isolated(symbol) {
+            let held = self.isolated_held.entry(asset).or_insert(0);
+            *held = held.saturating_sub(amount);
+        }
+        *self.collateral(symbol, asset) += amount;
+    }
+
+    fn is_isolated(&self, symbol: usize) -> bool {
+        self.positions
+            .get(&symbol)
+            .is_some_and(|position| position.mode == MarginMode::Isolated)
+    }
+
+    /// Take held funds out of the account to pay for a fill
+    pub fn settle(&mut self, asset: AssetId, amount: u64) {
+        let held = self.held.entry(asset).or_insert(0);
+        debug_assert!(*held >= amount, "Settled more than was held");
+        *held -= amount;
+    }
+
+    /// Available and held funds of an asset.
+    /// Collateral of isolated positions, free or held, and position margin are not included.
+    pub fn balance(&self, asset: AssetId) -> Balance {
+        let available = self.assets.get(&asset).copied().unwrap_or(0);
+        let isolated = self.isolated_held.get(&asset).copied().unwrap_or(0);
+        let held = self.held.get(&asset).copied().unwrap_or(0) - isolated;
+        Balance {
+            available,
+            held,
+            total: available + held,
+        }
+    }
+
+    /// Balances of all assets the participant ever held, by asset id
+    pub fn balances(&self) -> Vec<(AssetId, Balance)> {
+        let mut assets: Vec<AssetId> = self
+            .assets
+            .keys()
+            .chain(self.held.keys())
+            .copied()
+            .collect();
+        assets.sort_unstable();
+        assets.dedup();
+        assets
+            .into_iter()
+            .map(|asset| (asset, self.balance(asset)))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Balance {
+    pub available: u64,
+    pub held: u64,
+    pub total: u64,
 }
@@ -0,0 +1,89 @@
+use std::collections::BTreeMap;
+
+use crate::exchange::asset::{AssetId, OptionSpec};
+
+/// Price moves a portfolio is margined against, rates in basis points.
+///
+/// Every symbol on the same underlying asset moves by the same relative amount,
+/// so spot holdings hedged with futures or calendar spreads offset each other.
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub struct ScenarioGrid {
+    /// Largest move of the underlying price in each direction
+    pub price_move: u64,
+    /// Number of scenarios between no move and the largest move
+    pub steps: u64,
+    /// Charged on the gross notional value of all legs, covers the imperfect offsets
+    pub spread_charge: u64,
+}
+
+/// A holding or position whose value depends on the price of an underlying asset
+#[derive(Clone, Copy, Debug)]
+pub struct PortfolioLeg {
+    pub underlying: AssetId,
+    /// Contracts or units held, negative for short positions
+    pub volume: i64,
+    /// Current price of one contract or unit
+    pub price: u64,
+    pub kind: LegKind,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum LegKind {
+    /// Holdings of the underlying itself
+    Spot,
+    /// Futures and perpetual swaps, moving with the underlying price
+    Future,
+    /// Valued at its exercise value at the moved price of the underlying
+    Option {
+        spec: OptionSpec,
+        underlying_price: u64,
+    },
+}
+
+impl PortfolioLeg {
+    /// Change of the leg's value if the underlying moves to `factor` basis points of its price
+    fn change(&self, factor: u64) -> i64 {
+        let moved = match self.kind {
+            LegKind::Spot | LegKind::Future => self.price * factor / 10_000,
+            LegKind::Option {
+                spec,
+                underlying_price,
+            } => spec.intrinsic_value(underlying_price * factor / 10_000),
+        };
+        self.volume * (moved as i64 - self.price as i64)
+    }
+}
+
+impl ScenarioGrid {
+    /// Price factors of all scenarios in basis points
+    fn factors(&self) -> impl Iterator<Item = u64> {
+        let steps = std::cmp::max(self.steps, 1);
+        let price_move = std::cmp::min(self.price_move, 10_000);
+        (0..=steps).flat_map(move |step| {
+            let moved = price_move * step / steps;
+            [10_000 + moved, 10_000 - moved]
+        })
+    }
+
+    /// Worst loss over the grid, summed over the underlyings, plus the spread charge
+    pub fn requirement(&self, legs: &[PortfolioLeg]) -> u64 {
+        let mut underlyings: BTreeMap<AssetId, Vec<&PortfolioLeg>> = BTreeMap::new();
+        for leg in legs {
+            underlyings.entry(leg.underlying).or_default().push(leg);
+        }
+        let worst_loss: u64 = underlyings
+            .values()
+            .map(|legs| {
+                self.factors()
+                    .map(|factor| -legs.iter().map(|leg| leg.change(factor)).sum::<i64>())
+                    .max()
+                    .map_or(0, |loss| std::cmp::max(loss, 0) as u64)
+            })
+            .sum();
+        let gross: u64 = legs
+            .iter()
+            .map(|leg| leg.volume.unsigned_abs() * leg.price)
+            .sum();
+        worst_loss + (gross * self.spread_charge).div_ceil(10_000)
+    }
+}
@@ -1,5 +1,6 @@
 use crate::order_handling::order::OrderSide;
 
+/// Net position of a participant in one futures, perpetual swap or option symbol
 pub struct PositionRecord {
     pub symbol: usize,
     pub direction: PositionDirection,
@@ -7,14 +8,52 @@ pub struct PositionRecord {
 
     // Currency paid to open the position
     pub paid_value: u64,
-    pub profit: u64,
+    // Realized profit and loss of all closed positions
+    pub profit: i64,
+    // Margin held for the open position
+    pub margin: u64,
+    // Funding received, negative if more was paid than received
+    pub funding: i64,
+
+    pub mode: MarginMode,
+    // Collateral allocated to the position in isolated mode
+    pub collateral: u64,
 
     pub pending_buy_volume: u64,
     pub pending_sell_volume: u64,
+    /// Ids of the open reduce only orders, oldest first
+    pub reduce_only: Vec<u64>,
+}
+
+/// Result of applying a fill to a position
+pub struct PositionChange {
+    /// Volume that opened or extended the position
+    pub opened: u64,
+    /// Volume that reduced the position
+    pub closed: u64,
+    /// Profit or loss realized by the reduction
+    pub realized: i64,
 }
 
 impl PositionRecord {
-    pub fn pending_hold(mut self, side: OrderSide, volume: u64) {
+    pub fn new(symbol: usize) -> PositionRecord {
+        PositionRecord {
+            symbol,
+            direction: PositionDirection::Long,
+            volume: 0,
+            paid_value: 0,
+            profit: 0,
+            margin: 0,
+            funding: 0,
+            mode: MarginMode::Cross,
+            collateral: 0,
+            pending_buy_volume: 0,
+            pending_sell_volume: 0,
+            reduce_only: Vec::new(),
+        }
+    }
+
+    pub fn pending_hold(&mut self, side: OrderSide, volume: u64) {
         if side == OrderSide::ASK {
             self.pending_sell_volume += volume
         } else {
@@ -22,16 +61,99 @@ impl PositionRecord {
         }
     }
 
-    pub fn pending_release(mut self, side: OrderSide, volume: u64) {
+    pub fn pending_release(&mut self, side: OrderSide, volume: u64) {
         if side == OrderSide::ASK {
             self.pending_sell_volume -= volume
         } else {
             self.pending_buy_volume -= volume
         }
     }
+
+    /// Average price the open position was entered at
+    pub fn average_entry_price(&self) -> Option<u64> {
+        self.paid_value.checked_div(self.volume)
+    }
+
+    /// Profit or loss of the open position if it was closed at the mark price
+    pub fn unrealized_profit(&self, mark_price: u64) -> i64 {
+        let value = (self.volume * mark_price) as i64;
+        match self.direction {
+            PositionDirection::Long => value - self.paid_value as i64,
+            PositionDirection::Short => self.paid_value as i64 - value,
+        }
+    }
+
+    /// Part of an order that would open or extend the position.
+    ///
+    /// Pending orders on the same side are assumed to close the position first.
+    pub fn opening_volume(&self, side: OrderSide, volume: u64) -> u64 {
+        let closable = match (self.direction, side) {
+            (PositionDirection::Long, OrderSide::ASK) => {
+                self.volume.saturating_sub(self.pending_sell_volume)
+            }
+            (PositionDirection::Short, OrderSide::BID) => {
+                self.volume.saturating_sub(self.pending_buy_volume)
+            }
+            _ => 0,
+        };
+        volume.saturating_sub(closable)
+    }
+
+    /// Book a fill of `volume` contracts for a notional `value` into the position.
+    ///
+    /// Reductions realize profit against the average entry price,
+    /// a fill larger than the position flips its direction.
+    pub fn apply_fill(&mut self, side: OrderSide, volume: u64, value: u64) -> PositionChange {
+        let direction = match side {
+            OrderSide::BID => PositionDirection::Long,
+            OrderSide::ASK => PositionDirection::Short,
+        };
+        if self.volume == 0 || self.direction == direction {
+            self.direction = direction;
+            self.volume += volume;
+            self.paid_value += value;
+            return PositionChange {
+                opened: volume,
+                closed: 0,
+                realized: 0,
+            };
+        }
+
+        let closed = std::cmp::min(volume, self.volume);
+        let closing_value = value * closed / volume;
+        let entry_value = self.paid_value * closed / self.volume;
+        let realized = match self.direction {
+            PositionDirection::Long => closing_value as i64 - entry_value as i64,
+            PositionDirection::Short => entry_value as i64 - closing_value as i64,
+        };
+        self.volume -= closed;
+        self.paid_value -= entry_value;
+        self.profit += realized;
+
+        let opened = volume - closed;
+        if opened > 0 {
+            self.direction = direction;
+            self.volume = opened;
+            self.paid_value = value - closing_value;
+        }
+        PositionChange {
+            opened,
+            closed,
+            realized,
+        }
+    }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum PositionDirection {
     Long,
     Short,
 }
+
+/// What backs a position: all collateral of the participant in its settlement asset,
+/// or only the collateral allocated to the position itself
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MarginMode {
+    Cross,
+    Isolated,
+}
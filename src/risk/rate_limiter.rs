@@ -0,0 +1,321 @@
+use fxhash::FxHashMap;
+
+use crate::exchange::commands::OrderCommand;
+
+use super::risk_engine::RiskEngineResult;
+
+/// Limits of the requests a participant may send
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub struct RateLimits {
+    pub orders_per_second: u64,
+    /// Orders that can be sent at once after a quiet period
+    pub order_burst: u64,
+    pub cancels_per_second: u64,
+    pub cancel_burst: u64,
+    /// Highest number of orders per fill since the last day roll, 0 for no limit
+    pub max_order_to_trade_ratio: u64,
+    /// Orders a participant can send before the order-to-trade ratio applies
+    pub ratio_min_orders: u64,
+}
+
+/// Token bucket that refills continuously up to its capacity
+struct TokenBucket {
+    /// Billionths of a token
+    tokens: u128,
+    /// Nanoseconds since the Unix epoch
+    last: u64,
+}
+
+impl TokenBucket {
+    const UNIT: u128 = 1_000_000_000;
+
+    fn new(capacity: u64, now: u64) -> Self {
+        TokenBucket {
+            tokens: capacity as u128 * Self::UNIT,
+            last: now,
+        }
+    }
+
+    /// Take `count` tokens if there are enough of them
+    fn take(&mut self, count: u64, rate: u64, capacity: u64, now: u64) -> bool {
+        let elapsed = now.saturating_sub(self.last) as u128;
+        self.tokens = std::cmp::min(
+            self.tokens + elapsed * rate as u128,
+            capacity as u128 * Self::UNIT,
+        );
+        // A clock that goes back does not refill the bucket twice
+        self.last = std::cmp::max(self.last, now);
+        let needed = count as u128 * Self::UNIT;
+        if self.tokens < needed {
+            return false;
+        }
+        self.tokens -= needed;
+        true
+    }
+}
+
+/// Rate limits of one participant
+pub struct ParticipantLimits {
+    orders: TokenBucket,
+    cancels: TokenBucket,
+    /// Orders and fills since the last day roll
+    order_count: u64,
+    trade_count: u64,
+}
+
+/// Throttles the participants of a risk engine shard before their requests reach the order books
+pub struct RateLimiter {
+    limits: RateLimits,
+    participants: FxHashMap<u64, ParticipantLimits>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        RateLimiter {
+            limits,
+            participants: FxHashMap::default(),
+        }
+    }
+
+    /// Count a command against the limits of its participant at a time in nanoseconds
+    /// since the Unix epoch. Commands without orders, like those of the operator, are never limited.
+    pub fn check(&mut self, command: &OrderCommand, now: u64) -> RiskEngineResult {
+        let (participant_id, orders, cancels) = match command {
+            OrderCommand::Trade(trade) => (trade.participant_id, 1, 0),
+            OrderCommand::Quote(quote) => (quote.participant_id, 1, 0),
+            OrderCommand::Oco(oco) => (oco.first.participant_id, 1, 0),
+            OrderCommand::Bracket(bracket) => (bracket.entry.participant_id, 1, 0),
+            // A set larger than the burst could never pass
+            OrderCommand::MassQuote(mass_quote)
+                if mass_quote.quotes.len() as u64 > self.limits.order_burst =>
+            {
+                return RiskEngineResult::QuoteSetTooLarge
+            }
+            OrderCommand::MassQuote(mass_quote) => {
+                (mass_quote.participant_id, mass_quote.quotes.len() as u64, 0)
+            }
+            OrderCommand::Cancel(cancel) => (cancel.participant_id, 0, 1),
+            OrderCommand::Admin(_)
+            | OrderCommand::Query(_)
+            | OrderCommand::Margin(_)
+            | OrderCommand::Account(_)
+            | OrderCommand::Migration(_) => return RiskEngineResult::ValidForMatchingEngine,
+        };
+        let limits = self.limits;
+        let participant =
+            self.participants
+                .entry(participant_id)
+                .or_insert_with(|| ParticipantLimits {
+                    orders: TokenBucket::new(limits.order_burst, now),
+                    cancels: TokenBucket::new(limits.cancel_burst, now),
+                    order_count: 0,
+                    trade_count: 0,
+                });
+
+        if cancels > 0 {
+            if !participant.cancels.take(
+                cancels,
+                limits.cancels_per_second,
+                limits.cancel_burst,
+                now,
+            ) {
+                return RiskEngineResult::CancelRateExceeded;
+            }
+            return RiskEngineResult::ValidForMatchingEngine;
+        }
+
+        if limits.max_order_to_trade_ratio > 0
+            && participant.order_count >= limits.ratio_min_orders
+            && participant.order_count
+                >= limits.max_order_to_trade_ratio * std::cmp::max(participant.trade_count, 1)
+        {
+            return RiskEngineResult::OrderToTradeRatioExceeded;
+        }
+        if !participant
+            .orders
+            .take(orders, limits.orders_per_second, limits.order_burst, now)
+        {
+            return RiskEngineResult::OrderRateExceeded;
+        }
+        participant.order_count += orders;
+        RiskEngineResult::ValidForMatchingEngine
+    }
+
+    /// Count a fill of one of the participant's orders
+    pub fn record_trade(&mut self, participant_id: u64) {
+        if let Some(participant) = self.participants.get_mut(&participant_id) {
+            participant.trade_count += 1;
+        }
+    }
+
+    /// Take out the limits of a participant moving to another shard
+    pub fn take(&mut self, participant_id: u64) -> Option<ParticipantLimits> {
+        self.participants.remove(&participant_id)
+    }
+
+    /// Carry on with the limits of a participant that moved here
+    pub fn insert(&mut self, participant_id: u64, limits: ParticipantLimits) {
+        self.participants.insert(participant_id, limits);
+    }
+
+    /// Start a new period for the order-to-trade ratio
+    pub fn roll_day(&mut self) {
+        for participant in self.participants.values_mut() {
+            participant.order_count = 0;
+            participant.trade_count = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exchange::commands::{
+        CancelCommand, MassQuoteCommand, OrderType, QuoteCommand, TradeCommand,
+    };
+    use crate::order_handling::order::OrderSide;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(RateLimits {
+            orders_per_second: 2,
+            order_burst: 3,
+            cancels_per_second: 1,
+            cancel_burst: 1,
+            max_order_to_trade_ratio: 0,
+            ratio_min_orders: 0,
+        })
+    }
+
+    fn order() -> OrderCommand {
+        OrderCommand::Trade(TradeCommand {
+            id: 1,
+            participant_id: 1,
+            symbol: 0,
+            side: OrderSide::BID,
+            volume: 1,
+            limit: 1,
+            immediate_or_cancel: false,
+            order_type: OrderType::Limit,
+            hidden: false,
+            min_quantity: 0,
+            reduce_only: false,
+            close_position: false,
+        })
+    }
+
+    fn cancel() -> OrderCommand {
+        OrderCommand::Cancel(CancelCommand {
+            symbol: 0,
+            order_id: 1,
+            participant_id: 1,
+            remaining: 0,
+        })
+    }
+
+    #[test]
+    fn bucket_allows_a_burst_and_refills() {
+        let mut limiter = limiter();
+        for _ in 0..3 {
+            assert_eq!(
+                limiter.check(&order(), SECOND),
+                RiskEngineResult::ValidForMatchingEngine
+            );
+        }
+        assert_eq!(
+            limiter.check(&order(), SECOND),
+            RiskEngineResult::OrderRateExceeded
+        );
+        // Half a second refills one order
+        assert_eq!(
+            limiter.check(&order(), SECOND + SECOND / 2),
+            RiskEngineResult::ValidForMatchingEngine
+        );
+        assert_eq!(
+            limiter.check(&order(), SECOND + SECOND / 2),
+            RiskEngineResult::OrderRateExceeded
+        );
+        // Cancels have their own bucket
+        assert_eq!(
+            limiter.check(&cancel(), SECOND + SECOND / 2),
+            RiskEngineResult::ValidForMatchingEngine
+        );
+        assert_eq!(
+            limiter.check(&cancel(), SECOND + SECOND / 2),
+            RiskEngineResult::CancelRateExceeded
+        );
+    }
+
+    #[test]
+    fn mass_quote_larger_than_the_burst_is_refused_up_front() {
+        let mut limiter = limiter();
+        let quotes = |count: usize| {
+            OrderCommand::MassQuote(MassQuoteCommand {
+                id: 1,
+                participant_id: 1,
+                quotes: (0..count as u64)
+                    .map(|symbol| QuoteCommand {
+                        participant_id: 1,
+                        symbol,
+                        bid_id: 2 * symbol,
+                        bid_limit: 1,
+                        bid_volume: 1,
+                        ask_id: 2 * symbol + 1,
+                        ask_limit: 2,
+                        ask_volume: 1,
+                    })
+                    .collect(),
+                allow_partial: false,
+            })
+        };
+        assert_eq!(
+            limiter.check(&quotes(4), SECOND),
+            RiskEngineResult::QuoteSetTooLarge
+        );
+        // It took no tokens
+        assert_eq!(
+            limiter.check(&quotes(3), SECOND),
+            RiskEngineResult::ValidForMatchingEngine
+        );
+        assert_eq!(
+            limiter.check(&quotes(1), SECOND),
+            RiskEngineResult::OrderRateExceeded
+        );
+    }
+
+    #[test]
+    fn limits_move_with_the_participant() {
+        let mut from = limiter();
+        for _ in 0..3 {
+            from.check(&order(), SECOND);
+        }
+        let mut to = limiter();
+        to.insert(1, from.take(1).unwrap());
+        assert_eq!(
+            to.check(&order(), SECOND),
+            RiskEngineResult::OrderRateExceeded
+        );
+    }
+
+    #[test]
+    fn clock_going_back_does_not_refill() {
+        let mut limiter = limiter();
+        assert_eq!(
+            limiter.check(&cancel(), 10 * SECOND),
+            RiskEngineResult::ValidForMatchingEngine
+        );
+        assert_eq!(
+            limiter.check(&cancel(), 5 * SECOND),
+            RiskEngineResult::CancelRateExceeded
+        );
+        assert_eq!(
+            limiter.check(&cancel(), 10 * SECOND),
+            RiskEngineResult::CancelRateExceeded
+        );
+        assert_eq!(
+            limiter.check(&cancel(), 11 * SECOND),
+            RiskEngineResult::ValidForMatchingEngine
+        );
+    }
+}
@@ -1,86 +1,1138 @@
 use std::{
-    collections::HashMap,
+    collections::BTreeSet,
     sync::{Mutex, MutexGuard},
 };
 
+use fxhash::{FxHashMap, FxHashSet};
+
 use crate::{
     exchange::{
-        asset::{Symbol, SymbolType},
-        commands::{CancelCommand, OrderCommand, TradeCommand},
+        asset::{AssetId, FuturesSpec, OptionSpec, PerpetualSpec, Symbol, SymbolType},
+        commands::{
+            AccountCommand, AdminCommand, BracketCommand, CancelCommand, MarginCommand,
+            MassQuoteCommand, OcoCommand, OrderCommand, OrderType, QueryCommand, QuoteCommand,
+            TradeCommand,
+        },
         exchange::Exchange,
         exchange_settings::ExchangeSettings,
+        report::{LiquidationReport, Report},
+    },
+    order_handling::{
+        event::{Liquidity, MatchingEngineEvent},
+        order::OrderSide,
     },
-    order_handling::{event::MatchingEngineEvent, order::OrderSide},
 };
 
+use log::{debug, error, warn};
+
 use super::{
-    participant::{self, Participant},
+    exposure_limits::{ExposureAlert, ExposureKind, ExposureLimits},
+    fee_engine::{FeeEngine, FeeSchedule},
+    funding_engine::{FundingEngine, FundingRecord},
+    mark_prices::MarkPrices,
+    migration::{ParticipantState, ShardState},
+    order_limits::OrderLimitBook,
+    participant::{self, Balance, Participant},
+    portfolio_margin::{LegKind, PortfolioLeg},
+    position_record::{MarginMode, PositionDirection, PositionRecord},
     risk_order::RiskOrder,
 };
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum RiskEngineResult {
     ValidForMatchingEngine,
     InsufficientFunds,
+    /// Another quote of the same quote set failed and partial acceptance was not allowed
+    QuoteSetRejected,
+    /// The orders of a one-cancels-other pair or bracket do not fit together
+    InvalidOrderGroup,
+    /// The order type is not available for this kind of symbol
+    UnsupportedOrderType,
+    /// The option expired and is not traded anymore
+    SymbolExpired,
+    /// Trading in the symbol was halted
+    SymbolHalted,
+    /// The participant is being liquidated and can only cancel orders
+    Liquidating,
+    /// The margin mode can not be changed or the collateral not be moved like this
+    InvalidMarginCommand,
+    /// A participant with this id was already created
+    ParticipantExists,
+    /// The participant sent more orders than its rate limit allows
+    OrderRateExceeded,
+    /// The participant sent more cancels than its rate limit allows
+    CancelRateExceeded,
+    /// The participant sent too many orders for the fills they got since the last day roll
+    OrderToTradeRatioExceeded,
+    /// The mass quote has more quotes than the order rate limit allows at once
+    QuoteSetTooLarge,
+    /// The order volume is above the limit of the symbol or the participant
+    VolumeLimitExceeded,
+    /// Volume times limit price is above the limit of the symbol or the participant
+    NotionalLimitExceeded,
+    /// The limit price is too far from the mark or last traded price
+    PriceDeviationExceeded,
+    /// The net or gross position would pass the hard limit of the participant
+    PositionLimitExceeded,
+    /// The open orders would pass the hard limit of the participant
+    OpenOrderLimitExceeded,
+    /// A reduce only order has no position left to reduce
+    NothingToReduce,
+    /// The balance would pass the largest amount an asset can hold
+    BalanceOverflow,
 
     SymbolNotFound,
     UserNotFound,
     OrderNotFound,
 }
 
+/// Participant that takes over the positions the order books can not absorb during a liquidation
+/// and pays the losses participants can not pay.
+/// Every shard has its own insurance fund, funded by `AdminCommand::InsuranceDeposit`.
+/// Losses beyond the fund halt trading in their futures, perpetual swap or option symbol.
+pub const INSURANCE_FUND_ID: u64 = u64::MAX;
+
+/// Participant that collects the trading fees and pays the rebates.
+/// Every shard has its own fee account.
+pub const FEE_ACCOUNT_ID: u64 = u64::MAX - 1;
+
+/// Liquidation orders get ids from this one upwards, so they never collide with client order ids.
+/// The shard is in the upper half of the rest, so shards added later never reuse an id.
+const LIQUIDATION_ORDER_ID_BASE: u64 = 1 << 63;
+
 pub struct RiskEngine {
-    participants: HashMap<u64, Participant>,
+    /// Index of this shard
+    shard: u64,
+    participants: FxHashMap<u64, Participant>,
     settings: ExchangeSettings,
     // participant_id, symbol_id
-    orders: HashMap<u64, (u64, u64, RiskOrder)>,
+    orders: FxHashMap<u64, (u64, u64, RiskOrder)>,
+    /// Settlement prices of the expired options
+    expired: FxHashMap<u64, u64>,
+    /// Symbols that stopped trading
+    halted: FxHashSet<u64>,
+    /// Symbols this shard halted whose order books were not closed yet
+    halts: Vec<u64>,
+    funding: FundingEngine,
+    fees: FeeEngine,
+    mark_prices: MarkPrices,
+    /// Price of the last fill of each symbol seen by this shard
+    last_prices: FxHashMap<u64, u64>,
+    order_limits: OrderLimitBook,
+    exposure_limits: FxHashMap<u64, ExposureLimits>,
+    /// Volume times limit price of the open orders of each participant
+    open_exposure: FxHashMap<u64, u64>,
+    /// Soft limit breaches that were not reported yet
+    alerts: Vec<ExposureAlert>,
+    /// Orders to be canceled in their order books, reduce only orders the positions shrank below
+    /// and quote legs that could not take over their hold
+    cancels: Vec<CancelCommand>,
+
+    /// Participants whose margin changed since the last check, in id order
+    margin_checks: BTreeSet<u64>,
+    /// Participants and collateral pools whose positions are being liquidated
+    liquidating: FxHashSet<(u64, MarginAccount)>,
+    /// Open liquidation orders (participant_id, account, bankruptcy price)
+    liquidation_orders: FxHashMap<u64, (u64, MarginAccount, u64)>,
+    liquidation_count: u64,
+}
+
+/// Collateral pool that backs positions
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum MarginAccount {
+    /// All collateral in an asset, backing the cross margin positions that settle in it
+    Cross(AssetId),
+    /// Collateral allocated to the isolated position of a symbol
+    Isolated(u64),
+}
+
+impl MarginAccount {
+    pub fn of(symbol_id: u64, symbol: &Symbol, position: &PositionRecord) -> MarginAccount {
+        match position.mode {
+            MarginMode::Cross => MarginAccount::Cross(symbol.quote_asset),
+            MarginMode::Isolated => MarginAccount::Isolated(symbol_id),
+        }
+    }
+}
+
+/// Margin of the positions backed by one collateral pool, valued at mark prices
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MarginState {
+    /// Free collateral plus position margin plus unrealized profit and loss
+    pub equity: i64,
+    /// Margin the positions need to stay open
+    pub maintenance: u64,
+}
+
+impl MarginState {
+    /// Equity per maintenance margin in basis points, None without open positions that need margin
+    pub fn ratio(&self) -> Option<i64> {
+        if self.maintenance == 0 {
+            None
+        } else {
+            Some(self.equity * 10_000 / self.maintenance as i64)
+        }
+    }
+
+    pub fn is_below_maintenance(&self) -> bool {
+        self.equity < self.maintenance as i64
+    }
 }
 
 impl RiskEngine {
-    pub fn new(settings: ExchangeSettings) -> Self {
+    pub fn new(shard: u64, settings: ExchangeSettings) -> Self {
+        let mut participants = FxHashMap::default();
+        for id in [INSURANCE_FUND_ID, FEE_ACCOUNT_ID] {
+            participants.insert(
+                id,
+                Participant {
+                    id,
+                    ..Participant::default()
+                },
+            );
+        }
         Self {
-            participants: HashMap::new(),
+            shard,
+            participants,
             settings,
-            orders: HashMap::new(),
+            orders: FxHashMap::default(),
+            expired: FxHashMap::default(),
+            halted: FxHashSet::default(),
+            halts: Vec::new(),
+            funding: FundingEngine::default(),
+            fees: FeeEngine::default(),
+            mark_prices: MarkPrices::default(),
+            last_prices: FxHashMap::default(),
+            order_limits: OrderLimitBook::default(),
+            exposure_limits: FxHashMap::default(),
+            open_exposure: FxHashMap::default(),
+            alerts: Vec::new(),
+            cancels: Vec::new(),
+            margin_checks: BTreeSet::new(),
+            liquidating: FxHashSet::default(),
+            liquidation_orders: FxHashMap::default(),
+            liquidation_count: 0,
         }
     }
     pub fn add_participant(&mut self, part: Participant) {
         self.participants.insert(part.id, part);
     }
-    pub fn process_command(&mut self, command: OrderCommand) -> RiskEngineResult {
+
+    /// Price fat finger checks compare limit prices with, the mark price or the last fill
+    fn reference_price(
+        mark_prices: &MarkPrices,
+        last_prices: &FxHashMap<u64, u64>,
+        symbol: u64,
+    ) -> Option<u64> {
+        mark_prices
+            .get(symbol)
+            .or_else(|| last_prices.get(&symbol).copied())
+    }
+
+    /// Positions and open orders of a participant if the orders were accepted,
+    /// checked against their exposure limits. Returns the alerts of the soft limits passed.
+    fn check_exposure(
+        &self,
+        orders: &[TradeCommand],
+    ) -> Result<Vec<ExposureAlert>, RiskEngineResult> {
+        let participant_id = match orders.first() {
+            Some(order) => order.participant_id,
+            None => return Ok(Vec::new()),
+        };
+        let (limits, participant) = match (
+            self.exposure_limits.get(&participant_id),
+            self.participants.get(&participant_id),
+        ) {
+            (Some(limits), Some(participant)) => (limits, participant),
+            _ => return Ok(Vec::new()),
+        };
+
+        let mut exposures = Vec::new();
+        let symbols: BTreeSet<u64> = orders.iter().map(|order| order.symbol).collect();
+        for symbol_id in symbols {
+            match self.settings.symbols.get(symbol_id as usize) {
+                Some(symbol) if symbol.symbol_type != SymbolType::ExchangePair => (),
+                _ => continue,
+            }
+            let (mut buys, mut sells, position) =
+                match participant.positions.get(&(symbol_id as usize)) {
+                    Some(position) => (
+                        position.pending_buy_volume,
+                        position.pending_sell_volume,
+                        match position.direction {
+                            PositionDirection::Long => position.volume as i64,
+                            PositionDirection::Short => -(position.volume as i64),
+                        },
+                    ),
+                    None => (0, 0, 0),
+                };
+            for order in orders.iter().filter(|order| order.symbol == symbol_id) {
+                match order.side {
+                    OrderSide::BID => buys += order.volume,
+                    OrderSide::ASK => sells += order.volume,
+                }
+            }
+            let net = std::cmp::max(
+                (position + buys as i64).unsigned_abs(),
+                (position - sells as i64).unsigned_abs(),
+            );
+            let gross = position.unsigned_abs() + buys + sells;
+            exposures.push((Some(symbol_id), ExposureKind::NetPosition, net));
+            exposures.push((Some(symbol_id), ExposureKind::GrossPosition, gross));
+        }
+        // Open orders whose value does not fit are above every limit
+        let open = orders.iter().try_fold(
+            self.open_exposure
+                .get(&participant_id)
+                .copied()
+                .unwrap_or(0),
+            |open, order| open.checked_add(order.volume.checked_mul(order.limit)?),
+        );
+        exposures.push((None, ExposureKind::OpenOrders, open.unwrap_or(u64::MAX)));
+
+        let mut alerts = Vec::new();
+        for (symbol, kind, value) in exposures {
+            match limits.check(participant_id, symbol, kind, value) {
+                Ok(alert) => alerts.extend(alert),
+                Err(ExposureKind::OpenOrders) => {
+                    return Err(RiskEngineResult::OpenOrderLimitExceeded)
+                }
+                Err(_) => return Err(RiskEngineResult::PositionLimitExceeded),
+            }
+        }
+        Ok(alerts)
+    }
+
+    fn add_open_exposure(&mut self, orders: &[TradeCommand]) {
+        for order in orders {
+            let open = self.open_exposure.entry(order.participant_id).or_insert(0);
+            *open = open.saturating_add(order.volume.saturating_mul(order.limit));
+        }
+    }
+
+    /// Orders of a command that count in the open exposure.
+    /// Only one leg of a one-cancels-other pair can execute, so only the larger one counts.
+    fn exposed_orders(command: &OrderCommand) -> Vec<TradeCommand> {
+        let larger = |first: TradeCommand, second: TradeCommand| {
+            let notional = |order: &TradeCommand| order.volume.saturating_mul(order.limit);
+            if notional(&second) > notional(&first) {
+                second
+            } else {
+                first
+            }
+        };
+        match command {
+            OrderCommand::Trade(trade) => vec![*trade],
+            OrderCommand::Quote(quote) => quote.legs().collect(),
+            OrderCommand::Oco(oco) => vec![larger(oco.first, oco.second)],
+            OrderCommand::Bracket(bracket) => vec![
+                bracket.entry,
+                larger(bracket.exit.first, bracket.exit.second),
+            ],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Take the volume of a filled or canceled order out of the open exposure
+    fn reduce_open_exposure(&mut self, id: u64, volume: u64) {
+        if let Some((participant_id, _, order)) = self.orders.get(&id) {
+            if !order.exposed {
+                return;
+            }
+            if let Some(open) = self.open_exposure.get_mut(participant_id) {
+                *open = open.saturating_sub(volume.saturating_mul(order.limit));
+            }
+        }
+    }
+
+    /// The leg of a one-cancels-other pair that stays in the book on its own
+    /// counts in the open exposure instead of the leg that left it
+    fn pass_on_exposure(&mut self, from: u64, to: u64) {
+        let from = match self.orders.get_mut(&from) {
+            Some((participant_id, _, order)) if order.exposed => {
+                order.exposed = false;
+                (*participant_id, order.volume.saturating_mul(order.limit))
+            }
+            _ => return,
+        };
+        let to = match self.orders.get_mut(&to) {
+            Some((_, _, order)) if !order.exposed => {
+                order.exposed = true;
+                order.volume.saturating_mul(order.limit)
+            }
+            _ => 0,
+        };
+        if let Some(open) = self.open_exposure.get_mut(&from.0) {
+            *open = open.saturating_sub(from.1).saturating_add(to);
+        }
+    }
+
+    /// Soft limit breaches since the last call
+    pub fn take_alerts(&mut self) -> Vec<ExposureAlert> {
+        std::mem::take(&mut self.alerts)
+    }
+
+    /// Participant an open order belongs to
+    pub fn order_participant(&self, id: u64) -> Option<u64> {
+        self.orders
+            .get(&id)
+            .map(|(participant_id, _, _)| *participant_id)
+    }
+
+    /// Remove a participant with its open orders to move it to another shard
+    pub fn export_participant(&mut self, participant_id: u64) -> Option<ParticipantState> {
+        let participant = self.participants.remove(&participant_id)?;
+        let mut ids: Vec<u64> = self
+            .orders
+            .iter()
+            .filter(|(_, (owner, _, _))| *owner == participant_id)
+            .map(|(id, _)| *id)
+            .collect();
+        ids.sort_unstable();
+        let orders = ids
+            .into_iter()
+            .map(|id| {
+                let (_, symbol, order) = self.orders.remove(&id).unwrap();
+                (id, symbol, order)
+            })
+            .collect();
+        let mut liquidation_orders: Vec<(u64, MarginAccount, u64)> = self
+            .liquidation_orders
+            .iter()
+            .filter(|(_, (owner, _, _))| *owner == participant_id)
+            .map(|(id, (_, account, price))| (*id, *account, *price))
+            .collect();
+        liquidation_orders.sort_unstable();
+        for (id, _, _) in &liquidation_orders {
+            self.liquidation_orders.remove(id);
+        }
+        let mut liquidating: Vec<MarginAccount> = self
+            .liquidating
+            .iter()
+            .filter(|(owner, _)| *owner == participant_id)
+            .map(|(_, account)| *account)
+            .collect();
+        liquidating.sort_unstable();
+        for account in &liquidating {
+            self.liquidating.remove(&(participant_id, *account));
+        }
+        self.margin_checks.remove(&participant_id);
+        Some(ParticipantState {
+            participant,
+            orders,
+            open_exposure: self.open_exposure.remove(&participant_id).unwrap_or(0),
+            volumes: self.fees.take_volumes(participant_id),
+            liquidating,
+            liquidation_orders,
+            rate_limits: None,
+        })
+    }
+
+    /// Add a participant with its open orders that moved here from another shard
+    pub fn import_participant(&mut self, state: ParticipantState) {
+        let participant_id = state.participant.id;
+        for (id, symbol, order) in state.orders {
+            self.orders.insert(id, (participant_id, symbol, order));
+        }
+        for (id, account, price) in state.liquidation_orders {
+            self.liquidation_orders
+                .insert(id, (participant_id, account, price));
+        }
+        for account in state.liquidating {
+            self.liquidating.insert((participant_id, account));
+        }
+        if state.open_exposure > 0 {
+            self.open_exposure
+                .insert(participant_id, state.open_exposure);
+        }
+        self.fees.insert_volumes(participant_id, state.volumes);
+        self.participants.insert(participant_id, state.participant);
+        // The mark prices of this shard may have moved on already
+        self.margin_checks.insert(participant_id);
+    }
+
+    /// Copy what this shard keeps apart from its participants for a new shard
+    /// and give it an equal share of the insurance fund and the fee account,
+    /// `shards` counting the new one
+    pub fn export_shard(&mut self, shards: u64) -> ShardState {
+        let mut expired: Vec<(u64, u64)> = self
+            .expired
+            .iter()
+            .map(|(symbol, price)| (*symbol, *price))
+            .collect();
+        expired.sort_unstable();
+        let mut halted: Vec<u64> = self.halted.iter().copied().collect();
+        halted.sort_unstable();
+        let mut exposure_limits: Vec<(u64, ExposureLimits)> = self
+            .exposure_limits
+            .iter()
+            .map(|(participant_id, limits)| (*participant_id, *limits))
+            .collect();
+        exposure_limits.sort_unstable_by_key(|(participant_id, _)| *participant_id);
+        ShardState {
+            expired,
+            halted,
+            funding: self.funding.clone(),
+            mark_prices: self.mark_prices.clone(),
+            order_limits: self.order_limits.clone(),
+            exposure_limits,
+            insurance: self.give_share(INSURANCE_FUND_ID, shards),
+            fees: self.give_share(FEE_ACCOUNT_ID, shards),
+        }
+    }
+
+    /// Take `1/shards` of every balance of an account of the shard, in asset order
+    fn give_share(&mut self, participant_id: u64, shards: u64) -> Vec<(AssetId, u64)> {
+        let account = self.participants.get_mut(&participant_id).unwrap();
+        let mut shares: Vec<(AssetId, u64)> = account
+            .assets
+            .iter_mut()
+            .map(|(asset, balance)| {
+                let share = *balance / shards;
+                *balance -= share;
+                (*asset, share)
+            })
+            .filter(|(_, share)| *share > 0)
+            .collect();
+        shares.sort_unstable();
+        shares
+    }
+
+    /// Start out with the state an older shard sent. Every older shard sends the same
+    /// operator state, the shares of the insurance fund and the fee account add up.
+    pub fn import_shard(&mut self, state: ShardState) {
+        self.expired.extend(state.expired);
+        self.halted.extend(state.halted);
+        self.funding = state.funding;
+        self.mark_prices = state.mark_prices;
+        self.order_limits = state.order_limits;
+        self.exposure_limits.extend(state.exposure_limits);
+        for (participant_id, shares) in [
+            (INSURANCE_FUND_ID, state.insurance),
+            (FEE_ACCOUNT_ID, state.fees),
+        ] {
+            let account = self.participants.get_mut(&participant_id).unwrap();
+            for (asset, share) in shares {
+                let balance = account.assets.entry(asset).or_insert(0);
+                *balance = balance.saturating_add(share);
+            }
+        }
+    }
+    /// Check a command and hold what it needs.
+    /// Reduce only orders are capped at the position, so the command may be changed.
+    pub fn process_command(&mut self, command: &mut OrderCommand) -> RiskEngineResult {
+        if let Some(participant_id) = command.participant_id() {
+            if self.is_liquidating(participant_id) && !matches!(command, OrderCommand::Cancel(_)) {
+                return RiskEngineResult::Liquidating;
+            }
+        }
+        let result = match command {
+            OrderCommand::Trade(trade) if trade.is_reduce_only() => self.cap_reduce_only(trade),
+            OrderCommand::Oco(oco) if oco.first.is_reduce_only() || oco.second.is_reduce_only() => {
+                RiskEngineResult::UnsupportedOrderType
+            }
+            OrderCommand::Bracket(bracket)
+                if bracket.entry.is_reduce_only()
+                    || bracket.exit.first.is_reduce_only()
+                    || bracket.exit.second.is_reduce_only() =>
+            {
+                RiskEngineResult::UnsupportedOrderType
+            }
+            _ => RiskEngineResult::ValidForMatchingEngine,
+        };
+        if result != RiskEngineResult::ValidForMatchingEngine {
+            return result;
+        }
+        let command = &*command;
+        let orders: Vec<TradeCommand> = match command {
+            OrderCommand::Trade(trade) => vec![*trade],
+            OrderCommand::Quote(quote) => quote.legs().collect(),
+            OrderCommand::Oco(oco) => vec![oco.first, oco.second],
+            OrderCommand::Bracket(bracket) => {
+                vec![bracket.entry, bracket.exit.first, bracket.exit.second]
+            }
+            _ => Vec::new(),
+        };
+        if orders
+            .iter()
+            .any(|order| self.halted.contains(&order.symbol))
+        {
+            return RiskEngineResult::SymbolHalted;
+        }
+        let exposed = Self::exposed_orders(command);
+        // Stops and bracket exits trade later, at a price that is not known yet
+        let deferred: Vec<u64> = match command {
+            OrderCommand::Bracket(bracket) => vec![bracket.exit.first.id, bracket.exit.second.id],
+            _ => Vec::new(),
+        };
+        for order in &orders {
+            let reference_price = if order.order_type.is_stop() || deferred.contains(&order.id) {
+                None
+            } else {
+                Self::reference_price(&self.mark_prices, &self.last_prices, order.symbol)
+            };
+            let result = self.order_limits.check(order, reference_price);
+            if result != RiskEngineResult::ValidForMatchingEngine {
+                return result;
+            }
+        }
+        let alerts = match self.check_exposure(&exposed) {
+            Ok(alerts) => alerts,
+            Err(result) => return result,
+        };
+        let result = self.place_command(command);
+        if result == RiskEngineResult::ValidForMatchingEngine {
+            self.add_open_exposure(&exposed);
+            for order in orders
+                .iter()
+                .filter(|order| exposed.iter().all(|exposed| exposed.id != order.id))
+            {
+                if let Some((_, _, order)) = self.orders.get_mut(&order.id) {
+                    order.exposed = false;
+                }
+            }
+            self.alerts.extend(alerts);
+            if let OrderCommand::Trade(trade) = command {
+                if trade.reduce_only {
+                    let participant = self.participants.get_mut(&trade.participant_id).unwrap();
+                    let position = participant
+                        .positions
+                        .get_mut(&(trade.symbol as usize))
+                        .unwrap();
+                    position.reduce_only.push(trade.id);
+                }
+            }
+        }
+        result
+    }
+
+    /// Cap a reduce only order at the part of the position that other reduce only orders
+    /// do not close yet, a close position order takes all of it
+    fn cap_reduce_only(&self, trade: &mut TradeCommand) -> RiskEngineResult {
+        match self.settings.symbols.get(trade.symbol as usize) {
+            Some(symbol) if symbol.symbol_type == SymbolType::ExchangePair => {
+                return RiskEngineResult::UnsupportedOrderType
+            }
+            Some(_) => (),
+            None => return RiskEngineResult::SymbolNotFound,
+        }
+        let position = match self.participants.get(&trade.participant_id) {
+            Some(participant) => participant.positions.get(&(trade.symbol as usize)),
+            None => return RiskEngineResult::UserNotFound,
+        };
+        let reducible = position.map_or(0, |position| {
+            let reducing: u64 = Self::reduce_only_orders(&self.orders, position, trade.side)
+                .map(|(_, volume)| volume)
+                .sum();
+            Self::closable_volume(position, trade.side).saturating_sub(reducing)
+        });
+        if reducible == 0 {
+            return RiskEngineResult::NothingToReduce;
+        }
+        trade.volume = if trade.close_position {
+            reducible
+        } else {
+            std::cmp::min(trade.volume, reducible)
+        };
+        trade.reduce_only = true;
+        RiskEngineResult::ValidForMatchingEngine
+    }
+
+    /// Volume of the position an order on this side closes
+    fn closable_volume(position: &PositionRecord, side: OrderSide) -> u64 {
+        match (position.direction, side) {
+            (PositionDirection::Long, OrderSide::ASK)
+            | (PositionDirection::Short, OrderSide::BID) => position.volume,
+            _ => 0,
+        }
+    }
+
+    /// Open reduce only orders of a position on one side (id, volume), oldest first
+    fn reduce_only_orders<'a>(
+        orders: &'a FxHashMap<u64, (u64, u64, RiskOrder)>,
+        position: &'a PositionRecord,
+        side: OrderSide,
+    ) -> impl DoubleEndedIterator<Item = (u64, u64)> + 'a {
+        position
+            .reduce_only
+            .iter()
+            .filter_map(move |id| orders.get(id))
+            .filter(move |(_, _, order)| order.side == side)
+            .map(|(_, _, order)| (order.id, order.volume))
+    }
+
+    /// Reduce the newest reduce only orders of a position until the rest does not exceed it.
+    /// Orders keep counting until the book confirms the change, so a fill on its way
+    /// still counts as reducing the position.
+    fn check_reduce_only(&mut self, participant_id: u64, symbol_id: u64) {
+        let position = match self
+            .participants
+            .get(&participant_id)
+            .and_then(|participant| participant.positions.get(&(symbol_id as usize)))
+        {
+            Some(position) if !position.reduce_only.is_empty() => position,
+            _ => return,
+        };
+        for side in [OrderSide::BID, OrderSide::ASK] {
+            let closable = Self::closable_volume(position, side);
+            let mut resting: u64 = Self::reduce_only_orders(&self.orders, position, side)
+                .map(|(_, volume)| volume)
+                .sum();
+            for (id, volume) in Self::reduce_only_orders(&self.orders, position, side).rev() {
+                if resting <= closable {
+                    break;
+                }
+                let excess = std::cmp::min(resting - closable, volume);
+                resting -= excess;
+                self.cancels.push(CancelCommand {
+                    symbol: symbol_id,
+                    order_id: id,
+                    participant_id,
+                    remaining: volume - excess,
+                });
+            }
+        }
+    }
+
+    /// Cancels the risk engine started since the last call
+    pub fn take_cancels(&mut self) -> Vec<CancelCommand> {
+        std::mem::take(&mut self.cancels)
+    }
+
+    /// Symbols this shard halted since the last call, their order books have to be closed
+    pub fn take_halts(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.halts)
+    }
+
+    fn place_command(&mut self, command: &OrderCommand) -> RiskEngineResult {
         match command {
-            OrderCommand::Trade(command) => {
-                let symbol = &self.settings.symbols[command.symbol as usize];
+            OrderCommand::Trade(command) => match self.place_trade(command) {
+                // Retry with the margin the portfolio offsets free up
+                RiskEngineResult::InsufficientFunds => {
+                    let relief = match self.release_portfolio_margin(command) {
+                        Some(relief) => relief,
+                        None => return RiskEngineResult::InsufficientFunds,
+                    };
+                    let result = self.place_trade(command);
+                    match self.orders.get_mut(&command.id) {
+                        Some((_, _, order))
+                            if result == RiskEngineResult::ValidForMatchingEngine =>
+                        {
+                            order.relief = relief
+                        }
+                        _ => self.restore_portfolio_margin(command.participant_id, relief),
+                    }
+                    result
+                }
+                result => result,
+            },
+            OrderCommand::Cancel(command) => {
+                let symbol = match self.settings.symbols.get(command.symbol as usize) {
+                    Some(symbol) => symbol,
+                    None => return RiskEngineResult::SymbolNotFound,
+                };
                 let user = self.participants.get_mut(&command.participant_id);
                 match user {
                     Some(user) => match symbol.symbol_type {
-                        SymbolType::ExchangePair => {
-                            Self::place_exchange_order(symbol, user, command, &mut self.orders)
-                        }
-                        SymbolType::FuturesContract => todo!(),
-                        SymbolType::Option => todo!(),
+                        SymbolType::ExchangePair
+                        | SymbolType::FuturesContract(_)
+                        | SymbolType::PerpetualSwap(_)
+                        | SymbolType::Option(_) => RiskEngineResult::ValidForMatchingEngine,
                     },
                     None => RiskEngineResult::UserNotFound,
                 }
             }
-            OrderCommand::Cancel(command) => {
-                let symbol = &self.settings.symbols[command.symbol as usize];
+            OrderCommand::Quote(command) => {
+                let symbol = match self.settings.symbols.get(command.symbol as usize) {
+                    Some(symbol) => symbol,
+                    None => return RiskEngineResult::SymbolNotFound,
+                };
                 let user = self.participants.get_mut(&command.participant_id);
                 match user {
                     Some(user) => match symbol.symbol_type {
-                        SymbolType::ExchangePair => RiskEngineResult::ValidForMatchingEngine,
-                        SymbolType::FuturesContract => todo!(),
-                        SymbolType::Option => todo!(),
+                        SymbolType::ExchangePair => Self::place_exchange_quote(
+                            symbol,
+                            user,
+                            *command,
+                            &mut self.orders,
+                            Self::fee_rate(&self.settings, command.symbol),
+                        ),
+                        SymbolType::FuturesContract(spec)
+                        | SymbolType::PerpetualSwap(PerpetualSpec { margin: spec, .. }) => {
+                            Self::place_margined_quote(
+                                symbol,
+                                user,
+                                *command,
+                                &mut self.orders,
+                                Self::futures_hold(
+                                    spec,
+                                    self.mark_prices.get(command.symbol),
+                                    Self::fee_rate(&self.settings, command.symbol),
+                                ),
+                            )
+                        }
+                        SymbolType::Option(_) if self.expired.contains_key(&command.symbol) => {
+                            RiskEngineResult::SymbolExpired
+                        }
+                        SymbolType::Option(spec) => Self::place_margined_quote(
+                            symbol,
+                            user,
+                            *command,
+                            &mut self.orders,
+                            Self::option_hold(
+                                spec,
+                                Self::underlying_price(&self.mark_prices, &spec),
+                                Self::fee_rate(&self.settings, command.symbol),
+                            ),
+                        ),
+                    },
+                    None => RiskEngineResult::UserNotFound,
+                }
+            }
+            OrderCommand::Oco(command) => {
+                if !command.is_valid() {
+                    return RiskEngineResult::InvalidOrderGroup;
+                }
+                let symbol = match self.settings.symbols.get(command.first.symbol as usize) {
+                    Some(symbol) => symbol,
+                    None => return RiskEngineResult::SymbolNotFound,
+                };
+                let user = self.participants.get_mut(&command.first.participant_id);
+                match user {
+                    Some(user) => match symbol.symbol_type {
+                        SymbolType::ExchangePair => Self::place_exchange_oco(
+                            symbol,
+                            user,
+                            *command,
+                            0,
+                            &mut self.orders,
+                            Self::fee_rate(&self.settings, command.first.symbol),
+                        ),
+                        SymbolType::FuturesContract(_)
+                        | SymbolType::PerpetualSwap(_)
+                        | SymbolType::Option(_) => RiskEngineResult::UnsupportedOrderType,
+                    },
+                    None => RiskEngineResult::UserNotFound,
+                }
+            }
+            OrderCommand::Bracket(command) => {
+                if !command.is_valid() {
+                    return RiskEngineResult::InvalidOrderGroup;
+                }
+                let symbol = match self.settings.symbols.get(command.entry.symbol as usize) {
+                    Some(symbol) => symbol,
+                    None => return RiskEngineResult::SymbolNotFound,
+                };
+                let user = self.participants.get_mut(&command.entry.participant_id);
+                match user {
+                    Some(user) => match symbol.symbol_type {
+                        SymbolType::ExchangePair => Self::place_exchange_bracket(
+                            symbol,
+                            user,
+                            *command,
+                            &mut self.orders,
+                            Self::fee_rate(&self.settings, command.entry.symbol),
+                        ),
+                        SymbolType::FuturesContract(_)
+                        | SymbolType::PerpetualSwap(_)
+                        | SymbolType::Option(_) => RiskEngineResult::UnsupportedOrderType,
                     },
                     None => RiskEngineResult::UserNotFound,
                 }
             }
+            OrderCommand::MassQuote(_) => {
+                unreachable!("Mass quotes are checked per symbol by process_mass_quote")
+            }
+            OrderCommand::Admin(_) => {
+                unreachable!("Admin commands are applied by process_admin")
+            }
+            OrderCommand::Query(_) => {
+                unreachable!("Queries are answered by process_query")
+            }
+            OrderCommand::Margin(_) => {
+                unreachable!("Margin commands are applied by process_margin")
+            }
+            OrderCommand::Account(_) => {
+                unreachable!("Account commands are applied by process_account")
+            }
+            OrderCommand::Migration(_) => {
+                unreachable!("Migrations are coordinated by the risk engine processor")
+            }
+        }
+    }
+
+    fn place_trade(&mut self, command: &TradeCommand) -> RiskEngineResult {
+        let symbol = match self.settings.symbols.get(command.symbol as usize) {
+            Some(symbol) => symbol,
+            None => return RiskEngineResult::SymbolNotFound,
+        };
+        let user = self.participants.get_mut(&command.participant_id);
+        match user {
+            Some(user) => match symbol.symbol_type {
+                SymbolType::ExchangePair => Self::place_exchange_order(
+                    symbol,
+                    user,
+                    *command,
+                    &mut self.orders,
+                    Self::fee_rate(&self.settings, command.symbol),
+                ),
+                SymbolType::FuturesContract(spec)
+                | SymbolType::PerpetualSwap(PerpetualSpec { margin: spec, .. }) => {
+                    Self::place_margined_order(
+                        symbol,
+                        user,
+                        *command,
+                        &mut self.orders,
+                        Self::futures_hold(
+                            spec,
+                            self.mark_prices.get(command.symbol),
+                            Self::fee_rate(&self.settings, command.symbol),
+                        ),
+                    )
+                }
+                SymbolType::Option(_) if self.expired.contains_key(&command.symbol) => {
+                    RiskEngineResult::SymbolExpired
+                }
+                SymbolType::Option(spec) => Self::place_margined_order(
+                    symbol,
+                    user,
+                    *command,
+                    &mut self.orders,
+                    Self::option_hold(
+                        spec,
+                        Self::underlying_price(&self.mark_prices, &spec),
+                        Self::fee_rate(&self.settings, command.symbol),
+                    ),
+                ),
+            },
+            None => RiskEngineResult::UserNotFound,
+        }
+    }
+
+    /// Check a whole quote set and hold the funds for every accepted quote.
+    ///
+    /// Quotes are checked in the order they were sent.
+    /// Unless partial acceptance is allowed, a single failing quote rejects the whole set.
+    /// Returns the result for each quote in the same order.
+    pub fn process_mass_quote(&mut self, command: &MassQuoteCommand) -> Vec<RiskEngineResult> {
+        if self.is_liquidating(command.participant_id) {
+            return vec![RiskEngineResult::Liquidating; command.quotes.len()];
+        }
+        // Exposure is checked for the whole quote set at once
+        let legs: Vec<TradeCommand> = command.quotes.iter().flat_map(QuoteCommand::legs).collect();
+        let alerts = match self.check_exposure(&legs) {
+            Ok(alerts) => alerts,
+            Err(result) => return vec![result; command.quotes.len()],
+        };
+        let user = match self.participants.get_mut(&command.participant_id) {
+            Some(user) => user,
+            None => return vec![RiskEngineResult::UserNotFound; command.quotes.len()],
+        };
+
+        // Funds that are still free after the quotes accepted so far
+        let mut free: FxHashMap<MarginAccount, u64> = FxHashMap::default();
+        let mut replaced: FxHashSet<u64> = FxHashSet::default();
+        let mut results = Vec::with_capacity(command.quotes.len());
+        let order_limits = &self.order_limits;
+        let orders = &self.orders;
+        for quote in &command.quotes {
+            let symbol = match self.settings.symbols.get(quote.symbol as usize) {
+                Some(symbol) => symbol,
+                None => {
+                    results.push(RiskEngineResult::SymbolNotFound);
+                    continue;
+                }
+            };
+            if quote.participant_id != command.participant_id {
+                results.push(RiskEngineResult::UserNotFound);
+                continue;
+            }
+            if self.expired.contains_key(&quote.symbol) {
+                results.push(RiskEngineResult::SymbolExpired);
+                continue;
+            }
+            if self.halted.contains(&quote.symbol) {
+                results.push(RiskEngineResult::SymbolHalted);
+                continue;
+            }
+            let reference_price =
+                Self::reference_price(&self.mark_prices, &self.last_prices, quote.symbol);
+            let limited = quote
+                .legs()
+                .map(|leg| order_limits.check(&leg, reference_price))
+                .find(|result| *result != RiskEngineResult::ValidForMatchingEngine);
+            if let Some(result) = limited {
+                results.push(result);
+                continue;
+            }
+            // Positions the quote would close are not credited
+            let flat = PositionRecord::new(quote.symbol as usize);
+            let legs = match symbol.symbol_type {
+                SymbolType::ExchangePair => match Self::exchange_quote_legs(
+                    symbol,
+                    quote,
+                    Self::fee_rate(&self.settings, quote.symbol),
+                ) {
+                    Some(legs) => legs,
+                    None => {
+                        results.push(RiskEngineResult::BalanceOverflow);
+                        continue;
+                    }
+                },
+                SymbolType::FuturesContract(spec)
+                | SymbolType::PerpetualSwap(PerpetualSpec { margin: spec, .. }) => {
+                    Self::margined_quote_legs(
+                        symbol,
+                        quote,
+                        &flat,
+                        Self::futures_hold(
+                            spec,
+                            self.mark_prices.get(quote.symbol),
+                            Self::fee_rate(&self.settings, quote.symbol),
+                        ),
+                    )
+                }
+                SymbolType::Option(spec) => Self::margined_quote_legs(
+                    symbol,
+                    quote,
+                    &flat,
+                    Self::option_hold(
+                        spec,
+                        Self::underlying_price(&self.mark_prices, &spec),
+                        Self::fee_rate(&self.settings, quote.symbol),
+                    ),
+                ),
+            };
+            // Only the first quote of the set on a symbol takes over the holds of the last one
+            let replaces = replaced.insert(quote.symbol);
+            let required = Self::quote_requirement(legs.iter().map(|(leg, asset, hold)| {
+                let inherited = if replaces {
+                    Self::previous_quote_hold(user, orders, leg)
+                } else {
+                    0
+                };
+                (*asset, hold.saturating_sub(inherited))
+            }));
+
+            // Isolated positions are backed by their own collateral
+            let position = user.positions.get(&(quote.symbol as usize));
+            let account = |asset: usize| match position {
+                Some(position) => MarginAccount::of(quote.symbol, symbol, position),
+                None => MarginAccount::Cross(asset),
+            };
+            let sufficient = required.iter().all(|(asset, value)| {
+                let free = *free
+                    .entry(account(*asset))
+                    .or_insert_with(|| match position {
+                        Some(position) if position.mode == MarginMode::Isolated => {
+                            position.collateral
+                        }
+                        _ => user.assets.get(asset).copied().unwrap_or(0),
+                    });
+                free >= *value
+            });
+            if sufficient {
+                for (asset, value) in required {
+                    *free.get_mut(&account(asset)).unwrap() -= value;
+                }
+                results.push(RiskEngineResult::ValidForMatchingEngine);
+            } else {
+                results.push(RiskEngineResult::InsufficientFunds);
+            }
+        }
+
+        let all_valid = results
+            .iter()
+            .all(|result| *result == RiskEngineResult::ValidForMatchingEngine);
+        if !all_valid && !command.allow_partial {
+            for result in results.iter_mut() {
+                if *result == RiskEngineResult::ValidForMatchingEngine {
+                    *result = RiskEngineResult::QuoteSetRejected;
+                }
+            }
+            return results;
+        }
+
+        // Quotes placed so far with the quotes they replaced, undone if a later one fails
+        let mut placed: Vec<(&QuoteCommand, Option<(u64, u64)>)> = Vec::new();
+        let mut failed = None;
+        for (index, (quote, result)) in command.quotes.iter().zip(&results).enumerate() {
+            if *result == RiskEngineResult::ValidForMatchingEngine {
+                let symbol = &self.settings.symbols[quote.symbol as usize];
+                let previous = user.quotes.get(&quote.symbol).copied();
+                let result = match symbol.symbol_type {
+                    SymbolType::ExchangePair => Self::place_exchange_quote(
+                        symbol,
+                        user,
+                        *quote,
+                        &mut self.orders,
+                        Self::fee_rate(&self.settings, quote.symbol),
+                    ),
+                    SymbolType::FuturesContract(spec)
+                    | SymbolType::PerpetualSwap(PerpetualSpec { margin: spec, .. }) => {
+                        Self::place_margined_quote(
+                            symbol,
+                            user,
+                            *quote,
+                            &mut self.orders,
+                            Self::futures_hold(
+                                spec,
+                                self.mark_prices.get(quote.symbol),
+                                Self::fee_rate(&self.settings, quote.symbol),
+                            ),
+                        )
+                    }
+                    SymbolType::Option(spec) => Self::place_margined_quote(
+                        symbol,
+                        user,
+                        *quote,
+                        &mut self.orders,
+                        Self::option_hold(
+                            spec,
+                            Self::underlying_price(&self.mark_prices, &spec),
+                            Self::fee_rate(&self.settings, quote.symbol),
+                        ),
+                    ),
+                };
+                if result != RiskEngineResult::ValidForMatchingEngine {
+                    failed = Some((index, result));
+                    break;
+                }
+                placed.push((quote, previous));
+            }
+        }
+        if let Some((index, result)) = failed {
+            for (quote, previous) in placed.into_iter().rev() {
+                let symbol = &self.settings.symbols[quote.symbol as usize];
+                Self::unplace_quote(symbol, user, quote, previous, &mut self.orders);
+            }
+            for result in results.iter_mut() {
+                if *result == RiskEngineResult::ValidForMatchingEngine {
+                    *result = RiskEngineResult::QuoteSetRejected;
+                }
+            }
+            results[index] = result;
+            return results;
+        }
+        let accepted: Vec<TradeCommand> = command
+            .quotes
+            .iter()
+            .zip(&results)
+            .filter(|(_, result)| **result == RiskEngineResult::ValidForMatchingEngine)
+            .flat_map(|(quote, _)| quote.legs())
+            .collect();
+        if !accepted.is_empty() {
+            self.add_open_exposure(&accepted);
+            self.alerts.extend(alerts);
         }
+        results
     }
 
     // fn cancel_exchange_order(
     //     symbol: &Symbol,
     //     user: &mut Participant,
     //     command: CancelCommand,
-    //     orders: &mut HashMap<u64, (u64, u64, RiskOrder)>,
+    //     orders: &mut FxHashMap<u64, (u64, u64, RiskOrder)>,
     // ) -> RiskEngineResult {
     //     match orders.remove(&command.order_id) {
     //         Some((_, s_ymbol, order)) => {
@@ -108,98 +1160,2451 @@ impl RiskEngine {
         symbol: &Symbol,
         user: &mut Participant,
         trade_command: TradeCommand,
-        orders: &mut HashMap<u64, (u64, u64, RiskOrder)>,
+        orders: &mut FxHashMap<u64, (u64, u64, RiskOrder)>,
+        fee_rate: u64,
     ) -> RiskEngineResult {
-        let (pessimistic_asset, pessimistic_value) = trade_command.pessimistic(symbol);
-
-        match user.assets.get(&pessimistic_asset) {
-            Some(user_asset) => {
-                if user_asset >= &pessimistic_value {
-                    //Pessimistically reduce the assets by the highest possible amount
-                    user.assets
-                        .insert(pessimistic_asset, user_asset - pessimistic_value);
-                    orders.insert(
-                        trade_command.id,
-                        (
-                            trade_command.participant_id,
-                            trade_command.symbol,
-                            RiskOrder::from(trade_command),
-                        ),
-                    );
-                    RiskEngineResult::ValidForMatchingEngine
-                } else {
-                    RiskEngineResult::InsufficientFunds
-                }
-            }
-            None => RiskEngineResult::InsufficientFunds,
+        let (pessimistic_asset, pessimistic_value) =
+            match trade_command.pessimistic(symbol, fee_rate) {
+                Some(pessimistic) => pessimistic,
+                None => return RiskEngineResult::BalanceOverflow,
+            };
+
+        //Pessimistically hold the highest possible amount
+        if !user.hold(
+            trade_command.symbol as usize,
+            pessimistic_asset,
+            pessimistic_value,
+        ) {
+            return RiskEngineResult::InsufficientFunds;
         }
+        orders.insert(
+            trade_command.id,
+            (
+                trade_command.participant_id,
+                trade_command.symbol,
+                RiskOrder::held(trade_command, pessimistic_value),
+            ),
+        );
+        RiskEngineResult::ValidForMatchingEngine
     }
 
-    pub fn process_matcher_event(&mut self, event: MatchingEngineEvent) {
-        match event {
-            MatchingEngineEvent::Filled(id, volume, value) => {
-                let (participant_id, symbol_id, order) = self
-                    .orders
-                    .get_mut(&id)
-                    .expect("Order filled that was not known to the risk engine");
-
-                let participant = self.participants.get_mut(participant_id).expect(
-                    "Order was filled for participant that was not known to the risk engine.",
-                );
+    /// Check both sides of a quote at once and hold the funds for both or neither
+    fn place_exchange_quote(
+        symbol: &Symbol,
+        user: &mut Participant,
+        quote_command: QuoteCommand,
+        orders: &mut FxHashMap<u64, (u64, u64, RiskOrder)>,
+        fee_rate: u64,
+    ) -> RiskEngineResult {
+        match Self::exchange_quote_legs(symbol, &quote_command, fee_rate) {
+            Some(legs) => Self::place_quote_legs(user, &quote_command, legs, orders),
+            None => RiskEngineResult::BalanceOverflow,
+        }
+    }
 
-                let symbol = &self.settings.symbols[*symbol_id as usize];
+    /// Hold the legs of a quote (leg, asset, hold) for both sides or neither.
+    ///
+    /// Each side of the previous quote on the symbol hands its hold over to the same side
+    /// of this one once the order book cancels it, so only the difference is held now.
+    fn place_quote_legs(
+        user: &mut Participant,
+        quote_command: &QuoteCommand,
+        legs: Vec<(TradeCommand, AssetId, u64)>,
+        orders: &mut FxHashMap<u64, (u64, u64, RiskOrder)>,
+    ) -> RiskEngineResult {
+        let symbol_id = quote_command.symbol as usize;
+        let legs: Vec<(TradeCommand, AssetId, u64, u64)> = legs
+            .into_iter()
+            .map(|(leg, asset, hold)| {
+                let inherited = std::cmp::min(hold, Self::previous_quote_hold(user, orders, &leg));
+                (leg, asset, hold - inherited, inherited)
+            })
+            .collect();
+        let required =
+            Self::quote_requirement(legs.iter().map(|(_, asset, hold, _)| (*asset, *hold)));
 
-                // The pessimistic amount of value that was previously deducted for the filled amount of value
-                let (pessimistic_asset, pessimistic_value) =
-                    TradeCommand::historic_pessimistic(order.side, order.limit, symbol, volume);
+        let sufficient = required
+            .iter()
+            .all(|(asset, value)| user.available(symbol_id, *asset) >= *value);
+        if !sufficient {
+            return RiskEngineResult::InsufficientFunds;
+        }
+        for (asset, value) in required {
+            let held = user.hold(symbol_id, asset, value);
+            debug_assert!(held, "Quote held more than was available");
+        }
 
-                let (rising_asset, rising_value, falling_value) = match order.side {
-                    OrderSide::BID => (symbol.quote_asset, volume, value),
-                    OrderSide::ASK => (symbol.base_asset, value, volume),
-                };
-                let rising_asset = participant
-                    .assets
-                    .get_mut(&rising_asset)
-                    .expect("Order filled for user asset not known to the risk engine.");
-                *rising_asset += rising_value;
-                let asset = participant
-                    .assets
-                    .get_mut(&pessimistic_asset)
-                    .expect("Order filled for user asset not known to the risk engine.");
+        let previous = user.quotes.insert(
+            quote_command.symbol,
+            (quote_command.bid_id, quote_command.ask_id),
+        );
+        for (leg, _, hold, inherited) in legs {
+            if let Some(position) = user.positions.get_mut(&symbol_id) {
+                position.pending_hold(leg.side, leg.volume);
+            }
+            let replaced = previous.map(|(bid_id, ask_id)| match leg.side {
+                OrderSide::BID => bid_id,
+                OrderSide::ASK => ask_id,
+            });
+            if let Some((_, _, replaced)) = replaced.and_then(|id| orders.get_mut(&id)) {
+                replaced.successor = Some(leg.id);
+            }
+            let mut order = RiskOrder::held(leg, hold);
+            order.inherited = inherited;
+            orders.insert(leg.id, (leg.participant_id, leg.symbol, order));
+        }
+        RiskEngineResult::ValidForMatchingEngine
+    }
 
-                // Update the asset to reflect the value actually paid
-                let difference = pessimistic_value - falling_value;
-                *asset += difference;
+    /// Release the holds of a quote placed by `place_quote_legs` and restore the quote it replaced
+    fn unplace_quote(
+        symbol: &Symbol,
+        user: &mut Participant,
+        quote_command: &QuoteCommand,
+        previous: Option<(u64, u64)>,
+        orders: &mut FxHashMap<u64, (u64, u64, RiskOrder)>,
+    ) {
+        let symbol_id = quote_command.symbol as usize;
+        for leg in quote_command.legs() {
+            if let Some((_, _, order)) = orders.remove(&leg.id) {
+                user.release(symbol_id, Self::hold_asset(symbol, leg.side), order.hold);
+                if let Some(position) = user.positions.get_mut(&symbol_id) {
+                    position.pending_release(leg.side, leg.volume);
+                }
             }
-            MatchingEngineEvent::Canceled(id) => {
-                //Add the pessimistically removed assets back
+        }
+        match previous {
+            Some((bid_id, ask_id)) => {
+                user.quotes.insert(quote_command.symbol, (bid_id, ask_id));
+                for id in [bid_id, ask_id] {
+                    if let Some((_, _, order)) = orders.get_mut(&id) {
+                        order.successor = None;
+                    }
+                }
+            }
+            None => {
+                user.quotes.remove(&quote_command.symbol);
+            }
+        }
+    }
 
-                let (participant_id, symbol_id, order) = self
-                    .orders
-                    .get_mut(&id)
-                    .expect("Order filled that was not known to the risk engine");
+    /// What the leg of the participant's last quote on the same side holds
+    /// or still takes over, it passes all of it on to the leg that replaces it
+    fn previous_quote_hold(
+        user: &Participant,
+        orders: &FxHashMap<u64, (u64, u64, RiskOrder)>,
+        leg: &TradeCommand,
+    ) -> u64 {
+        let previous = match (user.quotes.get(&leg.symbol), leg.side) {
+            (Some((bid_id, _)), OrderSide::BID) => *bid_id,
+            (Some((_, ask_id)), OrderSide::ASK) => *ask_id,
+            (None, _) => return 0,
+        };
+        orders
+            .get(&previous)
+            .map_or(0, |(_, _, order)| order.hold + order.inherited)
+    }
 
-                let participant = self.participants.get_mut(participant_id).expect(
-                    "Order was filled for participant that was not known to the risk engine.",
+    /// Pass the hold of an order that left the book on to the quote leg that replaced it.
+    ///
+    /// If the order was filled while the new quote was on its way, the new leg holds
+    /// the rest from the collateral or is canceled. Returns the hold that is left to release.
+    fn pass_on_hold(
+        orders: &mut FxHashMap<u64, (u64, u64, RiskOrder)>,
+        participant: &mut Participant,
+        symbol_id: u64,
+        asset: AssetId,
+        successor: Option<u64>,
+        hold: u64,
+        cancels: &mut Vec<CancelCommand>,
+    ) -> u64 {
+        let successor = match successor.and_then(|id| orders.get_mut(&id)) {
+            Some((_, _, successor)) => successor,
+            None => return hold,
+        };
+        let passed = std::cmp::min(hold, successor.inherited);
+        successor.hold += passed;
+        let missing = std::mem::take(&mut successor.inherited) - passed;
+        if missing > 0 {
+            if participant.hold(symbol_id as usize, asset, missing) {
+                successor.hold += missing;
+            } else {
+                debug!(
+                    "Quote leg {} lacks {} of its hold and is canceled",
+                    successor.id, missing
                 );
+                cancels.push(CancelCommand {
+                    symbol: symbol_id,
+                    order_id: successor.id,
+                    participant_id: participant.id,
+                    remaining: 0,
+                });
+            }
+        }
+        hold - passed
+    }
 
-                let symbol = &self.settings.symbols[*symbol_id as usize];
+    /// Hold the funds of a one-cancels-other pair only once.
+    ///
+    /// Only one leg can execute, so the first leg holds the larger of both requirements
+    /// and the second leg holds nothing. Whichever leg fills takes over the whole hold.
+    /// `covered` is the part of the requirement that is paid for by something else,
+    /// like the proceeds of a bracket entry.
+    fn place_exchange_oco(
+        symbol: &Symbol,
+        user: &mut Participant,
+        oco_command: OcoCommand,
+        covered: u64,
+        orders: &mut FxHashMap<u64, (u64, u64, RiskOrder)>,
+        fee_rate: u64,
+    ) -> RiskEngineResult {
+        let (asset, first_value, second_value) = match (
+            oco_command.first.pessimistic(symbol, fee_rate),
+            oco_command.second.pessimistic(symbol, fee_rate),
+        ) {
+            (Some((asset, first_value)), Some((_, second_value))) => {
+                (asset, first_value, second_value)
+            }
+            _ => return RiskEngineResult::BalanceOverflow,
+        };
+        let required = std::cmp::max(first_value, second_value).saturating_sub(covered);
 
-                let (pessimistic_asset, pessimistic_value) = TradeCommand::historic_pessimistic(
-                    order.side,
-                    order.limit,
-                    symbol,
-                    order.volume,
-                );
+        if !user.hold(oco_command.first.symbol as usize, asset, required) {
+            return RiskEngineResult::InsufficientFunds;
+        }
 
-                let asset = (participant)
-                    .assets
-                    .get_mut(&pessimistic_asset)
-                    .expect("Order filled for user asset not known to the risk engine.");
+        let mut first = RiskOrder::held(oco_command.first, required);
+        first.linked = Some(oco_command.second.id);
+        let mut second = RiskOrder::held(oco_command.second, 0);
+        second.linked = Some(oco_command.first.id);
+        for order in [first, second] {
+            orders.insert(
+                order.id,
+                (
+                    oco_command.first.participant_id,
+                    oco_command.first.symbol,
+                    order,
+                ),
+            );
+        }
+        RiskEngineResult::ValidForMatchingEngine
+    }
 
-                // Update the asset to reflect the value actually paid
-                *asset += pessimistic_value;
+    /// Hold the entry of a bracket and whatever its exit pair needs beyond the entry's proceeds.
+    ///
+    /// The exit legs trade the asset the entry receives, so the proceeds of the entry
+    /// are moved into the exit hold as the entry fills.
+    fn place_exchange_bracket(
+        symbol: &Symbol,
+        user: &mut Participant,
+        bracket_command: BracketCommand,
+        orders: &mut FxHashMap<u64, (u64, u64, RiskOrder)>,
+        fee_rate: u64,
+    ) -> RiskEngineResult {
+        let entry = bracket_command.entry;
+        // Least amount the entry receives when it is completely filled
+        let proceeds = match entry.side {
+            OrderSide::BID => entry.volume,
+            OrderSide::ASK => match entry.volume.checked_mul(entry.limit) {
+                Some(proceeds) => proceeds,
+                None => return RiskEngineResult::BalanceOverflow,
+            },
+        };
+        let (entry_asset, entry_value) = match entry.pessimistic(symbol, fee_rate) {
+            Some(pessimistic) => pessimistic,
+            None => return RiskEngineResult::BalanceOverflow,
+        };
+        if !user.hold(entry.symbol as usize, entry_asset, entry_value) {
+            return RiskEngineResult::InsufficientFunds;
+        }
+        let exit = bracket_command.exit;
+        match Self::place_exchange_oco(symbol, user, exit, proceeds, orders, fee_rate) {
+            RiskEngineResult::ValidForMatchingEngine => (),
+            result => {
+                user.release(entry.symbol as usize, entry_asset, entry_value);
+                return result;
             }
         }
+
+        let mut entry_order = RiskOrder::held(entry, entry_value);
+        entry_order.activates = Some(bracket_command.exit.first.id);
+        orders.insert(entry.id, (entry.participant_id, entry.symbol, entry_order));
+        RiskEngineResult::ValidForMatchingEngine
+    }
+
+    /// How much the exit pair activated by a bracket entry still lacks of its full hold
+    fn exit_shortfall(&self, exit: u64) -> u64 {
+        let (_, symbol_id, first) = &self.orders[&exit];
+        let symbol = &self.settings.symbols[*symbol_id as usize];
+        let fee_rate = Self::fee_rate(&self.settings, *symbol_id);
+        // Both legs were checked to fit when they were placed
+        let (_, mut required) = TradeCommand::historic_pessimistic(
+            first.side,
+            first.limit,
+            symbol,
+            first.volume,
+            fee_rate,
+        )
+        .expect("Exit leg holds more than a balance can");
+        if let Some((_, _, second)) = first.linked.and_then(|id| self.orders.get(&id)) {
+            let (_, second_value) = TradeCommand::historic_pessimistic(
+                second.side,
+                second.limit,
+                symbol,
+                second.volume,
+                fee_rate,
+            )
+            .expect("Exit leg holds more than a balance can");
+            required = std::cmp::max(required, second_value);
+        }
+        required.saturating_sub(first.hold)
+    }
+
+    /// Hold what a futures or option order needs in the quote asset.
+    /// `hold` computes the requirement of an order against the current position.
+    fn place_margined_order(
+        symbol: &Symbol,
+        user: &mut Participant,
+        trade_command: TradeCommand,
+        orders: &mut FxHashMap<u64, (u64, u64, RiskOrder)>,
+        hold: impl Fn(&PositionRecord, &TradeCommand) -> u64,
+    ) -> RiskEngineResult {
+        let symbol_id = trade_command.symbol as usize;
+        let position = user
+            .positions
+            .entry(symbol_id)
+            .or_insert_with(|| PositionRecord::new(symbol_id));
+        let required = hold(position, &trade_command);
+
+        if !user.hold(symbol_id, symbol.quote_asset, required) {
+            return RiskEngineResult::InsufficientFunds;
+        }
+        user.positions
+            .get_mut(&symbol_id)
+            .unwrap()
+            .pending_hold(trade_command.side, trade_command.volume);
+        orders.insert(
+            trade_command.id,
+            (
+                trade_command.participant_id,
+                trade_command.symbol,
+                RiskOrder::held(trade_command, required),
+            ),
+        );
+        RiskEngineResult::ValidForMatchingEngine
+    }
+
+    /// Hold what both sides of a futures or option quote need, or nothing
+    fn place_margined_quote(
+        symbol: &Symbol,
+        user: &mut Participant,
+        quote_command: QuoteCommand,
+        orders: &mut FxHashMap<u64, (u64, u64, RiskOrder)>,
+        hold: impl Fn(&PositionRecord, &TradeCommand) -> u64,
+    ) -> RiskEngineResult {
+        let symbol_id = quote_command.symbol as usize;
+        let position = user
+            .positions
+            .entry(symbol_id)
+            .or_insert_with(|| PositionRecord::new(symbol_id));
+        let legs = Self::margined_quote_legs(symbol, &quote_command, position, hold);
+        Self::place_quote_legs(user, &quote_command, legs, orders)
+    }
+
+    /// What each side of a spot quote could spend (leg, asset_id, value), None if it does not fit
+    fn exchange_quote_legs(
+        symbol: &Symbol,
+        quote_command: &QuoteCommand,
+        fee_rate: u64,
+    ) -> Option<Vec<(TradeCommand, AssetId, u64)>> {
+        quote_command
+            .legs()
+            .map(|leg| {
+                let (asset, value) = leg.pessimistic(symbol, fee_rate)?;
+                Some((leg, asset, value))
+            })
+            .collect()
+    }
+
+    /// What each side of a futures or option quote needs against a position (leg, asset_id, value)
+    fn margined_quote_legs(
+        symbol: &Symbol,
+        quote_command: &QuoteCommand,
+        position: &PositionRecord,
+        hold: impl Fn(&PositionRecord, &TradeCommand) -> u64,
+    ) -> Vec<(TradeCommand, AssetId, u64)> {
+        quote_command
+            .legs()
+            .map(|leg| {
+                let required = hold(position, &leg);
+                (leg, symbol.quote_asset, required)
+            })
+            .collect()
+    }
+
+    /// Initial margin for the part of a futures order that could open a position.
+    /// Volume that closes an existing position needs no margin.
+    /// Asks are margined at least at the mark price, they may sell below their limit.
+    /// The fee at `fee_rate` basis points is held for the whole volume.
+    fn futures_hold(
+        spec: FuturesSpec,
+        mark_price: Option<u64>,
+        fee_rate: u64,
+    ) -> impl Fn(&PositionRecord, &TradeCommand) -> u64 {
+        move |position, trade| {
+            let opening = position.opening_volume(trade.side, trade.volume);
+            let price = match trade.side {
+                OrderSide::BID => trade.limit,
+                OrderSide::ASK => std::cmp::max(trade.limit, mark_price.unwrap_or(0)),
+            };
+            let fee = (trade.volume * price * fee_rate).div_ceil(10_000);
+            spec.initial_margin(opening * price) + fee
+        }
+    }
+
+    /// Premium and fee for bids, short margin for the part of an ask that could open a short position.
+    /// Asks pay the fee out of the premium they receive.
+    fn option_hold(
+        spec: OptionSpec,
+        underlying_price: u64,
+        fee_rate: u64,
+    ) -> impl Fn(&PositionRecord, &TradeCommand) -> u64 {
+        move |position, trade| match trade.side {
+            OrderSide::BID => {
+                let premium = trade.volume * trade.limit;
+                premium + (premium * fee_rate).div_ceil(10_000)
+            }
+            OrderSide::ASK => {
+                let opening = position.opening_volume(trade.side, trade.volume);
+                opening * spec.short_margin(trade.limit, underlying_price)
+            }
+        }
+    }
+
+    /// Highest fee rate of a symbol in basis points, held with its orders
+    fn fee_rate(settings: &ExchangeSettings, symbol_id: u64) -> u64 {
+        settings
+            .fees
+            .get(&symbol_id)
+            .map_or(0, FeeSchedule::max_rate)
+    }
+
+    /// Mark price of the underlying of an option.
+    /// Options on an underlying without mark price are margined as if at the money.
+    fn underlying_price(mark_prices: &MarkPrices, spec: &OptionSpec) -> u64 {
+        mark_prices.get(spec.underlying).unwrap_or(spec.strike)
+    }
+
+    /// Asset that holds are taken from for an order on the given side
+    fn hold_asset(symbol: &Symbol, side: OrderSide) -> AssetId {
+        match symbol.symbol_type {
+            SymbolType::ExchangePair => {
+                TradeCommand::historic_pessimistic(side, 0, symbol, 0, 0)
+                    .unwrap()
+                    .0
+            }
+            SymbolType::FuturesContract(_)
+            | SymbolType::PerpetualSwap(_)
+            | SymbolType::Option(_) => symbol.quote_asset,
+        }
+    }
+
+    /// What the sides of a quote need together (asset_id, value)
+    fn quote_requirement(legs: impl Iterator<Item = (AssetId, u64)>) -> Vec<(usize, u64)> {
+        let mut required: Vec<(usize, u64)> = Vec::with_capacity(2);
+        for (asset, value) in legs {
+            match required.iter_mut().find(|(a, _)| *a == asset) {
+                Some((_, sum)) => *sum += value,
+                None => required.push((asset, value)),
+            }
+        }
+        required
+    }
+
+    pub fn process_matcher_event(&mut self, event: MatchingEngineEvent) {
+        match event {
+            MatchingEngineEvent::FairPrice(symbol, price) => {
+                self.mark_prices.set_fair(symbol, price);
+                self.check_positions_of(symbol);
+            }
+            MatchingEngineEvent::Filled(id, volume, value, liquidity) => {
+                self.reduce_open_exposure(id, volume);
+                let (participant_id, symbol_id) = match self.orders.get(&id) {
+                    Some((participant_id, symbol_id, _)) => (*participant_id, *symbol_id),
+                    None => {
+                        debug!("Fill of unknown order {} ignored", id);
+                        return;
+                    }
+                };
+                if let Some(price) = value.checked_div(volume) {
+                    self.last_prices.insert(symbol_id, price);
+                }
+                match self.settings.symbols[symbol_id as usize].symbol_type {
+                    SymbolType::ExchangePair => self.settle_exchange_fill(id, volume, value),
+                    SymbolType::FuturesContract(_)
+                    | SymbolType::PerpetualSwap(_)
+                    | SymbolType::Option(_) => self.settle_position_fill(id, volume, value),
+                }
+                self.charge_fee(participant_id, symbol_id, value, liquidity);
+            }
+            MatchingEngineEvent::Canceled(id) => {
+                if let Some((_, _, order)) = self.orders.get(&id) {
+                    match order.linked {
+                        Some(linked) => self.pass_on_exposure(id, linked),
+                        None => self.reduce_open_exposure(id, order.volume),
+                    }
+                }
+                //Release the held assets
+                let (participant_id, symbol_id, order) = match self.orders.remove(&id) {
+                    Some(order) => order,
+                    // A late or repeated cancel of an order that is gone already
+                    None => {
+                        debug!("Cancel of unknown order {} ignored", id);
+                        return;
+                    }
+                };
+
+                if let Some(linked) = order.linked {
+                    if let Some((_, _, linked_order)) = self.orders.get_mut(&linked) {
+                        linked_order.linked = None;
+                    }
+                }
+
+                let participant = self.participants.get_mut(&participant_id).expect(
+                    "Order was canceled for participant that was not known to the risk engine.",
+                );
+
+                let symbol = &self.settings.symbols[symbol_id as usize];
+
+                if let SymbolType::FuturesContract(_)
+                | SymbolType::PerpetualSwap(_)
+                | SymbolType::Option(_) = symbol.symbol_type
+                {
+                    if let Some(position) = participant.positions.get_mut(&(symbol_id as usize)) {
+                        position.pending_release(order.side, order.volume);
+                        position
+                            .reduce_only
+                            .retain(|reduce_only| *reduce_only != id);
+                    }
+                }
+
+                let asset = Self::hold_asset(symbol, order.side);
+                let released = Self::pass_on_hold(
+                    &mut self.orders,
+                    participant,
+                    symbol_id,
+                    asset,
+                    order.successor,
+                    order.hold,
+                    &mut self.cancels,
+                );
+                participant.release(symbol_id as usize, asset, released);
+                if !order.relief.is_empty() {
+                    self.restore_portfolio_margin(participant_id, order.relief);
+                }
+
+                // The book could not absorb the rest of a liquidation order
+                if let Some((_, _, bankruptcy_price)) = self.liquidation_orders.get(&id) {
+                    if order.volume > 0 {
+                        let price = *bankruptcy_price;
+                        self.take_over(participant_id, symbol_id, order.side, order.volume, price);
+                    }
+                    self.liquidation_done(id);
+                }
+            }
+            MatchingEngineEvent::Reduced(id, volume) => self.release_reduced(id, volume),
+            MatchingEngineEvent::Flushed(_) => {
+                unreachable!("Migrations are coordinated by the risk engine processor")
+            }
+        }
+    }
+
+    /// Release the share of the hold that belongs to the volume the book took out of an order
+    fn release_reduced(&mut self, id: u64, volume: u64) {
+        self.reduce_open_exposure(id, volume);
+        let (participant_id, symbol_id, order) = match self.orders.get_mut(&id) {
+            Some(order) => order,
+            None => {
+                debug!("Reduction of unknown order {} ignored", id);
+                return;
+            }
+        };
+        let volume = std::cmp::min(volume, order.volume);
+        if volume == 0 {
+            return;
+        }
+        let released = order.hold * volume / order.volume;
+        order.hold -= released;
+        order.volume -= volume;
+
+        let participant = self
+            .participants
+            .get_mut(participant_id)
+            .expect("Order was reduced for participant that was not known to the risk engine.");
+        let symbol = &self.settings.symbols[*symbol_id as usize];
+        if let Some(position) = participant.positions.get_mut(&(*symbol_id as usize)) {
+            position.pending_release(order.side, volume);
+        }
+        let asset = Self::hold_asset(symbol, order.side);
+        participant.release(*symbol_id as usize, asset, released);
+    }
+
+    /// Exchange the assets of a spot fill and release what the order no longer needs held
+    fn settle_exchange_fill(&mut self, id: u64, volume: u64, value: u64) {
+        let (linked, activates) = {
+            let (_, _, order) = self
+                .orders
+                .get(&id)
+                .expect("Order filled that was not known to the risk engine");
+            (order.linked, order.activates)
+        };
+
+        // The order book cancels the other leg, its share of the hold now belongs to this order
+        let linked_hold = match linked {
+            Some(linked) => match self.orders.get_mut(&linked) {
+                Some((_, _, linked_order)) => {
+                    linked_order.linked = None;
+                    std::mem::take(&mut linked_order.hold)
+                }
+                None => 0,
+            },
+            None => 0,
+        };
+
+        // Proceeds of a bracket entry that are still needed to hold its exit legs
+        let exit_shortfall = activates.map_or(0, |exit| self.exit_shortfall(exit));
+
+        let (participant_id, symbol_id, order) = self.orders.get_mut(&id).unwrap();
+
+        let participant = self
+            .participants
+            .get_mut(participant_id)
+            .expect("Order was filled for participant that was not known to the risk engine.");
+
+        let symbol = &self.settings.symbols[*symbol_id as usize];
+
+        let (rising_asset, rising_value, falling_value) = match order.side {
+            OrderSide::BID => (symbol.quote_asset, volume, value),
+            OrderSide::ASK => (symbol.base_asset, value, volume),
+        };
+
+        // Keep holding the pessimistic amount for the remaining volume, release the rest
+        order.keep_relief(volume);
+        order.volume -= volume;
+        order.linked = None;
+        // The whole volume fit when the order was placed
+        let (pessimistic_asset, remaining_hold) = TradeCommand::historic_pessimistic(
+            order.side,
+            order.limit,
+            symbol,
+            order.volume,
+            Self::fee_rate(&self.settings, *symbol_id),
+        )
+        .expect("Order holds more than a balance can");
+        let needed = falling_value + remaining_hold;
+        let mut deficit = 0;
+        let released = match (order.hold + linked_hold).checked_sub(needed) {
+            Some(released) => released,
+            None => {
+                // What the hold lacks for the fill and the remaining volume comes out of the collateral
+                let missing = needed - order.hold - linked_hold;
+                warn!("Order {} held {} less than its fill needs", id, missing);
+                deficit =
+                    Self::settle_collateral(participant, *symbol_id, pessimistic_asset, 0, missing);
+                *participant.held.entry(pessimistic_asset).or_insert(0) += missing;
+                0
+            }
+        };
+        order.hold = remaining_hold;
+
+        participant.settle(pessimistic_asset, falling_value);
+        participant.release(*symbol_id as usize, pessimistic_asset, released);
+
+        // Proceeds still needed by the exit legs are held for them right away
+        let diverted = std::cmp::min(exit_shortfall, rising_value);
+        *participant.assets.entry(rising_asset).or_insert(0) += rising_value;
+        let held = participant.hold(*symbol_id as usize, rising_asset, diverted);
+        debug_assert!(held, "Diverted more than the fill paid");
+
+        let (participant_id, symbol_id) = (*participant_id, *symbol_id);
+        if order.volume == 0 {
+            let successor = order.successor;
+            self.orders.remove(&id);
+            Self::pass_on_hold(
+                &mut self.orders,
+                participant,
+                symbol_id,
+                pessimistic_asset,
+                successor,
+                0,
+                &mut self.cancels,
+            );
+        }
+        if let Some((_, _, exit)) = activates.and_then(|exit| self.orders.get_mut(&exit)) {
+            exit.hold += diverted;
+        }
+        if let Some(linked) = linked {
+            self.pass_on_exposure(linked, id);
+        }
+        self.cover_deficit(participant_id, symbol_id, pessimistic_asset, deficit);
+    }
+
+    /// Release the hold of a futures, perpetual swap or option fill into its position
+    fn settle_position_fill(&mut self, id: u64, volume: u64, value: u64) {
+        let (participant_id, symbol_id, order) = self.orders.get_mut(&id).unwrap();
+
+        let participant = self
+            .participants
+            .get_mut(participant_id)
+            .expect("Order was filled for participant that was not known to the risk engine.");
+
+        let symbol = &self.settings.symbols[*symbol_id as usize];
+
+        // Share of the order's hold that belongs to the filled volume
+        let hold = order.hold * volume / order.volume;
+        order.hold -= hold;
+        order.keep_relief(volume);
+        order.volume -= volume;
+        let side = order.side;
+        let filled = order.volume == 0;
+        let successor = order.successor;
+
+        if let Some(position) = participant.positions.get_mut(&(*symbol_id as usize)) {
+            position.pending_release(side, volume);
+        }
+        participant.release(*symbol_id as usize, symbol.quote_asset, hold);
+        let mut deficit = Self::book_position_fill(
+            participant,
+            *symbol_id,
+            symbol,
+            &self.mark_prices,
+            side,
+            volume,
+            value,
+        );
+
+        // Fills that were on their way while the option expired are settled right away
+        if let (SymbolType::Option(spec), Some(settlement_price)) =
+            (&symbol.symbol_type, self.expired.get(symbol_id))
+        {
+            deficit += Self::settle_expired_position(
+                participant,
+                *symbol_id,
+                symbol,
+                *spec,
+                *settlement_price,
+            );
+        }
+        let asset = symbol.quote_asset;
+
+        if filled {
+            if let Some(position) = participant.positions.get_mut(&(*symbol_id as usize)) {
+                position
+                    .reduce_only
+                    .retain(|reduce_only| *reduce_only != id);
+            }
+        }
+        let (participant_id, symbol_id) = (*participant_id, *symbol_id);
+        self.margin_checks.insert(participant_id);
+        self.cover_deficit(participant_id, symbol_id, asset, deficit);
+        if filled {
+            self.orders.remove(&id);
+            let participant = self.participants.get_mut(&participant_id).unwrap();
+            Self::pass_on_hold(
+                &mut self.orders,
+                participant,
+                symbol_id,
+                asset,
+                successor,
+                0,
+                &mut self.cancels,
+            );
+            self.liquidation_done(id);
+        }
+        self.check_reduce_only(participant_id, symbol_id);
+    }
+
+    /// Book a fill of `volume` contracts for `value` into a position.
+    ///
+    /// Futures margin the opened volume and settle realized profit,
+    /// options exchange the premium and margin the opened short volume.
+    /// Returns what the collateral could not pay.
+    fn book_position_fill(
+        participant: &mut Participant,
+        symbol_id: u64,
+        symbol: &Symbol,
+        mark_prices: &MarkPrices,
+        side: OrderSide,
+        volume: u64,
+        value: u64,
+    ) -> u64 {
+        let position = participant
+            .positions
+            .entry(symbol_id as usize)
+            .or_insert_with(|| PositionRecord::new(symbol_id as usize));
+
+        let (volume_before, margin_before) = (position.volume, position.margin);
+        let change = position.apply_fill(side, volume, value);
+        let released = if change.closed > 0 {
+            margin_before * change.closed / volume_before
+        } else {
+            0
+        };
+        let (credit, debit) = match symbol.symbol_type {
+            SymbolType::FuturesContract(spec)
+            | SymbolType::PerpetualSwap(PerpetualSpec { margin: spec, .. }) => {
+                let required = spec.initial_margin(value * change.opened / volume);
+                position.margin += required;
+                (
+                    released + std::cmp::max(change.realized, 0) as u64,
+                    required + std::cmp::max(-change.realized, 0) as u64,
+                )
+            }
+            // Premium is exchanged in full, realized profit needs no further settlement
+            SymbolType::Option(spec) => match side {
+                OrderSide::BID => (released, value),
+                OrderSide::ASK => {
+                    let underlying_price = Self::underlying_price(mark_prices, &spec);
+                    let required =
+                        change.opened * spec.short_margin(value / volume, underlying_price);
+                    position.margin += required;
+                    (released + value, required)
+                }
+            },
+            SymbolType::ExchangePair => unreachable!("Exchange pairs have no positions"),
+        };
+        position.margin -= released;
+        Self::settle_collateral(participant, symbol_id, symbol.quote_asset, credit, debit)
+    }
+
+    /// Book a credit and a debit on the collateral backing a position.
+    /// A debit that exceeds the collateral leaves it at zero,
+    /// returns the part of the debit it could not pay.
+    #[must_use]
+    fn settle_collateral(
+        participant: &mut Participant,
+        symbol_id: u64,
+        asset: AssetId,
+        credit: u64,
+        debit: u64,
+    ) -> u64 {
+        let collateral = participant.collateral(symbol_id as usize, asset);
+        let available = *collateral + credit;
+        *collateral = available.saturating_sub(debit);
+        debit.saturating_sub(available)
+    }
+
+    /// Let the insurance fund pay what a participant lacks after a settlement in a symbol.
+    ///
+    /// A loss beyond the insurance fund stays unpaid and halts a futures, perpetual swap
+    /// or option symbol, spot symbols keep trading.
+    fn cover_deficit(&mut self, debtor: u64, symbol_id: u64, asset: AssetId, deficit: u64) {
+        if deficit == 0 {
+            return;
+        }
+        let fund = self
+            .participants
+            .get_mut(&INSURANCE_FUND_ID)
+            .unwrap()
+            .assets
+            .entry(asset)
+            .or_insert(0);
+        let paid = std::cmp::min(*fund, deficit);
+        *fund -= paid;
+        warn!(
+            "Insurance fund pays {} of asset {} participant {} lacks",
+            paid, asset, debtor
+        );
+        if paid == deficit {
+            return;
+        }
+        error!(
+            "Loss of {} in asset {} of participant {} exceeds the insurance fund",
+            deficit - paid,
+            asset,
+            debtor
+        );
+        let symbol = &self.settings.symbols[symbol_id as usize];
+        if symbol.symbol_type != SymbolType::ExchangePair && self.halted.insert(symbol_id) {
+            warn!("Symbol {} halted", symbol_id);
+            self.halts.push(symbol_id);
+        }
+    }
+
+    /// Exercise a long or assign a short option position at the settlement price and close it.
+    /// Returns what the collateral could not pay.
+    fn settle_expired_position(
+        participant: &mut Participant,
+        symbol_id: u64,
+        symbol: &Symbol,
+        spec: OptionSpec,
+        settlement_price: u64,
+    ) -> u64 {
+        let position = match participant.positions.get_mut(&(symbol_id as usize)) {
+            Some(position) if position.volume > 0 => position,
+            _ => return 0,
+        };
+        let value = spec.intrinsic_value(settlement_price) * position.volume;
+        let (credit, debit, realized) = match position.direction {
+            PositionDirection::Long => (value, 0, value as i64 - position.paid_value as i64),
+            PositionDirection::Short => (
+                position.margin,
+                value,
+                position.paid_value as i64 - value as i64,
+            ),
+        };
+        position.profit += realized;
+        position.volume = 0;
+        position.paid_value = 0;
+        position.margin = 0;
+        Self::settle_collateral(participant, symbol_id, symbol.quote_asset, credit, debit)
+    }
+
+    /// Apply a command of the exchange operator to the participants of this shard
+    pub fn process_admin(&mut self, command: &AdminCommand) {
+        match *command {
+            AdminCommand::ExpireOption {
+                symbol: symbol_id,
+                settlement_price,
+            } => {
+                let symbol = match self.settings.symbols.get(symbol_id as usize) {
+                    Some(symbol) => symbol,
+                    None => {
+                        warn!("Expiry for unknown symbol {}", symbol_id);
+                        return;
+                    }
+                };
+                let spec = match symbol.symbol_type {
+                    SymbolType::Option(spec) => spec,
+                    _ => {
+                        warn!("Expiry for symbol {} that is no option", symbol_id);
+                        return;
+                    }
+                };
+                if self.expired.contains_key(&symbol_id) {
+                    return;
+                }
+                self.expired.insert(symbol_id, settlement_price);
+                let mut deficits = Vec::new();
+                for participant in self.participants.values_mut() {
+                    let deficit = Self::settle_expired_position(
+                        participant,
+                        symbol_id,
+                        symbol,
+                        spec,
+                        settlement_price,
+                    );
+                    deficits.push((participant.id, deficit));
+                }
+                let asset = symbol.quote_asset;
+                deficits.sort_unstable();
+                for (participant_id, deficit) in deficits {
+                    self.cover_deficit(participant_id, symbol_id, asset, deficit);
+                }
+            }
+            AdminCommand::MarkPrice { symbol, price } => {
+                self.mark_prices.set_index(symbol, price);
+                self.check_positions_of(symbol);
+            }
+            AdminCommand::Funding {
+                symbol: symbol_id,
+                time,
+                mark_price,
+                index_price,
+            } => {
+                let symbol = match self.settings.symbols.get(symbol_id as usize) {
+                    Some(symbol) => symbol,
+                    None => {
+                        warn!("Funding for unknown symbol {}", symbol_id);
+                        return;
+                    }
+                };
+                let spec = match symbol.symbol_type {
+                    SymbolType::PerpetualSwap(spec) => spec,
+                    _ => {
+                        warn!("Funding for symbol {} that is no perpetual swap", symbol_id);
+                        return;
+                    }
+                };
+                let record =
+                    match self
+                        .funding
+                        .fund(symbol_id, &spec, time, mark_price, index_price)
+                    {
+                        Some(record) => record,
+                        None => {
+                            warn!(
+                                "Funding for symbol {} before its interval passed",
+                                symbol_id
+                            );
+                            return;
+                        }
+                    };
+                let mut deficits = Vec::new();
+                for participant in self.participants.values_mut() {
+                    let deficit = Self::pay_funding(participant, symbol_id, symbol, &record);
+                    deficits.push((participant.id, deficit));
+                }
+                let asset = symbol.quote_asset;
+                deficits.sort_unstable();
+                for (participant_id, deficit) in deficits {
+                    self.cover_deficit(participant_id, symbol_id, asset, deficit);
+                }
+                self.check_positions_of(symbol_id);
+            }
+            AdminCommand::RollDay => self.fees.roll_day(),
+            AdminCommand::Halt { symbol } => {
+                self.halted.insert(symbol);
+            }
+            AdminCommand::InsuranceDeposit {
+                shard,
+                asset,
+                amount,
+            } => {
+                if shard != self.shard {
+                    return;
+                }
+                let fund = self.participants.get_mut(&INSURANCE_FUND_ID).unwrap();
+                let balance = fund.assets.entry(asset).or_insert(0);
+                match balance.checked_add(amount) {
+                    Some(sum) => *balance = sum,
+                    None => warn!(
+                        "Insurance deposit of {} in asset {} overflows",
+                        amount, asset
+                    ),
+                }
+            }
+            AdminCommand::SymbolLimits { symbol, limits } => {
+                self.order_limits.set_symbol(symbol, limits)
+            }
+            AdminCommand::ParticipantLimits {
+                participant_id,
+                limits,
+            } => self.order_limits.set_participant(participant_id, limits),
+            AdminCommand::ExposureLimits {
+                participant_id,
+                limits,
+            } => {
+                self.exposure_limits.insert(participant_id, limits);
+            }
+        }
+    }
+
+    /// Charge the fee of a fill from the collateral backing the symbol and credit it to the
+    /// fee account, or pay the rebate from the fee account
+    fn charge_fee(
+        &mut self,
+        participant_id: u64,
+        symbol_id: u64,
+        value: u64,
+        liquidity: Liquidity,
+    ) {
+        let schedule = match self.settings.fees.get(&symbol_id) {
+            Some(schedule) => schedule,
+            None => return,
+        };
+        let fee = self
+            .fees
+            .fee(schedule, participant_id, symbol_id, value, liquidity);
+        self.fees.record(participant_id, symbol_id, value);
+
+        let symbol = &self.settings.symbols[symbol_id as usize];
+        // Spot fills are paid in the base asset, everything else in the quote asset
+        let paid_asset = match symbol.symbol_type {
+            SymbolType::ExchangePair => symbol.base_asset,
+            _ => symbol.quote_asset,
+        };
+        let price = schedule
+            .asset
+            .filter(|asset| *asset != paid_asset)
+            .and_then(|asset| {
+                let pair = self.settings.symbols.iter().position(|symbol| {
+                    symbol.symbol_type == SymbolType::ExchangePair
+                        && symbol.base_asset == paid_asset
+                        && symbol.quote_asset == asset
+                })?;
+                let price = self
+                    .mark_prices
+                    .get(pair as u64)
+                    .filter(|price| *price > 0)?;
+                Some((asset, price))
+            });
+        // Fees in another asset come from the free funds of that asset,
+        // fees in the paid asset from the collateral backing the symbol
+        let (asset, amount, cross) = match price {
+            Some((asset, price)) if fee > 0 => (asset, (fee as u64).div_ceil(price), true),
+            Some((asset, price)) => (asset, fee.unsigned_abs() / price, true),
+            None => (paid_asset, fee.unsigned_abs(), false),
+        };
+        if amount == 0 {
+            return;
+        }
+        let account =
+            |participant: &Participant| match participant.positions.get(&(symbol_id as usize)) {
+                Some(position) if !cross => MarginAccount::of(symbol_id, symbol, position),
+                _ => MarginAccount::Cross(asset),
+            };
+
+        if fee < 0 {
+            // Rebates are only paid out of the fees the fee account collected
+            let fee_account = self.participants.get_mut(&FEE_ACCOUNT_ID).unwrap();
+            let funds = fee_account.assets.entry(asset).or_insert(0);
+            if *funds < amount {
+                warn!(
+                    "Fee account can not pay a rebate of {} to participant {}",
+                    amount, participant_id
+                );
+                return;
+            }
+            *funds -= amount;
+            let receiver = self.participants.get_mut(&participant_id).unwrap();
+            *receiver.pool(account(receiver)) += amount;
+        } else {
+            // The hold of the order covers the fee, the fee account only gets what was paid
+            let payer = self.participants.get_mut(&participant_id).unwrap();
+            let pool = payer.pool(account(payer));
+            let paid = std::cmp::min(*pool, amount);
+            *pool -= paid;
+            if paid < amount {
+                warn!(
+                    "Participant {} could not pay {} of a fee of {}",
+                    participant_id,
+                    amount - paid,
+                    amount
+                );
+            }
+            let fee_account = self.participants.get_mut(&FEE_ACCOUNT_ID).unwrap();
+            *fee_account.assets.entry(asset).or_insert(0) += paid;
+        }
+        self.margin_checks.insert(participant_id);
+    }
+
+    /// Pay the funding of a perpetual swap position, longs pay shorts if the rate is positive.
+    /// Payers round up and receivers round down, so funding never creates funds.
+    /// Returns what the collateral could not pay.
+    fn pay_funding(
+        participant: &mut Participant,
+        symbol_id: u64,
+        symbol: &Symbol,
+        record: &FundingRecord,
+    ) -> u64 {
+        let position = match participant.positions.get_mut(&(symbol_id as usize)) {
+            Some(position) if position.volume > 0 => position,
+            _ => return 0,
+        };
+        let notional = position.volume * record.mark_price;
+        let rate = record.rate.unsigned_abs();
+        let pays = (position.direction == PositionDirection::Long) == (record.rate > 0);
+        let (credit, debit) = if pays {
+            let amount = (notional * rate).div_ceil(10_000);
+            position.funding -= amount as i64;
+            (0, amount)
+        } else {
+            let amount = notional * rate / 10_000;
+            position.funding += amount as i64;
+            (amount, 0)
+        };
+        Self::settle_collateral(participant, symbol_id, symbol.quote_asset, credit, debit)
+    }
+
+    pub fn mark_price(&self, symbol: u64) -> Option<u64> {
+        self.mark_prices.get(symbol)
+    }
+
+    /// Margin of the participant's positions backed by a collateral pool
+    pub fn margin_state(&self, participant_id: u64, account: MarginAccount) -> Option<MarginState> {
+        self.participants.get(&participant_id).map(|participant| {
+            Self::margin_state_of(participant, &self.settings, &self.mark_prices, account)
+        })
+    }
+
+    /// Positions without a mark price are valued at their entry price
+    fn margin_state_of(
+        participant: &Participant,
+        settings: &ExchangeSettings,
+        mark_prices: &MarkPrices,
+        account: MarginAccount,
+    ) -> MarginState {
+        let collateral = match account {
+            MarginAccount::Cross(asset) => participant.assets.get(&asset).copied(),
+            MarginAccount::Isolated(symbol_id) => participant
+                .positions
+                .get(&(symbol_id as usize))
+                .map(|position| position.collateral),
+        };
+        let mut state = MarginState {
+            equity: collateral.unwrap_or(0) as i64,
+            maintenance: 0,
+        };
+        for (symbol_id, position) in &participant.positions {
+            let symbol = &settings.symbols[*symbol_id];
+            if MarginAccount::of(*symbol_id as u64, symbol, position) != account
+                || position.volume == 0
+            {
+                continue;
+            }
+            let mark_price = mark_prices
+                .get(*symbol_id as u64)
+                .unwrap_or_else(|| position.average_entry_price().unwrap());
+            // The premium of an option left the collateral at the fill, so an option
+            // counts with its mark value instead of its profit
+            let value = match symbol.symbol_type {
+                SymbolType::Option(_) => {
+                    let value = (position.volume * mark_price) as i64;
+                    match position.direction {
+                        PositionDirection::Long => value,
+                        PositionDirection::Short => -value,
+                    }
+                }
+                _ => position.unrealized_profit(mark_price),
+            };
+            state.equity += position.margin as i64 + value;
+            state.maintenance += match symbol.symbol_type {
+                SymbolType::FuturesContract(spec)
+                | SymbolType::PerpetualSwap(PerpetualSpec { margin: spec, .. }) => {
+                    spec.maintenance_margin(position.volume * mark_price)
+                }
+                SymbolType::Option(spec) if position.direction == PositionDirection::Short => {
+                    let underlying_price = Self::underlying_price(mark_prices, &spec);
+                    position.volume * spec.short_margin(mark_price, underlying_price)
+                }
+                SymbolType::Option(_) | SymbolType::ExchangePair => 0,
+            };
+        }
+        // Hedging spot holdings count as collateral and the offsets can only lower the margin
+        if let (Some(grid), MarginAccount::Cross(asset)) = (settings.portfolio_margin, account) {
+            let legs = Self::portfolio_legs(participant, settings, mark_prices, asset, None);
+            for leg in legs.iter().filter(|leg| matches!(leg.kind, LegKind::Spot)) {
+                state.equity += leg.volume * leg.price as i64;
+            }
+            state.maintenance = std::cmp::min(state.maintenance, grid.requirement(&legs));
+        }
+        state
+    }
+
+    /// Cross margin positions settling in `asset` and the spot holdings of their underlyings.
+    /// Holdings of the underlying of `order` are included as well.
+    fn portfolio_legs(
+        participant: &Participant,
+        settings: &ExchangeSettings,
+        mark_prices: &MarkPrices,
+        asset: AssetId,
+        order: Option<&PortfolioLeg>,
+    ) -> Vec<PortfolioLeg> {
+        let mut legs: Vec<PortfolioLeg> = participant
+            .positions
+            .iter()
+            .filter(|(symbol_id, position)| {
+                let symbol = &settings.symbols[**symbol_id];
+                MarginAccount::of(**symbol_id as u64, symbol, position)
+                    == MarginAccount::Cross(asset)
+                    && position.volume > 0
+            })
+            .map(|(symbol_id, position)| {
+                let symbol = &settings.symbols[*symbol_id];
+                let volume = match position.direction {
+                    PositionDirection::Long => position.volume as i64,
+                    PositionDirection::Short => -(position.volume as i64),
+                };
+                let price = mark_prices
+                    .get(*symbol_id as u64)
+                    .unwrap_or_else(|| position.average_entry_price().unwrap());
+                let (underlying, kind) = match symbol.symbol_type {
+                    SymbolType::Option(spec) => (
+                        settings.symbols[spec.underlying as usize].base_asset,
+                        LegKind::Option {
+                            spec,
+                            underlying_price: Self::underlying_price(mark_prices, &spec),
+                        },
+                    ),
+                    _ => (symbol.base_asset, LegKind::Future),
+                };
+                PortfolioLeg {
+                    underlying,
+                    volume,
+                    price,
+                    kind,
+                }
+            })
+            .collect();
+
+        let underlyings: BTreeSet<AssetId> =
+            legs.iter().chain(order).map(|leg| leg.underlying).collect();
+        for underlying in underlyings {
+            // Pairs pay in their base asset and deliver their quote asset
+            let pair = settings.symbols.iter().position(|symbol| {
+                symbol.symbol_type == SymbolType::ExchangePair
+                    && symbol.base_asset == asset
+                    && symbol.quote_asset == underlying
+            });
+            let holding = participant.assets.get(&underlying).copied().unwrap_or(0);
+            let price = pair.and_then(|pair| mark_prices.get(pair as u64));
+            if let (Some(price), true) = (price, holding > 0) {
+                legs.push(PortfolioLeg {
+                    underlying,
+                    volume: holding as i64,
+                    price,
+                    kind: LegKind::Spot,
+                });
+            }
+        }
+        legs
+    }
+
+    /// Free the position margin a risk reducing order makes unnecessary, so its hold can be paid.
+    ///
+    /// Only the shortfall of the order is released, and never more than the position margin
+    /// that exceeds the portfolio requirement after the order was filled.
+    /// Returns the margin released from each position (symbol_id, margin).
+    fn release_portfolio_margin(&mut self, command: &TradeCommand) -> Option<Vec<(usize, u64)>> {
+        let grid = self.settings.portfolio_margin?;
+        let (settings, mark_prices) = (&self.settings, &self.mark_prices);
+        let symbol = &settings.symbols[command.symbol as usize];
+        let participant = self.participants.get_mut(&command.participant_id)?;
+        let volume = match command.side {
+            OrderSide::BID => command.volume as i64,
+            OrderSide::ASK => -(command.volume as i64),
+        };
+        let empty = PositionRecord::new(command.symbol as usize);
+        let position = participant
+            .positions
+            .get(&(command.symbol as usize))
+            .unwrap_or(&empty);
+        let (asset, leg, required) = match symbol.symbol_type {
+            // Spot can only be bought with the settlement asset, not sold short
+            SymbolType::ExchangePair if command.side == OrderSide::BID => (
+                symbol.base_asset,
+                PortfolioLeg {
+                    underlying: symbol.quote_asset,
+                    volume,
+                    price: command.limit,
+                    kind: LegKind::Spot,
+                },
+                command.volume * command.limit,
+            ),
+            SymbolType::ExchangePair => return None,
+            _ if position.mode == MarginMode::Isolated => return None,
+            SymbolType::FuturesContract(spec)
+            | SymbolType::PerpetualSwap(PerpetualSpec { margin: spec, .. }) => (
+                symbol.quote_asset,
+                PortfolioLeg {
+                    underlying: symbol.base_asset,
+                    volume,
+                    price: command.limit,
+                    kind: LegKind::Future,
+                },
+                Self::futures_hold(
+                    spec,
+                    mark_prices.get(command.symbol),
+                    Self::fee_rate(settings, command.symbol),
+                )(position, command),
+            ),
+            SymbolType::Option(spec) => {
+                let underlying_price = Self::underlying_price(mark_prices, &spec);
+                (
+                    symbol.quote_asset,
+                    PortfolioLeg {
+                        underlying: settings.symbols[spec.underlying as usize].base_asset,
+                        volume,
+                        price: command.limit,
+                        kind: LegKind::Option {
+                            spec,
+                            underlying_price,
+                        },
+                    },
+                    Self::option_hold(
+                        spec,
+                        underlying_price,
+                        Self::fee_rate(settings, command.symbol),
+                    )(position, command),
+                )
+            }
+        };
+
+        let mut legs = Self::portfolio_legs(participant, settings, mark_prices, asset, Some(&leg));
+        let before = grid.requirement(&legs);
+        legs.push(leg);
+        let after = grid.requirement(&legs);
+        if after >= before {
+            return None;
+        }
+
+        let mut margined: Vec<usize> = participant
+            .positions
+            .iter()
+            .filter(|(symbol_id, position)| {
+                let symbol = &settings.symbols[**symbol_id];
+                MarginAccount::of(**symbol_id as u64, symbol, position)
+                    == MarginAccount::Cross(asset)
+                    && position.margin > 0
+            })
+            .map(|(symbol_id, _)| *symbol_id)
+            .collect();
+        margined.sort_unstable();
+        let held: u64 = margined
+            .iter()
+            .map(|symbol_id| participant.positions[symbol_id].margin)
+            .sum();
+        let free = participant.assets.get(&asset).copied().unwrap_or(0);
+        let shortfall = required.saturating_sub(free);
+        if shortfall == 0 || shortfall > held.saturating_sub(after) {
+            return None;
+        }
+
+        let mut remaining = shortfall;
+        let mut relief = Vec::new();
+        for symbol_id in margined {
+            let position = participant.positions.get_mut(&symbol_id).unwrap();
+            let released = std::cmp::min(position.margin, remaining);
+            if released > 0 {
+                position.margin -= released;
+                remaining -= released;
+                relief.push((symbol_id, released));
+            }
+        }
+        *participant.assets.entry(asset).or_insert(0) += shortfall;
+        Some(relief)
+    }
+
+    /// Give the margin released for a hedge back to its positions, as far as the free funds reach
+    fn restore_portfolio_margin(&mut self, participant_id: u64, relief: Vec<(usize, u64)>) {
+        let participant = match self.participants.get_mut(&participant_id) {
+            Some(participant) => participant,
+            None => return,
+        };
+        for (symbol_id, margin) in relief {
+            let asset = self.settings.symbols[symbol_id].quote_asset;
+            let free = participant.assets.entry(asset).or_insert(0);
+            if let Some(position) = participant.positions.get_mut(&symbol_id) {
+                let restored = std::cmp::min(*free, margin);
+                if restored < margin {
+                    debug!(
+                        "Participant {} lacks {} to restore the margin of symbol {}",
+                        participant_id,
+                        margin - restored,
+                        symbol_id
+                    );
+                }
+                *free -= restored;
+                position.margin += restored;
+            }
+        }
+        // A position short of its margin is liquidated
+        self.margin_checks.insert(participant_id);
+    }
+
+    pub fn process_margin(&mut self, command: &MarginCommand) -> RiskEngineResult {
+        match *command {
+            MarginCommand::SetMode {
+                participant_id,
+                symbol: symbol_id,
+                mode,
+            } => {
+                let symbol = match self.settings.symbols.get(symbol_id as usize) {
+                    Some(symbol) if symbol.symbol_type != SymbolType::ExchangePair => symbol,
+                    _ => return RiskEngineResult::SymbolNotFound,
+                };
+                let user = match self.participants.get_mut(&participant_id) {
+                    Some(user) => user,
+                    None => return RiskEngineResult::UserNotFound,
+                };
+                let position = user
+                    .positions
+                    .entry(symbol_id as usize)
+                    .or_insert_with(|| PositionRecord::new(symbol_id as usize));
+                if position.volume > 0
+                    || position.pending_buy_volume > 0
+                    || position.pending_sell_volume > 0
+                {
+                    return RiskEngineResult::InvalidMarginCommand;
+                }
+                if position.mode == MarginMode::Isolated && mode == MarginMode::Cross {
+                    let collateral = std::mem::take(&mut position.collateral);
+                    *user.assets.entry(symbol.quote_asset).or_insert(0) += collateral;
+                }
+                user.positions.get_mut(&(symbol_id as usize)).unwrap().mode = mode;
+                RiskEngineResult::ValidForMatchingEngine
+            }
+            MarginCommand::Transfer {
+                participant_id,
+                from,
+                to,
+                amount,
+            } => {
+                let user = match self.participants.get_mut(&participant_id) {
+                    Some(user) => user,
+                    None => return RiskEngineResult::UserNotFound,
+                };
+                let mut accounts = Vec::with_capacity(2);
+                for symbol_id in [from, to].iter().flatten() {
+                    let symbol = match self.settings.symbols.get(*symbol_id as usize) {
+                        Some(symbol) => symbol,
+                        None => return RiskEngineResult::SymbolNotFound,
+                    };
+                    match user.positions.get(&(*symbol_id as usize)) {
+                        Some(position) if position.mode == MarginMode::Isolated => {
+                            accounts.push(symbol.quote_asset)
+                        }
+                        _ => return RiskEngineResult::InvalidMarginCommand,
+                    }
+                }
+                let asset = match accounts.as_slice() {
+                    [asset] => *asset,
+                    [first, second] if first == second && from != to => *first,
+                    _ => return RiskEngineResult::InvalidMarginCommand,
+                };
+                let account = |symbol_id: Option<u64>| match symbol_id {
+                    Some(symbol_id) => MarginAccount::Isolated(symbol_id),
+                    None => MarginAccount::Cross(asset),
+                };
+
+                let source = user.pool(account(from));
+                if *source < amount {
+                    return RiskEngineResult::InsufficientFunds;
+                }
+                *source -= amount;
+                // Collateral may only leave a pool as long as its positions stay above maintenance
+                let margin =
+                    Self::margin_state_of(user, &self.settings, &self.mark_prices, account(from));
+                if margin.maintenance > 0 && margin.is_below_maintenance() {
+                    *user.pool(account(from)) += amount;
+                    return RiskEngineResult::InsufficientFunds;
+                }
+                *user.pool(account(to)) += amount;
+                self.margin_checks.insert(participant_id);
+                RiskEngineResult::ValidForMatchingEngine
+            }
+        }
+    }
+
+    pub fn process_account(&mut self, command: &AccountCommand) -> RiskEngineResult {
+        let participant_id = command.participant_id();
+        if let AccountCommand::CreateParticipant { .. } = command {
+            if self.participants.contains_key(&participant_id) {
+                return RiskEngineResult::ParticipantExists;
+            }
+            self.add_participant(Participant {
+                id: participant_id,
+                ..Participant::default()
+            });
+            return RiskEngineResult::ValidForMatchingEngine;
+        }
+        if self.is_liquidating(participant_id) {
+            return RiskEngineResult::Liquidating;
+        }
+        let user = match self.participants.get_mut(&participant_id) {
+            Some(user) => user,
+            None => return RiskEngineResult::UserNotFound,
+        };
+        match *command {
+            AccountCommand::CreateParticipant { .. } => unreachable!(),
+            AccountCommand::Deposit { asset, amount, .. } => {
+                let free = user.assets.entry(asset).or_insert(0);
+                *free = match free.checked_add(amount) {
+                    Some(free) => free,
+                    None => return RiskEngineResult::BalanceOverflow,
+                };
+            }
+            AccountCommand::Withdraw { asset, amount, .. } => {
+                // Held funds are already taken out of the balance
+                let free = user.assets.entry(asset).or_insert(0);
+                if *free < amount {
+                    return RiskEngineResult::InsufficientFunds;
+                }
+                *free -= amount;
+                if !Self::is_margin_sufficient(user, &self.settings, &self.mark_prices) {
+                    *user.assets.get_mut(&asset).unwrap() += amount;
+                    return RiskEngineResult::InsufficientFunds;
+                }
+            }
+            AccountCommand::Adjust { asset, amount, .. } => {
+                let free = user.assets.entry(asset).or_insert(0);
+                *free = match free.checked_add_signed(amount) {
+                    Some(free) => free,
+                    None if amount < 0 => return RiskEngineResult::InsufficientFunds,
+                    None => return RiskEngineResult::BalanceOverflow,
+                };
+                // A correction may leave the positions under-margined
+                self.margin_checks.insert(participant_id);
+            }
+        }
+        RiskEngineResult::ValidForMatchingEngine
+    }
+
+    /// Whether all cross margin accounts of the participant stay above maintenance margin
+    fn is_margin_sufficient(
+        participant: &Participant,
+        settings: &ExchangeSettings,
+        mark_prices: &MarkPrices,
+    ) -> bool {
+        let assets: BTreeSet<AssetId> = participant
+            .positions
+            .iter()
+            .filter(|(_, position)| position.volume > 0 && position.mode == MarginMode::Cross)
+            .map(|(symbol_id, _)| settings.symbols[*symbol_id].quote_asset)
+            .collect();
+        assets.into_iter().all(|asset| {
+            let margin = Self::margin_state_of(
+                participant,
+                settings,
+                mark_prices,
+                MarginAccount::Cross(asset),
+            );
+            !margin.is_below_maintenance()
+        })
+    }
+
+    fn is_liquidating(&self, participant_id: u64) -> bool {
+        self.liquidating
+            .iter()
+            .any(|(liquidated, _)| *liquidated == participant_id)
+    }
+
+    /// Queue a margin check for every participant with a position valued at the symbol's price
+    fn check_positions_of(&mut self, symbol_id: u64) {
+        let symbols = &self.settings.symbols;
+        for participant in self.participants.values() {
+            let affected = participant
+                .positions
+                .iter()
+                .any(|(position_symbol, position)| {
+                    position.volume > 0
+                        && (*position_symbol as u64 == symbol_id
+                            || matches!(symbols[*position_symbol].symbol_type,
+                            SymbolType::Option(spec) if spec.underlying == symbol_id))
+                });
+            if affected {
+                self.margin_checks.insert(participant.id);
+            }
+        }
+    }
+
+    /// Check the margin of every participant whose margin changed since the last check
+    /// and start liquidating those below maintenance margin.
+    ///
+    /// Returns the commands for the order books of each started liquidation:
+    /// cancels of the participant's resting orders followed by liquidation orders.
+    pub fn check_margins(&mut self) -> Vec<(LiquidationReport, Vec<OrderCommand>)> {
+        let mut liquidations = Vec::new();
+        for participant_id in std::mem::take(&mut self.margin_checks) {
+            if participant_id == INSURANCE_FUND_ID {
+                continue;
+            }
+            let participant = match self.participants.get(&participant_id) {
+                Some(participant) => participant,
+                None => continue,
+            };
+            let accounts: BTreeSet<MarginAccount> = participant
+                .positions
+                .iter()
+                .filter(|(_, position)| position.volume > 0)
+                .map(|(symbol_id, position)| {
+                    let symbol = &self.settings.symbols[*symbol_id];
+                    MarginAccount::of(*symbol_id as u64, symbol, position)
+                })
+                .collect();
+            for account in accounts {
+                if self.liquidating.contains(&(participant_id, account)) {
+                    continue;
+                }
+                let participant = &self.participants[&participant_id];
+                let margin =
+                    Self::margin_state_of(participant, &self.settings, &self.mark_prices, account);
+                if margin.is_below_maintenance() {
+                    let commands = self.liquidate(participant_id, account, margin);
+                    let report = LiquidationReport {
+                        participant_id,
+                        account,
+                        margin,
+                    };
+                    liquidations.push((report, commands));
+                }
+            }
+        }
+        liquidations
+    }
+
+    /// Cancel the participant's resting orders on the symbols backed by the collateral pool
+    /// and close their positions with immediate-or-cancel orders at the bankruptcy price.
+    ///
+    /// The equity that is left is spread over the positions by their notional value,
+    /// so each position is closed at the price that uses up its share.
+    fn liquidate(
+        &mut self,
+        participant_id: u64,
+        account: MarginAccount,
+        margin: MarginState,
+    ) -> Vec<OrderCommand> {
+        warn!(
+            "Liquidating participant {} in {:?}, equity {}, maintenance margin {}",
+            participant_id, account, margin.equity, margin.maintenance
+        );
+        self.liquidating.insert((participant_id, account));
+        let symbols = &self.settings.symbols;
+        let participant = &self.participants[&participant_id];
+        // Only margined symbols have positions
+        let in_account: FxHashSet<usize> = participant
+            .positions
+            .iter()
+            .filter(|(symbol_id, position)| {
+                MarginAccount::of(**symbol_id as u64, &symbols[**symbol_id], position) == account
+            })
+            .map(|(symbol_id, _)| *symbol_id)
+            .collect();
+        let is_liquidated = |symbol_id: usize| in_account.contains(&symbol_id);
+
+        let mut resting: Vec<(u64, u64)> = self
+            .orders
+            .iter()
+            .filter(|(_, (owner, symbol_id, _))| {
+                *owner == participant_id && is_liquidated(*symbol_id as usize)
+            })
+            .map(|(id, (_, symbol_id, _))| (*id, *symbol_id))
+            .collect();
+        resting.sort_unstable();
+        let mut commands: Vec<OrderCommand> = resting
+            .into_iter()
+            .map(|(order_id, symbol)| {
+                OrderCommand::Cancel(CancelCommand {
+                    symbol,
+                    order_id,
+                    participant_id,
+                    remaining: 0,
+                })
+            })
+            .collect();
+
+        // Long options can only lose their premium, they need no liquidation
+        let mark_prices = &self.mark_prices;
+        let participant = self.participants.get_mut(&participant_id).unwrap();
+        let mut positions: Vec<(usize, &mut PositionRecord, u64)> = participant
+            .positions
+            .iter_mut()
+            .filter(|(symbol_id, position)| {
+                position.volume > 0
+                    && is_liquidated(**symbol_id)
+                    && !(matches!(symbols[**symbol_id].symbol_type, SymbolType::Option(_))
+                        && position.direction == PositionDirection::Long)
+            })
+            .map(|(symbol_id, position)| {
+                let mark_price = mark_prices
+                    .get(*symbol_id as u64)
+                    .unwrap_or_else(|| position.average_entry_price().unwrap());
+                (*symbol_id, position, mark_price)
+            })
+            .collect();
+        positions.sort_unstable_by_key(|(symbol_id, _, _)| *symbol_id);
+
+        // The products can pass u64, each share is at most the equity
+        let equity = std::cmp::max(margin.equity, 0) as u128;
+        let notional: u128 = positions
+            .iter()
+            .map(|(_, position, mark_price)| position.volume as u128 * *mark_price as u128)
+            .sum();
+        for (symbol_id, position, mark_price) in positions {
+            let share = (equity * position.volume as u128 * mark_price as u128)
+                .checked_div(notional)
+                .unwrap_or(0) as u64;
+            let (side, bankruptcy_price) = match position.direction {
+                PositionDirection::Long => (
+                    OrderSide::ASK,
+                    mark_price.saturating_sub(share / position.volume),
+                ),
+                PositionDirection::Short => (
+                    OrderSide::BID,
+                    mark_price.saturating_add(share / position.volume),
+                ),
+            };
+            let order = TradeCommand {
+                id: LIQUIDATION_ORDER_ID_BASE + (self.shard << 32) + self.liquidation_count,
+                participant_id,
+                symbol: symbol_id as u64,
+                side,
+                volume: position.volume,
+                limit: bankruptcy_price,
+                immediate_or_cancel: true,
+                order_type: OrderType::Limit,
+                hidden: false,
+                min_quantity: 0,
+                reduce_only: false,
+                close_position: false,
+            };
+            self.liquidation_count += 1;
+
+            // Nothing is held, the order only reduces the position
+            position.pending_hold(side, order.volume);
+            self.orders.insert(
+                order.id,
+                (participant_id, order.symbol, RiskOrder::held(order, 0)),
+            );
+            self.liquidation_orders
+                .insert(order.id, (participant_id, account, bankruptcy_price));
+            let open = self.open_exposure.entry(participant_id).or_insert(0);
+            *open = open.saturating_add(order.volume.saturating_mul(order.limit));
+            commands.push(OrderCommand::Trade(order));
+        }
+        if !commands
+            .iter()
+            .any(|command| matches!(command, OrderCommand::Trade(_)))
+        {
+            self.liquidating.remove(&(participant_id, account));
+        }
+        commands
+    }
+
+    /// Hand the rest of a position the book could not absorb to the insurance fund,
+    /// the participant closes and the fund opens it at the bankruptcy price
+    fn take_over(
+        &mut self,
+        participant_id: u64,
+        symbol_id: u64,
+        side: OrderSide,
+        volume: u64,
+        price: u64,
+    ) {
+        warn!(
+            "Insurance fund takes over {} of symbol {} from participant {} at {}",
+            volume, symbol_id, participant_id, price
+        );
+        let symbol = &self.settings.symbols[symbol_id as usize];
+        let asset = symbol.quote_asset;
+        let mut deficits = Vec::new();
+        for (id, side) in [(participant_id, side), (INSURANCE_FUND_ID, -side)] {
+            let participant = self.participants.get_mut(&id).unwrap();
+            let deficit = Self::book_position_fill(
+                participant,
+                symbol_id,
+                symbol,
+                &self.mark_prices,
+                side,
+                volume,
+                volume * price,
+            );
+            deficits.push((id, deficit));
+        }
+        for (id, deficit) in deficits {
+            self.cover_deficit(id, symbol_id, asset, deficit);
+        }
+        self.check_reduce_only(participant_id, symbol_id);
+    }
+
+    /// Forget a finished liquidation order and end the liquidation once none is left
+    fn liquidation_done(&mut self, id: u64) {
+        if let Some((participant_id, account, _)) = self.liquidation_orders.remove(&id) {
+            let open = self
+                .liquidation_orders
+                .values()
+                .any(|(participant, a, _)| *participant == participant_id && *a == account);
+            if !open {
+                self.liquidating.remove(&(participant_id, account));
+                self.margin_checks.insert(participant_id);
+            }
+        }
+    }
+
+    pub fn process_query(&self, query: &QueryCommand) -> Report {
+        match *query {
+            QueryCommand::FundingHistory { symbol } => Report::FundingHistory {
+                symbol,
+                records: self.funding.history(symbol).to_vec(),
+            },
+            QueryCommand::Balances { participant_id } => Report::Balances {
+                participant_id,
+                balances: self.balances(participant_id),
+            },
+        }
+    }
+
+    /// Balances of every asset of a participant, empty if it is not on this shard
+    pub fn balances(&self, participant_id: u64) -> Vec<(AssetId, Balance)> {
+        self.participants
+            .get(&participant_id)
+            .map_or_else(Vec::new, Participant::balances)
+    }
+
+    /// Participants on this shard in id order
+    pub fn participant_ids(&self) -> Vec<u64> {
+        let mut ids: Vec<u64> = self.participants.keys().copied().collect();
+        ids.sort_unstable();
+        ids
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exchange::asset::{OptionKind, OptionMarginModel};
+    use crate::exchange::commands::Stop;
+    use crate::risk::exposure_limits::ExposureLimit;
+    use crate::risk::fee_engine::FeeRates;
+    use crate::risk::order_limits::OrderLimits;
+    use crate::risk::portfolio_margin::ScenarioGrid;
+
+    const PARTICIPANT: u64 = 1;
+
+    /// A shard with a spot pair trading asset 0 against asset 1
+    fn engine() -> RiskEngine {
+        let settings = ExchangeSettings {
+            symbols: vec![Symbol {
+                symbol_type: SymbolType::ExchangePair,
+                base_asset: 0,
+                quote_asset: 1,
+            }],
+            risk_engine_shards: 1,
+            ..ExchangeSettings::default()
+        };
+        RiskEngine::new(0, settings)
+    }
+
+    /// A shard with the spot pair and a call option on it, margined in asset 1
+    fn option_engine() -> RiskEngine {
+        let mut engine = engine();
+        engine.settings.symbols.push(Symbol {
+            symbol_type: SymbolType::Option(OptionSpec {
+                underlying: 0,
+                strike: 100,
+                expiry: 0,
+                kind: OptionKind::Call,
+                margin: OptionMarginModel::PercentOfUnderlying {
+                    rate: 1_000,
+                    minimum: 500,
+                },
+            }),
+            base_asset: 0,
+            quote_asset: 1,
+        });
+        engine
+    }
+
+    fn deposit(engine: &mut RiskEngine, participant_id: u64, asset: AssetId, amount: u64) {
+        let _ = engine.process_account(&AccountCommand::CreateParticipant { participant_id });
+        let result = engine.process_account(&AccountCommand::Deposit {
+            participant_id,
+            asset,
+            amount,
+        });
+        assert_eq!(result, RiskEngineResult::ValidForMatchingEngine);
+    }
+
+    fn balance(engine: &RiskEngine, participant_id: u64, asset: AssetId) -> Balance {
+        engine.participants[&participant_id].balance(asset)
+    }
+
+    fn order(id: u64, side: OrderSide, volume: u64, limit: u64) -> OrderCommand {
+        OrderCommand::Trade(TradeCommand {
+            id,
+            participant_id: PARTICIPANT,
+            symbol: 0,
+            side,
+            volume,
+            limit,
+            immediate_or_cancel: false,
+            order_type: OrderType::Limit,
+            hidden: false,
+            min_quantity: 0,
+            reduce_only: false,
+            close_position: false,
+        })
+    }
+
+    fn quote(bid_id: u64, bid_limit: u64, ask_id: u64) -> OrderCommand {
+        OrderCommand::Quote(QuoteCommand {
+            participant_id: PARTICIPANT,
+            symbol: 0,
+            bid_id,
+            bid_limit,
+            bid_volume: 5,
+            ask_id,
+            ask_limit: 200,
+            ask_volume: 10,
+        })
+    }
+
+    #[test]
+    fn requote_holds_only_the_difference() {
+        let mut engine = engine();
+        deposit(&mut engine, PARTICIPANT, 0, 1_000);
+        deposit(&mut engine, PARTICIPANT, 1, 10);
+
+        let result = engine.process_command(&mut quote(1, 100, 2));
+        assert_eq!(result, RiskEngineResult::ValidForMatchingEngine);
+        // Both quotes together would need 1_250
+        let result = engine.process_command(&mut quote(3, 150, 4));
+        assert_eq!(result, RiskEngineResult::ValidForMatchingEngine);
+        assert_eq!(balance(&engine, PARTICIPANT, 0).available, 250);
+
+        engine.process_matcher_event(MatchingEngineEvent::Canceled(1));
+        engine.process_matcher_event(MatchingEngineEvent::Canceled(2));
+        assert_eq!(balance(&engine, PARTICIPANT, 0).available, 250);
+        assert_eq!(balance(&engine, PARTICIPANT, 0).held, 750);
+        assert_eq!(balance(&engine, PARTICIPANT, 1).held, 10);
+
+        engine.process_matcher_event(MatchingEngineEvent::Canceled(3));
+        engine.process_matcher_event(MatchingEngineEvent::Canceled(4));
+        assert_eq!(balance(&engine, PARTICIPANT, 0).available, 1_000);
+        assert_eq!(balance(&engine, PARTICIPANT, 1).available, 10);
+        assert!(engine.take_cancels().is_empty());
+    }
+
+    #[test]
+    fn requote_cancels_a_leg_whose_predecessor_was_filled() {
+        let mut engine = engine();
+        deposit(&mut engine, PARTICIPANT, 0, 1_000);
+        deposit(&mut engine, PARTICIPANT, 1, 10);
+        engine.process_command(&mut quote(1, 100, 2));
+        engine.process_command(&mut quote(3, 150, 4));
+
+        // The old bid traded before the order book replaced it
+        engine.process_matcher_event(MatchingEngineEvent::Filled(1, 5, 500, Liquidity::Maker));
+        engine.process_matcher_event(MatchingEngineEvent::Canceled(2));
+        let cancels = engine.take_cancels();
+        assert_eq!(cancels.len(), 1);
+        assert_eq!(cancels[0].order_id, 3);
+
+        engine.process_matcher_event(MatchingEngineEvent::Canceled(3));
+        engine.process_matcher_event(MatchingEngineEvent::Canceled(4));
+        assert_eq!(balance(&engine, PARTICIPANT, 0).available, 500);
+        assert_eq!(balance(&engine, PARTICIPANT, 0).held, 0);
+        assert_eq!(balance(&engine, PARTICIPANT, 1).available, 15);
+    }
+
+    #[test]
+    fn repeated_cancel_is_ignored() {
+        let mut engine = engine();
+        deposit(&mut engine, PARTICIPANT, 0, 1_000);
+        let result = engine.process_command(&mut order(1, OrderSide::BID, 5, 100));
+        assert_eq!(result, RiskEngineResult::ValidForMatchingEngine);
+
+        engine.process_matcher_event(MatchingEngineEvent::Canceled(1));
+        engine.process_matcher_event(MatchingEngineEvent::Canceled(1));
+        engine.process_matcher_event(MatchingEngineEvent::Filled(1, 5, 500, Liquidity::Maker));
+        assert_eq!(balance(&engine, PARTICIPANT, 0).available, 1_000);
+        assert_eq!(balance(&engine, PARTICIPANT, 0).held, 0);
+    }
+
+    #[test]
+    fn longs_pay_funding_rounded_up_and_shorts_receive_it_rounded_down() {
+        let spec = PerpetualSpec {
+            margin: FuturesSpec {
+                initial_margin: 1_000,
+                maintenance_margin: 500,
+            },
+            funding_interval: 3_600,
+            max_funding_rate: 75,
+        };
+        let symbol = Symbol {
+            symbol_type: SymbolType::PerpetualSwap(spec),
+            base_asset: 0,
+            quote_asset: 1,
+        };
+        let record = FundingRecord {
+            time: 0,
+            mark_price: 1_001,
+            index_price: 1_000,
+            rate: 7,
+        };
+        let mut payments = Vec::new();
+        for direction in [PositionDirection::Long, PositionDirection::Short] {
+            let mut participant = Participant::default();
+            participant.assets.insert(1, 100);
+            let mut position = PositionRecord::new(0);
+            position.direction = direction;
+            position.volume = 10;
+            participant.positions.insert(0, position);
+            assert_eq!(
+                RiskEngine::pay_funding(&mut participant, 0, &symbol, &record),
+                0
+            );
+            payments.push((participant.assets[&1], participant.positions[&0].funding));
+        }
+        // 10 at 1001 is 10010, 7 basis points of it are 7.007
+        assert_eq!(payments, vec![(92, -8), (107, 7)]);
+    }
+
+    #[test]
+    fn settle_collateral_returns_the_unpaid_debit() {
+        let mut participant = Participant::default();
+        participant.assets.insert(1, 100);
+        assert_eq!(
+            RiskEngine::settle_collateral(&mut participant, 0, 1, 20, 100),
+            0
+        );
+        assert_eq!(participant.assets[&1], 20);
+        assert_eq!(
+            RiskEngine::settle_collateral(&mut participant, 0, 1, 10, 50),
+            20
+        );
+        assert_eq!(participant.assets[&1], 0);
+    }
+
+    #[test]
+    fn insurance_fund_covers_a_deficit() {
+        let mut engine = engine();
+        deposit(&mut engine, INSURANCE_FUND_ID, 1, 50);
+        deposit(&mut engine, 2, 1, 100);
+        engine.cover_deficit(PARTICIPANT, 0, 1, 30);
+        assert_eq!(balance(&engine, INSURANCE_FUND_ID, 1).available, 20);
+        assert_eq!(balance(&engine, 2, 1).available, 100);
+        assert!(engine.take_halts().is_empty());
+    }
+
+    #[test]
+    fn loss_beyond_the_insurance_fund_halts_the_symbol() {
+        let mut engine = option_engine();
+        deposit(&mut engine, INSURANCE_FUND_ID, 1, 10);
+        deposit(&mut engine, 2, 1, 100);
+        deposit(&mut engine, FEE_ACCOUNT_ID, 1, 100);
+
+        engine.cover_deficit(PARTICIPANT, 1, 1, 40);
+        assert_eq!(balance(&engine, INSURANCE_FUND_ID, 1).available, 0);
+        assert_eq!(balance(&engine, 2, 1).available, 100);
+        assert_eq!(balance(&engine, FEE_ACCOUNT_ID, 1).available, 100);
+        assert_eq!(engine.take_halts(), vec![1]);
+
+        deposit(&mut engine, 2, 0, 100);
+        let mut ask = order(0, OrderSide::ASK, 1, 10);
+        if let OrderCommand::Trade(trade) = &mut ask {
+            trade.participant_id = 2;
+            trade.symbol = 1;
+        }
+        assert_eq!(
+            engine.process_command(&mut ask),
+            RiskEngineResult::SymbolHalted
+        );
+    }
+
+    #[test]
+    fn spot_loss_beyond_the_insurance_fund_does_not_halt() {
+        let mut engine = engine();
+        deposit(&mut engine, 2, 1, 100);
+        engine.cover_deficit(PARTICIPANT, 0, 1, 40);
+        assert_eq!(balance(&engine, 2, 1).available, 100);
+        assert!(engine.take_halts().is_empty());
+    }
+
+    #[test]
+    fn options_count_with_their_mark_value() {
+        let mut engine = option_engine();
+        deposit(&mut engine, PARTICIPANT, 1, 900);
+        // Ten calls bought for a premium of 100
+        let mut position = PositionRecord::new(1);
+        position.volume = 10;
+        position.paid_value = 100;
+        let participant = engine.participants.get_mut(&PARTICIPANT).unwrap();
+        participant.positions.insert(1, position);
+        engine.mark_prices.set_index(1, 10);
+
+        let state = engine
+            .margin_state(PARTICIPANT, MarginAccount::Cross(1))
+            .unwrap();
+        assert_eq!(state.equity, 1_000);
+    }
+
+    #[test]
+    fn insurance_deposit_funds_only_its_shard() {
+        let mut engine = engine();
+        for shard in [0, 1] {
+            engine.process_admin(&AdminCommand::InsuranceDeposit {
+                shard,
+                asset: 1,
+                amount: 500,
+            });
+        }
+        assert_eq!(balance(&engine, INSURANCE_FUND_ID, 1).available, 500);
+    }
+
+    #[test]
+    fn canceled_hedge_restores_the_released_margin() {
+        let mut engine = engine();
+        // A future on asset 1 settling in asset 0, the pair hedges it
+        engine.settings.symbols.push(Symbol {
+            symbol_type: SymbolType::FuturesContract(FuturesSpec {
+                initial_margin: 1_000,
+                maintenance_margin: 500,
+            }),
+            base_asset: 1,
+            quote_asset: 0,
+        });
+        engine.settings.portfolio_margin = Some(ScenarioGrid {
+            price_move: 1_000,
+            steps: 1,
+            spread_charge: 0,
+        });
+        deposit(&mut engine, PARTICIPANT, 0, 950);
+        let participant = engine.participants.get_mut(&PARTICIPANT).unwrap();
+        let mut position = PositionRecord::new(1);
+        position.apply_fill(OrderSide::ASK, 10, 1_000);
+        position.margin = 100;
+        participant.positions.insert(1, position);
+
+        // The hedge needs 1_000, 50 of the short's margin is released for it
+        let result = engine.process_command(&mut order(1, OrderSide::BID, 10, 100));
+        assert_eq!(result, RiskEngineResult::ValidForMatchingEngine);
+        assert_eq!(engine.participants[&PARTICIPANT].positions[&1].margin, 50);
+        assert_eq!(balance(&engine, PARTICIPANT, 0).available, 0);
+
+        engine.process_matcher_event(MatchingEngineEvent::Canceled(1));
+        assert_eq!(engine.participants[&PARTICIPANT].positions[&1].margin, 100);
+        assert_eq!(balance(&engine, PARTICIPANT, 0).available, 950);
+    }
+
+    #[test]
+    fn order_value_that_does_not_fit_is_rejected() {
+        let mut engine = engine();
+        deposit(&mut engine, PARTICIPANT, 0, u64::MAX);
+        let mut bid = order(0, OrderSide::BID, u64::MAX / 2, 3);
+        assert_eq!(
+            engine.process_command(&mut bid),
+            RiskEngineResult::BalanceOverflow
+        );
+        let mut quote = quote(1, u64::MAX / 4, 2);
+        assert_eq!(
+            engine.process_command(&mut quote),
+            RiskEngineResult::BalanceOverflow
+        );
+        assert_eq!(balance(&engine, PARTICIPANT, 0).held, 0);
+    }
+
+    #[test]
+    fn balances_do_not_overflow() {
+        let mut engine = engine();
+        deposit(&mut engine, PARTICIPANT, 0, u64::MAX - 1);
+        let result = engine.process_account(&AccountCommand::Deposit {
+            participant_id: PARTICIPANT,
+            asset: 0,
+            amount: 2,
+        });
+        assert_eq!(result, RiskEngineResult::BalanceOverflow);
+        let result = engine.process_account(&AccountCommand::Adjust {
+            participant_id: PARTICIPANT,
+            asset: 0,
+            amount: 2,
+        });
+        assert_eq!(result, RiskEngineResult::BalanceOverflow);
+        // Balances above i64::MAX are adjusted without wrapping
+        let result = engine.process_account(&AccountCommand::Adjust {
+            participant_id: PARTICIPANT,
+            asset: 0,
+            amount: -1,
+        });
+        assert_eq!(result, RiskEngineResult::ValidForMatchingEngine);
+        assert_eq!(balance(&engine, PARTICIPANT, 0).available, u64::MAX - 2);
+        assert_eq!(balance(&engine, PARTICIPANT, 1).available, 0);
+        let result = engine.process_account(&AccountCommand::Adjust {
+            participant_id: PARTICIPANT,
+            asset: 1,
+            amount: -1,
+        });
+        assert_eq!(result, RiskEngineResult::InsufficientFunds);
+    }
+
+    #[test]
+    fn isolated_holds_are_not_in_the_balance() {
+        let mut engine = engine();
+        deposit(&mut engine, PARTICIPANT, 1, 100);
+        let participant = engine.participants.get_mut(&PARTICIPANT).unwrap();
+        let mut position = PositionRecord::new(1);
+        position.mode = MarginMode::Isolated;
+        position.collateral = 50;
+        participant.positions.insert(1, position);
+
+        assert!(participant.hold(1, 1, 30));
+        assert!(participant.hold(0, 1, 20));
+        let balance = participant.balance(1);
+        assert_eq!(
+            (balance.available, balance.held, balance.total),
+            (80, 20, 100)
+        );
+
+        participant.release(1, 1, 30);
+        assert_eq!(participant.positions[&1].collateral, 50);
+        assert_eq!(participant.balance(1).held, 20);
+    }
+
+    /// The spot pair charging takers 0.2% and paying makers 0.1%
+    fn fee_engine() -> RiskEngine {
+        let mut engine = engine();
+        engine.settings.fees.insert(
+            0,
+            FeeSchedule {
+                rates: FeeRates {
+                    maker: -10,
+                    taker: 20,
+                },
+                tiers: Vec::new(),
+                asset: None,
+            },
+        );
+        engine
+    }
+
+    #[test]
+    fn orders_hold_their_fee() {
+        let mut engine = fee_engine();
+        deposit(&mut engine, PARTICIPANT, 0, 1_001);
+        let result = engine.process_command(&mut order(1, OrderSide::BID, 10, 100));
+        assert_eq!(result, RiskEngineResult::InsufficientFunds);
+
+        deposit(&mut engine, PARTICIPANT, 0, 1);
+        let result = engine.process_command(&mut order(1, OrderSide::BID, 10, 100));
+        assert_eq!(result, RiskEngineResult::ValidForMatchingEngine);
+        assert_eq!(balance(&engine, PARTICIPANT, 0).held, 1_002);
+
+        engine.process_matcher_event(MatchingEngineEvent::Filled(1, 10, 1_000, Liquidity::Taker));
+        assert_eq!(balance(&engine, PARTICIPANT, 0).total, 0);
+        assert_eq!(balance(&engine, PARTICIPANT, 1).available, 10);
+        assert_eq!(balance(&engine, FEE_ACCOUNT_ID, 0).available, 2);
+    }
+
+    #[test]
+    fn rebates_are_paid_from_collected_fees() {
+        let mut engine = fee_engine();
+        deposit(&mut engine, PARTICIPANT, 1, 20);
+        let result = engine.process_command(&mut order(1, OrderSide::ASK, 20, 100));
+        assert_eq!(result, RiskEngineResult::ValidForMatchingEngine);
+
+        // The fee account has nothing to pay the rebate with
+        engine.process_matcher_event(MatchingEngineEvent::Filled(1, 10, 1_000, Liquidity::Maker));
+        assert_eq!(balance(&engine, PARTICIPANT, 0).available, 1_000);
+
+        deposit(&mut engine, FEE_ACCOUNT_ID, 0, 5);
+        engine.process_matcher_event(MatchingEngineEvent::Filled(1, 10, 1_000, Liquidity::Maker));
+        assert_eq!(balance(&engine, PARTICIPANT, 0).available, 2_001);
+        assert_eq!(balance(&engine, FEE_ACCOUNT_ID, 0).available, 4);
+    }
+
+    #[test]
+    fn price_deviation_only_limits_orders_that_trade_now() {
+        let mut engine = engine();
+        deposit(&mut engine, PARTICIPANT, 0, 10_000);
+        engine.order_limits.set_symbol(
+            0,
+            OrderLimits {
+                max_notional: Some(1_000_000),
+                max_price_deviation: Some(1_000),
+                ..OrderLimits::default()
+            },
+        );
+        engine.last_prices.insert(0, 100);
+
+        let result = engine.process_command(&mut order(1, OrderSide::BID, 10, 150));
+        assert_eq!(result, RiskEngineResult::PriceDeviationExceeded);
+        let mut stop = order(2, OrderSide::BID, 10, 150);
+        if let OrderCommand::Trade(trade) = &mut stop {
+            trade.order_type = OrderType::Stop(Stop {
+                trigger: 150,
+                market: false,
+            });
+        }
+        let result = engine.process_command(&mut stop);
+        assert_eq!(result, RiskEngineResult::ValidForMatchingEngine);
+
+        let result = engine.process_command(&mut order(3, OrderSide::BID, u64::MAX, 100));
+        assert_eq!(result, RiskEngineResult::NotionalLimitExceeded);
+    }
+
+    #[test]
+    fn one_cancels_other_counts_one_leg() {
+        let mut engine = engine();
+        deposit(&mut engine, PARTICIPANT, 1, 100);
+        engine.exposure_limits.insert(
+            PARTICIPANT,
+            ExposureLimits {
+                open_orders: ExposureLimit {
+                    soft: None,
+                    hard: Some(1_500),
+                },
+                ..ExposureLimits::default()
+            },
+        );
+        let trade = |command: OrderCommand| match command {
+            OrderCommand::Trade(trade) => trade,
+            _ => unreachable!(),
+        };
+        let mut oco = OrderCommand::Oco(OcoCommand {
+            first: trade(order(1, OrderSide::ASK, 10, 100)),
+            second: trade(order(2, OrderSide::ASK, 10, 120)),
+        });
+        let result = engine.process_command(&mut oco);
+        assert_eq!(result, RiskEngineResult::ValidForMatchingEngine);
+        assert_eq!(engine.open_exposure[&PARTICIPANT], 1_200);
+
+        // The smaller leg fills in part and the book cancels the larger one
+        engine.process_matcher_event(MatchingEngineEvent::Filled(1, 4, 400, Liquidity::Taker));
+        engine.process_matcher_event(MatchingEngineEvent::Canceled(2));
+        assert_eq!(engine.open_exposure[&PARTICIPANT], 600);
+        engine.process_matcher_event(MatchingEngineEvent::Canceled(1));
+        assert_eq!(engine.open_exposure[&PARTICIPANT], 0);
+    }
+
+    #[test]
+    fn shrinking_position_reduces_the_reduce_only_remainder() {
+        let mut engine = engine();
+        engine.settings.symbols.push(Symbol {
+            symbol_type: SymbolType::FuturesContract(FuturesSpec {
+                initial_margin: 1_000,
+                maintenance_margin: 500,
+            }),
+            base_asset: 1,
+            quote_asset: 0,
+        });
+        deposit(&mut engine, PARTICIPANT, 0, 1_000);
+        let mut position = PositionRecord::new(1);
+        position.apply_fill(OrderSide::BID, 10, 1_000);
+        let participant = engine.participants.get_mut(&PARTICIPANT).unwrap();
+        participant.positions.insert(1, position);
+
+        let mut command = order(1, OrderSide::ASK, 10, 100);
+        if let OrderCommand::Trade(trade) = &mut command {
+            trade.symbol = 1;
+            trade.reduce_only = true;
+        }
+        let result = engine.process_command(&mut command);
+        assert_eq!(result, RiskEngineResult::ValidForMatchingEngine);
+
+        let participant = engine.participants.get_mut(&PARTICIPANT).unwrap();
+        let position = participant.positions.get_mut(&1).unwrap();
+        position.apply_fill(OrderSide::ASK, 5, 500);
+        engine.check_reduce_only(PARTICIPANT, 1);
+        let cancels = engine.take_cancels();
+        assert_eq!(cancels.len(), 1);
+        assert_eq!((cancels[0].order_id, cancels[0].remaining), (1, 5));
+        // The order keeps counting until the book confirms
+        assert_eq!(
+            engine.participants[&PARTICIPANT].positions[&1].reduce_only,
+            vec![1]
+        );
+
+        engine.process_matcher_event(MatchingEngineEvent::Reduced(1, 5));
+        assert_eq!(engine.orders[&1].2.volume, 5);
+        let position = &engine.participants[&PARTICIPANT].positions[&1];
+        assert_eq!(position.pending_sell_volume, 5);
+        engine.check_reduce_only(PARTICIPANT, 1);
+        assert!(engine.take_cancels().is_empty());
+    }
+
+    #[test]
+    fn new_shards_get_the_operator_state_and_a_share_of_the_funds() {
+        let mut first = engine();
+        let mut second = engine();
+        let mut added = engine();
+        for engine in [&mut first, &mut second] {
+            deposit(engine, INSURANCE_FUND_ID, 1, 90);
+            engine.process_admin(&AdminCommand::MarkPrice {
+                symbol: 0,
+                price: 7,
+            });
+        }
+        deposit(&mut first, FEE_ACCOUNT_ID, 0, 31);
+
+        added.import_shard(first.export_shard(3));
+        added.import_shard(second.export_shard(3));
+
+        assert_eq!(balance(&first, INSURANCE_FUND_ID, 1).available, 60);
+        assert_eq!(balance(&second, INSURANCE_FUND_ID, 1).available, 60);
+        assert_eq!(balance(&added, INSURANCE_FUND_ID, 1).available, 60);
+        assert_eq!(balance(&first, FEE_ACCOUNT_ID, 0).available, 21);
+        assert_eq!(balance(&added, FEE_ACCOUNT_ID, 0).available, 10);
+        assert_eq!(added.mark_prices.get(0), Some(7));
     }
 }
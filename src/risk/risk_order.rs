@@ -5,6 +5,23 @@ pub struct RiskOrder {
     pub volume: u64,
     pub side: OrderSide,
     pub id: u64,
+
+    /// Amount currently held for this order, the pessimistic asset of a pair or the margin of a futures order
+    pub hold: u64,
+    /// Other leg of a one-cancels-other pair, both legs share one hold
+    pub linked: Option<u64>,
+    /// First exit leg of a bracket, activated once this entry order is filled
+    pub activates: Option<u64>,
+    /// Leg of the next quote on the same side, takes over the hold once this order leaves the book
+    pub successor: Option<u64>,
+    /// Hold this quote leg still takes over from the leg it replaces
+    pub inherited: u64,
+    /// Whether volume times limit counts in the open exposure of the participant,
+    /// of a one-cancels-other pair only the larger leg does
+    pub exposed: bool,
+    /// Position margin the portfolio offsets of this order released (symbol_id, margin),
+    /// given back to the positions if the order is canceled
+    pub relief: Vec<(usize, u64)>,
 }
 
 impl RiskOrder {
@@ -20,7 +37,31 @@ impl RiskOrder {
             volume,
             side,
             id,
+            hold: 0,
+            linked: None,
+            activates: None,
+            successor: None,
+            inherited: 0,
+            exposed: true,
+            relief: Vec::new(),
+        }
+    }
+
+    /// The relief of filled volume stays released, its hedge is in place now.
+    /// Call before the volume is reduced by the fill.
+    pub fn keep_relief(&mut self, filled: u64) {
+        if self.volume == 0 {
+            return;
         }
+        for (_, margin) in self.relief.iter_mut() {
+            *margin -= *margin * filled / self.volume;
+        }
+    }
+
+    pub fn held(command: TradeCommand, hold: u64) -> RiskOrder {
+        let mut order = RiskOrder::from(command);
+        order.hold = hold;
+        order
     }
 }
 
@@ -1,9 +1,137 @@
-use crate::exchange::exchange_settings::ExchangeSettings;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::RwLock;
 
-use super::participant::Participant;
+use crossbeam::channel::Sender;
 
-pub fn risk_router(settings: &ExchangeSettings, participant_id: &u64) -> usize{
+use crate::order_handling::event::MatchingEngineEvent;
 
-    (participant_id % settings.risk_engine_shards) as usize
+use super::migration::Handoff;
 
+/// Points of each shard on the hash ring, more points spread the participants more evenly
+const VIRTUAL_NODES: u64 = 64;
+
+/// Deterministic 64 bit mix, so every run places the participants the same way
+fn hash(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Consistent hash ring of the risk engine shards.
+///
+/// Adding a shard only moves the participants that land on its points.
+#[derive(Default)]
+struct HashRing {
+    points: BTreeMap<u64, usize>,
+}
+
+impl HashRing {
+    fn add(&mut self, shard: usize) {
+        for node in 0..VIRTUAL_NODES {
+            self.points
+                .insert(hash(((shard as u64) << 32) | node), shard);
+        }
+    }
+
+    fn shard(&self, participant_id: u64) -> usize {
+        let point = hash(participant_id);
+        self.points
+            .range(point..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .map_or(0, |(_, shard)| *shard)
+    }
+}
+
+#[derive(Default)]
+struct Directory {
+    ring: HashRing,
+    /// Shard of every participant the exchange created or moved
+    participants: HashMap<u64, usize>,
+    event_senders: Vec<Sender<MatchingEngineEvent>>,
+    handoff_senders: Vec<Sender<Handoff>>,
+}
+
+/// Which risk engine shard keeps each participant, shared by the exchange,
+/// the order books and the risk engines.
+///
+/// New participants are placed by consistent hashing and stay on their shard
+/// until they are migrated, so growing the shard count moves nobody by itself.
+#[derive(Default)]
+pub struct ShardDirectory {
+    inner: RwLock<Directory>,
+}
+
+impl ShardDirectory {
+    /// Add a shard with the channels its matching engine events and migrated participants arrive on.
+    /// Returns its index.
+    pub fn add_shard(
+        &self,
+        event_sender: Sender<MatchingEngineEvent>,
+        handoff_sender: Sender<Handoff>,
+    ) -> usize {
+        let mut directory = self.inner.write().unwrap();
+        let shard = directory.event_senders.len();
+        directory.ring.add(shard);
+        directory.event_senders.push(event_sender);
+        directory.handoff_senders.push(handoff_sender);
+        shard
+    }
+
+    /// Shard that keeps the participant
+    pub fn shard(&self, participant_id: u64) -> usize {
+        let directory = self.inner.read().unwrap();
+        match directory.participants.get(&participant_id) {
+            Some(shard) => *shard,
+            None => directory.ring.shard(participant_id),
+        }
+    }
+
+    /// Shard the hash ring places the participant on, regardless of where it is kept
+    pub fn placement(&self, participant_id: u64) -> usize {
+        self.inner.read().unwrap().ring.shard(participant_id)
+    }
+
+    /// Keep the participant on the shard the ring places it on, unless it already has one
+    pub fn place(&self, participant_id: u64) -> usize {
+        let mut directory = self.inner.write().unwrap();
+        let shard = directory.ring.shard(participant_id);
+        *directory
+            .participants
+            .entry(participant_id)
+            .or_insert(shard)
+    }
+
+    /// Keep the participant on a shard
+    pub fn assign(&self, participant_id: u64, shard: usize) {
+        self.inner
+            .write()
+            .unwrap()
+            .participants
+            .insert(participant_id, shard);
+    }
+
+    /// Participants the exchange placed, in id order
+    pub fn participants(&self) -> Vec<(u64, usize)> {
+        let mut participants: Vec<(u64, usize)> = self
+            .inner
+            .read()
+            .unwrap()
+            .participants
+            .iter()
+            .map(|(participant_id, shard)| (*participant_id, *shard))
+            .collect();
+        participants.sort_unstable();
+        participants
+    }
+
+    /// Event channel of every shard, by index
+    pub fn event_senders(&self) -> Vec<Sender<MatchingEngineEvent>> {
+        self.inner.read().unwrap().event_senders.clone()
+    }
+
+    pub fn handoff_sender(&self, shard: usize) -> Sender<Handoff> {
+        self.inner.read().unwrap().handoff_senders[shard].clone()
+    }
 }